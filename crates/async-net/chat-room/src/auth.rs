@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyverr::{AnyError, AnyResult};
+use serde::Deserialize;
+use tokio::fs;
+
+/// Stop tracking failing addresses beyond this many; expired ones are pruned first.
+const MAX_TRACKED_ADDRS: usize = 1024;
+
+/// The users allowed into the room and their tokens.
+///
+/// JSON: `{"users": {"alice": "s3cret"}}`, TOML: `[users]` then `alice = "s3cret"`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Credentials {
+    pub users: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Parses `content` as TOML when `path` ends in `.toml`, as JSON otherwise.
+    pub fn parse(path: &Path, content: &str) -> AnyResult<Self> {
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(content).map_err(AnyError::wrap)
+        } else {
+            serde_json::from_str(content).map_err(AnyError::wrap)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    credentials: Credentials,
+    /// Modification time and length of the file the credentials were read from.
+    version: (Option<SystemTime>, u64),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Checks `AUTH <user> <token>` lines against a credentials file, and locks out addresses that
+/// keep failing.
+#[derive(Debug)]
+pub struct Auth {
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    max_failures: u32,
+    lockout: Duration,
+}
+
+impl Auth {
+    /// An address is refused for `lockout` once it failed `max_failures` times within it.
+    pub async fn open(path: &Path, max_failures: u32, lockout: Duration) -> AnyResult<Self> {
+        let loaded = load(path).await?;
+        println!(
+            "Auth loaded {} user(s) from {}",
+            loaded.credentials.users.len(),
+            path.display()
+        );
+        Ok(Self {
+            path: path.to_path_buf(),
+            loaded: RwLock::new(loaded),
+            failures: Mutex::new(HashMap::new()),
+            max_failures: max_failures.max(1),
+            lockout,
+        })
+    }
+
+    /// Checks the first line of a client from `addr` and returns the user it authenticated as.
+    ///
+    /// The error tells the client why it is refused without telling it which part was wrong;
+    /// the details are logged.
+    pub async fn verify(&self, line: &str, addr: IpAddr) -> AnyResult<String> {
+        if self.is_locked_out(addr, Instant::now()) {
+            eprintln!("Auth refused for {}: locked out", addr);
+            return Err(AnyError::quick(
+                "Too many failed attempts, try again later",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        self.reload_if_changed().await;
+
+        let mut parts = line.split_whitespace();
+        let checked = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(cmd), Some(user), Some(token), None) if cmd.eq_ignore_ascii_case("AUTH") => {
+                let loaded = self.loaded.read().unwrap();
+                match loaded.credentials.users.get(user) {
+                    Some(expected) if eq_constant_time(expected.as_bytes(), token.as_bytes()) => {
+                        Ok(user.to_string())
+                    }
+                    Some(_) => Err(format!("wrong token for {}", user)),
+                    None => Err(format!("unknown user {}", user)),
+                }
+            }
+            _ => Err("malformed AUTH line".to_string()),
+        };
+
+        match checked {
+            Ok(user) => {
+                self.failures.lock().unwrap().remove(&addr);
+                Ok(user)
+            }
+            Err(reason) => {
+                let count = self.record_failure(addr, Instant::now());
+                eprintln!("Auth failed for {} ({} in a row): {}", addr, count, reason);
+                Err(AnyError::quick(
+                    "Authentication failed, expected AUTH <user> <token>",
+                    anyverr::ErrKind::ValueValidation,
+                ))
+            }
+        }
+    }
+
+    /// Rereads the credentials when the file changed; a broken file keeps the previous ones.
+    async fn reload_if_changed(&self) {
+        let version = match fs::metadata(&self.path).await {
+            Ok(meta) => (meta.modified().ok(), meta.len()),
+            Err(e) => {
+                eprintln!("Auth failed to stat {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        if self.loaded.read().unwrap().version == version {
+            return;
+        }
+        match load(&self.path).await {
+            Ok(loaded) => {
+                println!(
+                    "Auth reloaded {} user(s) from {}",
+                    loaded.credentials.users.len(),
+                    self.path.display()
+                );
+                *self.loaded.write().unwrap() = loaded;
+            }
+            Err(e) => eprintln!("Auth failed to reload {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn is_locked_out(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(&addr) {
+            Some(f) if now.duration_since(f.since) >= self.lockout => {
+                failures.remove(&addr);
+                false
+            }
+            Some(f) => f.count >= self.max_failures,
+            None => false,
+        }
+    }
+
+    /// Counts a failure of `addr` and returns how many it has in the current window.
+    fn record_failure(&self, addr: IpAddr, now: Instant) -> u32 {
+        let mut failures = self.failures.lock().unwrap();
+        if !failures.contains_key(&addr) && failures.len() >= MAX_TRACKED_ADDRS {
+            failures.retain(|_, f| now.duration_since(f.since) < self.lockout);
+        }
+        let entry = failures.entry(addr).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        entry.count += 1;
+        entry.count
+    }
+}
+
+async fn load(path: &Path) -> AnyResult<Loaded> {
+    let meta = fs::metadata(path).await.map_err(AnyError::wrap)?;
+    let content = fs::read_to_string(path).await.map_err(AnyError::wrap)?;
+    Ok(Loaded {
+        credentials: Credentials::parse(path, &content)?,
+        version: (meta.modified().ok(), meta.len()),
+    })
+}
+
+/// Compares two tokens in a time that does not depend on where they differ.
+fn eq_constant_time(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(ext: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("chat-room-auth-{}.{}", nanos, ext))
+    }
+
+    #[test]
+    fn test_parse_credentials() -> AnyResult<()> {
+        let json = Credentials::parse(Path::new("c.json"), r#"{"users":{"alice":"a1"}}"#)?;
+        assert_eq!(json.users["alice"], "a1");
+        let toml = Credentials::parse(Path::new("c.toml"), "[users]\nbob = \"b2\"\n")?;
+        assert_eq!(toml.users["bob"], "b2");
+        assert!(Credentials::parse(Path::new("c.json"), "[users]").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_lockout_and_reload() -> AnyResult<()> {
+        let path = temp_path("json");
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        fs::write(&path, r#"{"users":{"alice":"a1"}}"#)
+            .await
+            .map_err(AnyError::wrap)?;
+        let auth = Auth::open(&path, 2, Duration::from_secs(60)).await?;
+
+        assert_eq!(auth.verify("AUTH alice a1", ip).await?, "alice");
+        assert!(auth.verify("AUTH alice nope", ip).await.is_err());
+        assert!(auth.verify("hello", ip).await.is_err());
+        // locked out, even with the right token
+        assert!(auth.verify("AUTH alice a1", ip).await.is_err());
+        let other: IpAddr = "127.0.0.2".parse().unwrap();
+        assert!(auth.verify("AUTH alice a1", other).await.is_ok());
+        assert!(!auth.is_locked_out(ip, Instant::now() + Duration::from_secs(60)));
+
+        fs::write(&path, r#"{"users":{"alice":"rotated"}}"#)
+            .await
+            .map_err(AnyError::wrap)?;
+        assert!(auth.verify("AUTH alice a1", other).await.is_err());
+        assert_eq!(auth.verify("AUTH alice rotated", other).await?, "alice");
+        let _ = fs::remove_file(&path).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::VecDeque, path::Path};
+
+use anyverr::{AnyError, AnyResult};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use crate::{Msg, MsgKind};
+
+/// The last messages said in the room, replayed to everybody who joins.
+#[derive(Debug)]
+pub struct History {
+    cap: usize,
+    entries: VecDeque<Msg>,
+    /// Feeds the task appending every recorded message to the history file.
+    log: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl History {
+    /// Keeps the last `cap` messages in memory only. `0` disables the history.
+    pub fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            entries: VecDeque::with_capacity(cap),
+            log: None,
+        }
+    }
+
+    /// Loads the last `cap` messages from the JSON-lines file at `path` and appends new ones to it.
+    ///
+    /// Lines that fail to parse are skipped; a missing file starts an empty history.
+    pub async fn open(cap: usize, path: &Path) -> AnyResult<Self> {
+        let mut history = Self::new(cap);
+        match fs::read_to_string(path).await {
+            Ok(content) => {
+                let mut skipped = 0;
+                for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<Msg>(line) {
+                        Ok(msg) => history.push(msg),
+                        Err(_) => skipped += 1,
+                    }
+                }
+                println!(
+                    "History loaded {} message(s) from {}",
+                    history.entries.len(),
+                    path.display()
+                );
+                if skipped > 0 {
+                    eprintln!("History skipped {} malformed line(s)", skipped);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(AnyError::wrap(e)),
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(AnyError::wrap)?;
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            while let Some(line) = rx.recv().await {
+                let res = async {
+                    file.write_all(line.as_bytes()).await?;
+                    file.flush().await
+                };
+                if let Err(e) = res.await {
+                    eprintln!("Failed to append to {}: {}", path.display(), e);
+                }
+            }
+        });
+        history.log = Some(tx);
+        Ok(history)
+    }
+
+    /// Keeps `msg` if it is something said in the room; joins, leaves and renames are not.
+    pub fn record(&mut self, msg: &Msg) {
+        if !matches!(msg.kind, MsgKind::Say(_) | MsgKind::Me(_)) {
+            return;
+        }
+        if let Some(log) = &self.log {
+            match serde_json::to_string(msg) {
+                Ok(line) => {
+                    let _ = log.send(line + "\n");
+                }
+                Err(e) => eprintln!("Failed to serialize history entry: {}", e),
+            }
+        }
+        self.push(msg.clone());
+    }
+
+    /// The kept messages, oldest first.
+    pub fn messages(&self) -> Vec<Msg> {
+        self.entries.iter().cloned().collect()
+    }
+
+    fn push(&mut self, msg: Msg) {
+        if self.cap == 0 {
+            return;
+        }
+        if self.entries.len() == self.cap {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn say(text: &str) -> Msg {
+        Msg {
+            from: 1,
+            user: "ci".into(),
+            kind: MsgKind::Say(text.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_persistence() -> AnyResult<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(AnyError::wrap)?
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("chat-room-history-{}.jsonl", nanos));
+
+        let mut history = History::open(2, &path).await?;
+        for text in ["build #1 ok", "build #2 failed", "build #3 ok"] {
+            history.record(&say(text));
+        }
+        history.record(&Msg {
+            kind: MsgKind::Join,
+            ..say("")
+        });
+        assert_eq!(
+            history.messages(),
+            vec![say("build #2 failed"), say("build #3 ok")]
+        );
+
+        // let the appender catch up before reading the file back
+        drop(history);
+        for _ in 0..100 {
+            let content = fs::read_to_string(&path).await.map_err(AnyError::wrap)?;
+            if content.lines().count() == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let reloaded = History::open(2, &path).await?;
+        let _ = fs::remove_file(&path).await;
+        assert_eq!(
+            reloaded.messages(),
+            vec![say("build #2 failed"), say("build #3 ok")]
+        );
+        Ok(())
+    }
+}
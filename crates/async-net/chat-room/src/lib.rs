@@ -1,29 +1,48 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
 
 use anyverr::{AnyError, AnyResult};
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-    sync::broadcast,
-};
+use tokio::{io::BufReader, net::TcpListener, sync::broadcast::error::RecvError};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Config {
-    ip: String,
-    port: u16,
-}
+mod auth;
+mod history;
+mod msg;
+mod room;
+mod session;
+mod ws;
 
-#[derive(Debug, Clone)]
-pub struct Msg {
-    pub user: String,
-    pub data: String,
-}
+pub use auth::*;
+pub use history::*;
+pub use msg::*;
+pub use room::*;
 
-impl Msg {
-    pub fn to_string(user: String, data: String) -> String {
-        format!("[{}]: {}", user, data)
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub ip: String,
+    pub port: u16,
+    /// Also accept WebSocket clients into the room on this port.
+    pub ws_port: Option<u16>,
+    /// Longest line a client may send, in bytes; longer ones disconnect it.
+    pub max_line_len: usize,
+    /// How many broadcasts a client may fall behind before it lags.
+    pub channel_capacity: usize,
+    /// Lines queued for one client before further ones are skipped.
+    pub outbound_queue: usize,
+    /// What happens to a client that lags.
+    pub lag_policy: LagPolicy,
+    /// Messages replayed to a client when it joins. `0` disables the replay.
+    pub history_len: usize,
+    /// Append every message to this JSON-lines file and reload the history from it at startup.
+    pub history_file: Option<PathBuf>,
+    /// Require `AUTH <user> <token>` as the first line, checked against this JSON or TOML file.
+    ///
+    /// The file is reread when it changes. Without it clients join anonymously.
+    pub auth_file: Option<PathBuf>,
+    /// Failed attempts after which an address is locked out.
+    pub auth_max_failures: u32,
+    /// How long the failed attempts of an address count against it, in milliseconds.
+    pub auth_lockout: u64,
 }
 
 impl Default for Config {
@@ -31,10 +50,43 @@ impl Default for Config {
         Self {
             ip: "127.0.0.1".into(),
             port: 59413,
+            ws_port: None,
+            max_line_len: 4096,
+            channel_capacity: 256,
+            outbound_queue: 64,
+            lag_policy: LagPolicy::Notify,
+            history_len: 100,
+            history_file: None,
+            auth_file: None,
+            auth_max_failures: 5,
+            auth_lockout: 60_000,
         }
     }
 }
 
+/// How to treat a client that reads slower than the room talks.
+///
+/// A lag is either the broadcast channel overtaking the client or its outbound queue being full;
+/// the messages it misses are skipped either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Tell the client how many messages it missed and carry on.
+    #[default]
+    Notify,
+    /// Notify the client, and disconnect it on its `max_lags`-th lag.
+    Disconnect { max_lags: u32 },
+}
+
+/// What every connection shares.
+#[derive(Debug)]
+pub(crate) struct AppState {
+    pub room: Room,
+    pub config: Config,
+    /// `None` when clients join anonymously.
+    pub auth: Option<Auth>,
+}
+
 pub async fn run(config: Config) -> AnyResult<()> {
     let socket_addr_str = format!("{}:{}", config.ip, config.port);
     let socket_addr = SocketAddr::from_str(&socket_addr_str).unwrap();
@@ -46,53 +98,310 @@ pub async fn run(config: Config) -> AnyResult<()> {
     });
 
     println!("Tcp listen on: {}", tcp_listener.local_addr().unwrap());
+    let ws_listener = match config.ws_port {
+        Some(port) => {
+            let ws_listener = TcpListener::bind(format!("{}:{}", config.ip, port))
+                .await
+                .map_err(AnyError::wrap)?;
+            println!(
+                "WebSocket listen on: {}",
+                ws_listener.local_addr().map_err(AnyError::wrap)?
+            );
+            Some(ws_listener)
+        }
+        None => None,
+    };
+    serve(tcp_listener, ws_listener, config).await
+}
 
-    let (tx, mut rx) = broadcast::channel::<String>(2);
+/// Accepts clients on `tcp_listener`, and WebSocket clients on `ws_listener`, into one room.
+pub async fn serve(
+    tcp_listener: TcpListener,
+    ws_listener: Option<TcpListener>,
+    config: Config,
+) -> AnyResult<()> {
+    let history = match &config.history_file {
+        Some(path) => History::open(config.history_len, path).await?,
+        None => History::new(config.history_len),
+    };
+    let auth = match &config.auth_file {
+        Some(path) => Some(
+            Auth::open(
+                path,
+                config.auth_max_failures,
+                Duration::from_millis(config.auth_lockout),
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let state = Arc::new(AppState {
+        room: Room::new(config.channel_capacity, history),
+        config,
+        auth,
+    });
+    let mut rx = state.room.subscribe();
 
     tokio::spawn(async move {
         loop {
-            if let Ok(data) = rx.recv().await {
-                println!("recv rx data: {data}");
+            match rx.recv().await {
+                Ok(msg) => println!("{}", msg),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
             }
         }
     });
 
-    loop {
-        let (mut stream, target) = tcp_listener.accept().await.map_err(|e| AnyError::wrap(e))?;
-        let tx = tx.clone();
-        let mut rx = tx.subscribe();
-
+    if let Some(ws_listener) = ws_listener {
+        let state = state.clone();
         tokio::spawn(async move {
-            let mut buf = vec![0u8; 2048];
-
             loop {
-                tokio::select! {
-                  n = stream.read(&mut buf) => {
-                     match n {
-                         Ok(0) =>{
-                             println!("{} closed", target);
-                             break;
-                         },
-                         Ok(n) => {
-                             let _ = tx.send(Msg::to_string(
-                                 target.to_string(),
-                                 String::from_utf8_lossy(&buf[..n]).into_owned(),
-                             ));
-                         },
-                         Err(e)=>{
-                            eprintln!("Err: failed to send {}",e);
-                         }
-                     }
-                 }
-                 d= rx.recv() => {
-                    if let Ok(data) = d {
-                       if let Err(e) =  stream.write(data.as_bytes()).await{
-                            eprintln!("failed to send recv data: {e}");
-                       };
+                let (stream, target) = match ws_listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept WebSocket connection: {}", e);
+                        continue;
+                    }
+                };
+                let state = state.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = ws::handle(&state, stream, target).await {
+                        eprintln!("Error handling WebSocket connection for {}: {}", target, e);
                     }
-                 }
-                }
+                    println!("{} closed", target);
+                });
             }
         });
     }
+
+    loop {
+        let (stream, target) = tcp_listener.accept().await.map_err(AnyError::wrap)?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            if let Err(e) = session::handle(&state, BufReader::new(reader), writer, target).await {
+                eprintln!("Error handling connection for {}: {}", target, e);
+            }
+            println!("{} closed", target);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, Lines},
+        net::{TcpStream, tcp::OwnedReadHalf, tcp::OwnedWriteHalf},
+    };
+
+    use super::*;
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr, nick: &str) -> Self {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut client = Client {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            };
+            client.expect("* Welcome!").await;
+            client.send(nick).await;
+            client.expect("* Hi").await;
+            client
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn expect(&mut self, prefix: &str) -> String {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            assert!(line.starts_with(prefix), "{:?} !~ {:?}", line, prefix);
+            line
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chat() -> AnyResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let addr = listener.local_addr().map_err(AnyError::wrap)?;
+        tokio::spawn(serve(listener, None, Config::default()));
+
+        let mut alice = Client::connect(addr, "alice").await;
+        let mut bob = Client::connect(addr, "Bob").await;
+        alice.expect("* Bob joined").await;
+
+        // a split write still arrives as one message
+        bob.writer.write_all(b"hel").await.map_err(AnyError::wrap)?;
+        bob.send("lo").await;
+        alice.expect("[Bob]: hello").await;
+        bob.send("/me waves").await;
+        alice.expect("* Bob waves").await;
+
+        bob.send("/nick ALICE").await;
+        bob.expect("* Invalid nickname").await;
+        bob.send("/nick bobby").await;
+        bob.expect("* You are now known as bobby").await;
+        alice.expect("* Bob is now known as bobby").await;
+        bob.send("/who").await;
+        bob.expect("* Online (2): alice, bobby").await;
+
+        bob.send("/quit").await;
+        bob.expect("* Bye!").await;
+        // none of bob's own messages were echoed back to him
+        assert_eq!(bob.lines.next_line().await.map_err(AnyError::wrap)?, None);
+        alice.expect("* bobby left").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history_replay() -> AnyResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let addr = listener.local_addr().map_err(AnyError::wrap)?;
+        let config = Config {
+            history_len: 2,
+            ..Config::default()
+        };
+        tokio::spawn(serve(listener, None, config));
+
+        let mut alice = Client::connect(addr, "alice").await;
+        for line in ["one", "two", "/me three"] {
+            alice.send(line).await;
+        }
+        // lines are handled in order, so everything above is published once this is answered
+        alice.send("/who").await;
+        alice.expect("* Online (1)").await;
+
+        let mut bob = Client::connect(addr, "bob").await;
+        bob.expect("* Last 2 message(s):").await;
+        bob.expect("[alice]: two").await;
+        bob.expect("* alice three").await;
+        bob.expect("* End of history").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_gateway() -> AnyResult<()> {
+        use tokio::io::AsyncReadExt;
+
+        async fn read_frame(ws: &mut TcpStream) -> (u8, Vec<u8>) {
+            let mut header = [0u8; 2];
+            ws.read_exact(&mut header).await.unwrap();
+            // the test only exchanges short frames
+            let mut payload = vec![0u8; (header[1] & 0x7F) as usize];
+            ws.read_exact(&mut payload).await.unwrap();
+            (header[0] & 0x0F, payload)
+        }
+        async fn read_text(ws: &mut TcpStream) -> String {
+            let (opcode, payload) = read_frame(ws).await;
+            assert_eq!(opcode, 0x1);
+            String::from_utf8(payload).unwrap()
+        }
+        async fn write_frame(ws: &mut TcpStream, opcode: u8, payload: &[u8]) {
+            let mask = [0x37, 0xfa, 0x21, 0x3d];
+            let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            ws.write_all(&frame).await.unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let ws_listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let addr = listener.local_addr().map_err(AnyError::wrap)?;
+        let ws_addr = ws_listener.local_addr().map_err(AnyError::wrap)?;
+        tokio::spawn(serve(listener, Some(ws_listener), Config::default()));
+
+        let mut alice = Client::connect(addr, "alice").await;
+        let mut ws = TcpStream::connect(ws_addr).await.map_err(AnyError::wrap)?;
+        ws.write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .map_err(AnyError::wrap)?;
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(ws.read_u8().await.map_err(AnyError::wrap)?);
+        }
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        assert!(read_text(&mut ws).await.starts_with("* Welcome!"));
+        write_frame(&mut ws, 0x1, b"carol").await;
+        assert!(read_text(&mut ws).await.starts_with("* Hi carol"));
+        alice.expect("* carol joined").await;
+
+        write_frame(&mut ws, 0x1, b"hi from the browser").await;
+        alice.expect("[carol]: hi from the browser").await;
+        alice.send("welcome").await;
+        assert_eq!(read_text(&mut ws).await, "[alice]: welcome");
+
+        write_frame(&mut ws, 0x9, b"ping").await;
+        assert_eq!(read_frame(&mut ws).await, (0xA, b"ping".to_vec()));
+        write_frame(&mut ws, 0x8, &1000u16.to_be_bytes()).await;
+        assert_eq!(read_frame(&mut ws).await.0, 0x8);
+        alice.expect("* carol left").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auth() -> AnyResult<()> {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(AnyError::wrap)?
+            .as_nanos();
+        let path = std::env::temp_dir().join(format!("chat-room-users-{}.toml", nanos));
+        tokio::fs::write(&path, "[users]\nalice = \"a1\"\n")
+            .await
+            .map_err(AnyError::wrap)?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let addr = listener.local_addr().map_err(AnyError::wrap)?;
+        let config = Config {
+            auth_file: Some(path.clone()),
+            ..Config::default()
+        };
+        tokio::spawn(serve(listener, None, config));
+
+        let mut alice = Client::connect(addr, "AUTH alice a1").await;
+        alice.send("/nick bob").await;
+        alice.expect("* Nicknames are fixed").await;
+
+        let (reader, mut writer) = TcpStream::connect(addr)
+            .await
+            .map_err(AnyError::wrap)?
+            .into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"AUTH alice wrong\n")
+            .await
+            .map_err(AnyError::wrap)?;
+        let line = lines.next_line().await.map_err(AnyError::wrap)?;
+        assert!(line.is_some_and(|l| l.starts_with("* Welcome! Authenticate")));
+        let line = lines.next_line().await.map_err(AnyError::wrap)?;
+        assert!(line.is_some_and(|l| l.starts_with("* Authentication failed")));
+        assert_eq!(lines.next_line().await.map_err(AnyError::wrap)?, None);
+
+        let _ = tokio::fs::remove_file(&path).await;
+        Ok(())
+    }
 }
@@ -3,7 +3,10 @@ use chat_room::Config;
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let config = Config::default();
+    let config = Config {
+        ws_port: Some(59423),
+        ..Config::default()
+    };
     chat_room::run(config).await?;
     Ok(())
 }
@@ -0,0 +1,117 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyverr::AnyError;
+use serde::{Deserialize, Serialize};
+
+/// Identifies one connection for as long as it is in the room.
+pub type ClientId = u64;
+
+/// One event broadcast to everybody in the room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Msg {
+    /// The connection the event originates from; it is never delivered back to it.
+    pub from: ClientId,
+    pub user: String,
+    pub kind: MsgKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MsgKind {
+    Say(String),
+    /// `/me waves` is shown as `* alice waves`.
+    Me(String),
+    Join,
+    Leave,
+    /// Renamed from the given nickname.
+    Nick(String),
+}
+
+impl Display for Msg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            MsgKind::Say(text) => write!(f, "[{}]: {}", self.user, text),
+            MsgKind::Me(text) => write!(f, "* {} {}", self.user, text),
+            MsgKind::Join => write!(f, "* {} joined", self.user),
+            MsgKind::Leave => write!(f, "* {} left", self.user),
+            MsgKind::Nick(old) => write!(f, "* {} is now known as {}", old, self.user),
+        }
+    }
+}
+
+/// One line sent by a client after the nickname handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Say(String),
+    Nick(String),
+    Who,
+    Me(String),
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(cmd) = s.strip_prefix('/') else {
+            return Ok(Command::Say(s.to_string()));
+        };
+        let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+        let arg = arg.trim();
+        let required = |usage: &str| {
+            if arg.is_empty() {
+                Err(AnyError::quick(
+                    format!("Usage: {}", usage),
+                    anyverr::ErrKind::ValueValidation,
+                ))
+            } else {
+                Ok(arg.to_string())
+            }
+        };
+
+        match name.to_lowercase().as_str() {
+            "nick" => required("/nick <name>").map(Command::Nick),
+            "me" => required("/me <action>").map(Command::Me),
+            "who" => Ok(Command::Who),
+            "quit" => Ok(Command::Quit),
+            _ => Err(AnyError::quick(
+                format!(
+                    "Unknown command /{}. Commands: /nick, /who, /me, /quit",
+                    name
+                ),
+                anyverr::ErrKind::ValueValidation,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let parse = |s: &str| s.parse::<Command>().ok();
+        assert_eq!(parse("hello /who"), Some(Command::Say("hello /who".into())));
+        assert_eq!(parse("/nick  bob "), Some(Command::Nick("bob".into())));
+        assert_eq!(parse("/ME waves"), Some(Command::Me("waves".into())));
+        assert_eq!(parse("/who"), Some(Command::Who));
+        assert_eq!(parse("/quit"), Some(Command::Quit));
+        assert_eq!(parse("/nick"), None);
+        assert_eq!(parse("/dance"), None);
+    }
+
+    #[test]
+    fn test_display_msg() {
+        let msg = |kind| Msg {
+            from: 1,
+            user: "bob".into(),
+            kind,
+        };
+        assert_eq!(msg(MsgKind::Say("hi".into())).to_string(), "[bob]: hi");
+        assert_eq!(msg(MsgKind::Me("waves".into())).to_string(), "* bob waves");
+        assert_eq!(
+            msg(MsgKind::Nick("alice".into())).to_string(),
+            "* alice is now known as bob"
+        );
+    }
+}
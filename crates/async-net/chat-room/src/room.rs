@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyverr::{AnyError, AnyResult};
+use tokio::sync::broadcast;
+
+use crate::{ClientId, History, Msg, MsgKind};
+
+const MAX_NICK_LEN: usize = 24;
+
+#[derive(Debug)]
+struct Member {
+    nick: String,
+    addr: SocketAddr,
+}
+
+/// The members of the room and the channel their messages are broadcast on.
+#[derive(Debug)]
+pub struct Room {
+    tx: broadcast::Sender<Msg>,
+    next_id: AtomicU64,
+    members: Mutex<HashMap<ClientId, Member>>,
+    /// Also held while publishing, so a replay and a subscription never overlap or leave a gap.
+    history: Mutex<History>,
+}
+
+impl Room {
+    /// `capacity` is how many messages a member may fall behind before it lags.
+    pub fn new(capacity: usize, history: History) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        Self {
+            tx,
+            next_id: AtomicU64::new(1),
+            members: Mutex::new(HashMap::new()),
+            history: Mutex::new(history),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Msg> {
+        self.tx.subscribe()
+    }
+
+    /// Subscribes and returns the history up to the first message the subscription receives.
+    pub fn subscribe_with_history(&self) -> (Vec<Msg>, broadcast::Receiver<Msg>) {
+        let history = self.history.lock().unwrap();
+        (history.messages(), self.tx.subscribe())
+    }
+
+    pub fn publish(&self, msg: Msg) {
+        let mut history = self.history.lock().unwrap();
+        history.record(&msg);
+        // nobody listening is not an error
+        let _ = self.tx.send(msg);
+    }
+
+    /// Adds a member under `nick` and announces it to the others.
+    pub fn join(&self, nick: &str, addr: SocketAddr) -> AnyResult<ClientId> {
+        let mut members = self.members.lock().unwrap();
+        check_nick(&members, nick, None)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        members.insert(
+            id,
+            Member {
+                nick: nick.to_string(),
+                addr,
+            },
+        );
+        println!("{} joined as {}", addr, nick);
+        self.publish(Msg {
+            from: id,
+            user: nick.to_string(),
+            kind: MsgKind::Join,
+        });
+        Ok(id)
+    }
+
+    /// Changes the nickname of `id`, announces it and returns the old one.
+    pub fn rename(&self, id: ClientId, nick: &str) -> AnyResult<String> {
+        let mut members = self.members.lock().unwrap();
+        check_nick(&members, nick, Some(id))?;
+        let member = members
+            .get_mut(&id)
+            .ok_or_else(|| AnyError::quick("Not in the room", anyverr::ErrKind::RuleViolation))?;
+        let old = std::mem::replace(&mut member.nick, nick.to_string());
+        self.publish(Msg {
+            from: id,
+            user: nick.to_string(),
+            kind: MsgKind::Nick(old.clone()),
+        });
+        Ok(old)
+    }
+
+    /// Removes `id` and announces it to the others.
+    pub fn leave(&self, id: ClientId) {
+        let Some(member) = self.members.lock().unwrap().remove(&id) else {
+            return;
+        };
+        println!("{} left as {}", member.addr, member.nick);
+        self.publish(Msg {
+            from: id,
+            user: member.nick,
+            kind: MsgKind::Leave,
+        });
+    }
+
+    /// Nicknames of everybody in the room, sorted.
+    pub fn who(&self) -> Vec<String> {
+        let mut nicks: Vec<_> = self
+            .members
+            .lock()
+            .unwrap()
+            .values()
+            .map(|m| m.nick.clone())
+            .collect();
+        nicks.sort_unstable_by_key(|n| n.to_lowercase());
+        nicks
+    }
+}
+
+/// Validates `nick` and makes sure no member other than `except` uses it.
+fn check_nick(
+    members: &HashMap<ClientId, Member>,
+    nick: &str,
+    except: Option<ClientId>,
+) -> AnyResult<()> {
+    if nick.is_empty() || nick.chars().count() > MAX_NICK_LEN {
+        return Err(AnyError::quick(
+            format!("Nicknames are 1 to {} characters long", MAX_NICK_LEN),
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    if !nick
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(AnyError::quick(
+            "Nicknames may only contain letters, digits, '-' and '_'",
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    if members
+        .iter()
+        .any(|(id, m)| Some(*id) != except && m.nick.eq_ignore_ascii_case(nick))
+    {
+        return Err(AnyError::quick(
+            format!("{} is already taken", nick),
+            anyverr::ErrKind::RuleViolation,
+        ));
+    }
+    Ok(())
+}
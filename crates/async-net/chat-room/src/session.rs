@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+
+use anyverr::{AnyError, AnyResult};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, error::TrySendError},
+    },
+};
+
+use crate::{AppState, ClientId, Command, LagPolicy, Msg, MsgKind};
+
+const HELP: &str = "Commands: /nick <name>, /who, /me <action>, /quit";
+
+/// Runs one client: authentication or the nickname handshake, then its commands until it quits
+/// or disconnects.
+///
+/// Output goes through a bounded queue drained by a writer task of its own, so a client that
+/// stops reading only ever lags itself.
+pub(crate) async fn handle<R, W>(
+    state: &AppState,
+    mut reader: R,
+    writer: W,
+    addr: SocketAddr,
+) -> AnyResult<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (tx, rx) = mpsc::channel(state.config.outbound_queue.max(1));
+    let writer_task = tokio::spawn(write_lines(writer, rx));
+    let mut out = Outbox::new(tx, state.config.lag_policy);
+
+    let res = session(state, &mut reader, &mut out, addr).await;
+    if out.too_slow {
+        // whatever is still queued would never reach it anyway
+        writer_task.abort();
+        return res;
+    }
+    drop(out);
+    let written = writer_task.await.map_err(AnyError::wrap)?;
+    res.and(written)
+}
+
+async fn session<R>(
+    state: &AppState,
+    reader: &mut R,
+    out: &mut Outbox,
+    addr: SocketAddr,
+) -> AnyResult<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let max_line_len = state.config.max_line_len;
+    let mut buf = Vec::new();
+    let (id, nick) = match &state.auth {
+        Some(auth) => {
+            out.push("* Welcome! Authenticate with: AUTH <user> <token>".into())?;
+            let Some(line) = read_line(reader, &mut buf, max_line_len).await? else {
+                return Ok(());
+            };
+            let joined = match auth.verify(&line, addr.ip()).await {
+                Ok(user) => state.room.join(&user, addr).map(|id| (id, user)),
+                Err(e) => Err(e),
+            };
+            match joined {
+                Ok(joined) => joined,
+                Err(e) => {
+                    out.push(format!("* {}", e))?;
+                    return Ok(());
+                }
+            }
+        }
+        None => {
+            out.push("* Welcome! Pick a nickname:".into())?;
+            loop {
+                let Some(nick) = read_line(reader, &mut buf, max_line_len).await? else {
+                    return Ok(());
+                };
+                let nick = nick.trim().to_string();
+                match state.room.join(&nick, addr) {
+                    Ok(id) => break (id, nick),
+                    Err(e) => out.push(format!("* Invalid nickname: {}", e))?,
+                }
+            }
+        }
+    };
+
+    let res = chat(state, reader, out, &mut buf, id, nick).await;
+    state.room.leave(id);
+    res
+}
+
+async fn chat<R>(
+    state: &AppState,
+    reader: &mut R,
+    out: &mut Outbox,
+    buf: &mut Vec<u8>,
+    id: ClientId,
+    mut nick: String,
+) -> AnyResult<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let room = &state.room;
+    let max_line_len = state.config.max_line_len;
+    let (history, mut rx) = room.subscribe_with_history();
+    out.push(format!(
+        "* Hi {}, {} online. {}",
+        nick,
+        room.who().len(),
+        HELP
+    ))?;
+    if !history.is_empty() {
+        // the replay may be longer than the queue, so wait for room instead of skipping
+        out.send(format!("* Last {} message(s):", history.len()))
+            .await?;
+        for msg in history {
+            out.send(msg.to_string()).await?;
+        }
+        out.send("* End of history".into()).await?;
+    }
+
+    loop {
+        tokio::select! {
+            line = read_line(reader, buf, max_line_len) => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let kind = match line.parse::<Command>() {
+                    Ok(Command::Say(text)) => MsgKind::Say(text),
+                    Ok(Command::Me(text)) => MsgKind::Me(text),
+                    Ok(Command::Nick(_)) if state.auth.is_some() => {
+                        out.push("* Nicknames are fixed to the authenticated user".into())?;
+                        continue;
+                    }
+                    Ok(Command::Nick(new)) => {
+                        match room.rename(id, &new) {
+                            Ok(_) => {
+                                out.push(format!("* You are now known as {}", new))?;
+                                nick = new;
+                            }
+                            Err(e) => out.push(format!("* Invalid nickname: {}", e))?,
+                        }
+                        continue;
+                    }
+                    Ok(Command::Who) => {
+                        let who = room.who();
+                        out.push(format!("* Online ({}): {}", who.len(), who.join(", ")))?;
+                        continue;
+                    }
+                    Ok(Command::Quit) => {
+                        out.push("* Bye!".into())?;
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        out.push(format!("* {}", e))?;
+                        continue;
+                    }
+                };
+                room.publish(Msg { from: id, user: nick.clone(), kind });
+            }
+            msg = rx.recv() => match msg {
+                Ok(msg) if msg.from == id => {}
+                Ok(msg) => out.push(msg.to_string())?,
+                Err(RecvError::Lagged(n)) => out.lagged(n)?,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // the writer failed; its error is reported once it is joined
+            _ = out.tx.closed() => return Ok(()),
+        }
+    }
+}
+
+/// The bounded queue of lines for one client, with the accounting of what it missed.
+struct Outbox {
+    tx: mpsc::Sender<String>,
+    policy: LagPolicy,
+    /// Messages skipped since the client was last told about it.
+    skipped: u64,
+    lags: u32,
+    /// Set once the lag policy gave up on the client.
+    too_slow: bool,
+}
+
+impl Outbox {
+    fn new(tx: mpsc::Sender<String>, policy: LagPolicy) -> Self {
+        Self {
+            tx,
+            policy,
+            skipped: 0,
+            lags: 0,
+            too_slow: false,
+        }
+    }
+
+    /// Queues `line`, or skips it when the queue is full.
+    ///
+    /// Pending skips are reported ahead of the next line that fits.
+    fn push(&mut self, line: String) -> AnyResult<()> {
+        if self.skipped > 0 {
+            let notice = format!(
+                "* {} message(s) skipped, you are reading too slowly",
+                self.skipped
+            );
+            match self.tx.try_send(notice) {
+                Ok(()) => self.skipped = 0,
+                Err(TrySendError::Full(_)) => return self.lagged(1),
+                Err(TrySendError::Closed(_)) => return Err(Self::closed()),
+            }
+        }
+        match self.tx.try_send(line) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => self.lagged(1),
+            Err(TrySendError::Closed(_)) => Err(Self::closed()),
+        }
+    }
+
+    /// Queues `line`, waiting for a free slot instead of skipping it.
+    async fn send(&mut self, line: String) -> AnyResult<()> {
+        self.tx.send(line).await.map_err(|_| Self::closed())
+    }
+
+    /// Records `n` missed messages and applies the lag policy.
+    fn lagged(&mut self, n: u64) -> AnyResult<()> {
+        self.skipped += n;
+        self.lags += 1;
+        match self.policy {
+            LagPolicy::Disconnect { max_lags } if self.lags >= max_lags => {
+                self.too_slow = true;
+                Err(AnyError::quick(
+                    format!("Disconnected after lagging {} times", self.lags),
+                    anyverr::ErrKind::RuleViolation,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn closed() -> AnyError {
+        AnyError::quick("Client stopped receiving", anyverr::ErrKind::RuleViolation)
+    }
+}
+
+async fn write_lines<W>(mut writer: W, mut rx: mpsc::Receiver<String>) -> AnyResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(line) = rx.recv().await {
+        writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .map_err(AnyError::wrap)?;
+    }
+    writer.shutdown().await.map_err(AnyError::wrap)
+}
+
+/// Reads one `\n`-terminated line without its terminator, `None` at the end of the stream.
+///
+/// Cancel safe: a partial line is kept in `buf` and completed by the next call.
+///
+/// ct-room's `LineReader` is a deliberate copy of this, since the two examples share no crate;
+/// keep them in step.
+async fn read_line<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> AnyResult<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // one byte past the limit tells an over-long line from one that is exactly `max_len`
+    let limit = (max_len + 1).saturating_sub(buf.len()) as u64;
+    let n = (&mut *reader)
+        .take(limit)
+        .read_until(b'\n', buf)
+        .await
+        .map_err(AnyError::wrap)?;
+    if n == 0 && buf.is_empty() {
+        return Ok(None);
+    }
+    if buf.last() != Some(&b'\n') && buf.len() > max_len {
+        return Err(AnyError::quick(
+            format!("Line longer than {} bytes", max_len),
+            anyverr::ErrKind::RuleViolation,
+        ));
+    }
+    // anything else without a terminator is the last line before the end of the stream
+    let line = String::from_utf8_lossy(buf)
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    buf.clear();
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_notify() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut out = Outbox::new(tx, LagPolicy::Notify);
+        for line in ["a", "b", "c", "d"] {
+            assert!(out.push(line.into()).is_ok());
+        }
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        assert_eq!(rx.try_recv().unwrap(), "b");
+        assert!(out.lagged(3).is_ok());
+        assert!(out.push("e".into()).is_ok());
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            "* 5 message(s) skipped, you are reading too slowly"
+        );
+        assert_eq!(rx.try_recv().unwrap(), "e");
+        assert!(!out.too_slow);
+    }
+
+    #[test]
+    fn test_outbox_disconnect() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut out = Outbox::new(tx, LagPolicy::Disconnect { max_lags: 2 });
+        assert!(out.push("a".into()).is_ok());
+        assert!(out.push("b".into()).is_ok());
+        assert_eq!(rx.try_recv().unwrap(), "a");
+        // the notice takes the free slot, so the line itself lags again
+        assert!(out.push("c".into()).is_err());
+        assert!(out.too_slow);
+    }
+}
@@ -0,0 +1,380 @@
+//! A minimal RFC 6455 server: the opening handshake, text messages, ping and close.
+//!
+//! Every text message is turned into chat lines, so WebSocket clients run the same session as
+//! TCP clients and share their room.
+
+use std::net::SocketAddr;
+
+use anyverr::{AnyError, AnyResult};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+use crate::{AppState, session};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_LEN: usize = 8 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Served to plain GET requests, so a browser tab pointed at the gateway can join the room.
+const CLIENT_PAGE: &str = r#"<!doctype html>
+<meta charset="utf-8">
+<title>chat-room</title>
+<pre id="log" style="height:80vh;overflow:auto"></pre>
+<input id="line" style="width:100%" autofocus placeholder="type a line and press enter">
+<script>
+  const log = document.getElementById("log");
+  const line = document.getElementById("line");
+  const ws = new WebSocket(`ws://${location.host}/`);
+  const show = (text) => { log.textContent += text + "\n"; log.scrollTop = log.scrollHeight; };
+  ws.onmessage = (e) => show(e.data);
+  ws.onclose = () => show("* Disconnected");
+  line.onkeydown = (e) => {
+    if (e.key === "Enter") { ws.send(line.value); line.value = ""; }
+  };
+</script>
+"#;
+
+/// Upgrades `stream` to a WebSocket and runs a chat session over it.
+pub(crate) async fn handle(state: &AppState, stream: TcpStream, addr: SocketAddr) -> AnyResult<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let Some(buf) = handshake(&mut reader, &mut writer).await? else {
+        return Ok(());
+    };
+
+    // the session speaks lines on one end of the pipe, the gateway speaks frames on the socket
+    let (session_side, gateway_side) = tokio::io::duplex(64 * 1024);
+    let (session_r, session_w) = tokio::io::split(session_side);
+    let (res, pumped) = tokio::join!(
+        session::handle(state, BufReader::new(session_r), session_w, addr),
+        pump(reader, writer, gateway_side, buf, state.config.max_line_len),
+    );
+    res.and(pumped)
+}
+
+/// Answers the opening handshake and returns whatever the client sent after it.
+///
+/// Requests without `Upgrade: websocket` are answered with the browser client page and `None`.
+async fn handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+) -> AnyResult<Option<Vec<u8>>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if reader.read_buf(&mut buf).await.map_err(AnyError::wrap)? == 0 {
+            return Err(AnyError::quick(
+                "Closed during the WebSocket handshake",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(&buf).map_err(AnyError::wrap)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial if buf.len() < MAX_HANDSHAKE_LEN => continue,
+            httparse::Status::Partial => {
+                return reject(writer, "Handshake too large").await;
+            }
+        };
+
+        let header = |name: &str| {
+            req.headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| String::from_utf8_lossy(h.value).into_owned())
+                .unwrap_or_default()
+        };
+        let has_token = |value: String, token: &str| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        if req.method != Some("GET") {
+            return reject(writer, "Only GET is supported").await;
+        }
+        if !has_token(header("Upgrade"), "websocket") {
+            send_page(writer).await?;
+            return Ok(None);
+        }
+        if !has_token(header("Connection"), "upgrade") {
+            return reject(writer, "Missing Connection: Upgrade").await;
+        }
+        if header("Sec-WebSocket-Version").trim() != "13" {
+            return reject(writer, "Unsupported Sec-WebSocket-Version, expected 13").await;
+        }
+        let key = header("Sec-WebSocket-Key");
+        let key = key.trim();
+        if BASE64.decode(key).map(|k| k.len()) != Ok(16) {
+            return reject(writer, "Invalid Sec-WebSocket-Key").await;
+        }
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        writer
+            .write_all(response.as_bytes())
+            .await
+            .map_err(AnyError::wrap)?;
+        return Ok(Some(buf.split_off(len)));
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+async fn reject<T>(writer: &mut OwnedWriteHalf, reason: &str) -> AnyResult<T> {
+    let response = format!(
+        "HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reason.len(),
+        reason
+    );
+    let _ = writer.write_all(response.as_bytes()).await;
+    Err(AnyError::quick(
+        format!("WebSocket handshake rejected: {}", reason),
+        anyverr::ErrKind::ValueValidation,
+    ))
+}
+
+async fn send_page(writer: &mut OwnedWriteHalf) -> AnyResult<()> {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        CLIENT_PAGE.len(),
+        CLIENT_PAGE
+    );
+    writer
+        .write_all(response.as_bytes())
+        .await
+        .map_err(AnyError::wrap)
+}
+
+/// Moves text messages from the socket to the session as lines, and lines back as text frames.
+async fn pump(
+    mut reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    gateway: tokio::io::DuplexStream,
+    mut buf: Vec<u8>,
+    max_len: usize,
+) -> AnyResult<()> {
+    let (from_session, mut to_session) = tokio::io::split(gateway);
+    let mut lines = BufReader::new(from_session).lines();
+    // the text of a fragmented message, until its final frame
+    let mut message: Option<Vec<u8>> = None;
+
+    loop {
+        while let Some(frame) = Frame::parse(&mut buf, max_len)? {
+            match frame.opcode {
+                OP_TEXT | OP_CONTINUATION => {
+                    match (frame.opcode, message.as_mut()) {
+                        (OP_TEXT, None) => message = Some(frame.payload),
+                        (OP_CONTINUATION, Some(text)) => text.extend_from_slice(&frame.payload),
+                        _ => return close(&mut writer, CLOSE_PROTOCOL_ERROR).await,
+                    }
+                    if message.as_ref().map_or(0, Vec::len) > max_len {
+                        return close(&mut writer, CLOSE_TOO_BIG).await;
+                    }
+                    if !frame.fin {
+                        continue;
+                    }
+                    let Ok(text) = String::from_utf8(message.take().unwrap_or_default()) else {
+                        return close(&mut writer, CLOSE_INVALID_DATA).await;
+                    };
+                    for line in text.lines() {
+                        to_session
+                            .write_all(format!("{}\n", line).as_bytes())
+                            .await
+                            .map_err(AnyError::wrap)?;
+                    }
+                }
+                OP_PING => write_frame(&mut writer, OP_PONG, &frame.payload).await?,
+                OP_PONG => {}
+                OP_CLOSE => return close(&mut writer, CLOSE_NORMAL).await,
+                OP_BINARY => return close(&mut writer, CLOSE_UNSUPPORTED).await,
+                _ => return close(&mut writer, CLOSE_PROTOCOL_ERROR).await,
+            }
+        }
+
+        tokio::select! {
+            n = reader.read_buf(&mut buf) => {
+                if n.map_err(AnyError::wrap)? == 0 {
+                    return Ok(());
+                }
+            }
+            line = lines.next_line() => match line.map_err(AnyError::wrap)? {
+                Some(line) => write_frame(&mut writer, OP_TEXT, line.as_bytes()).await?,
+                // the session is over
+                None => return close(&mut writer, CLOSE_NORMAL).await,
+            },
+        }
+    }
+}
+
+async fn close(writer: &mut OwnedWriteHalf, code: u16) -> AnyResult<()> {
+    write_frame(writer, OP_CLOSE, &code.to_be_bytes()).await?;
+    writer.shutdown().await.map_err(AnyError::wrap)
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> AnyResult<()> {
+    writer
+        .write_all(&Frame::encode(opcode, payload))
+        .await
+        .map_err(AnyError::wrap)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Takes the first complete client frame off `buf`, `None` until one has arrived.
+    fn parse(buf: &mut Vec<u8>, max_len: usize) -> AnyResult<Option<Frame>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 || !masked {
+            return Err(AnyError::quick(
+                "Client frames must be masked and carry no extension bits",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let (len, mut offset) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        let is_control = opcode & 0x8 != 0;
+        if (is_control && (len > 125 || !fin)) || len > max_len as u64 {
+            return Err(AnyError::quick(
+                format!("Frame of {} bytes exceeds the limit", len),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let len = len as usize;
+        if buf.len() < offset + 4 + len {
+            return Ok(None);
+        }
+        let mask = [
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ];
+        offset += 4;
+        let payload = buf[offset..offset + len]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        buf.drain(..offset + len);
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    /// A single unmasked server frame.
+    fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // the example of RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_parse_frame() -> AnyResult<()> {
+        let mut buf = masked(OP_TEXT, false, b"hel");
+        buf.extend(masked(OP_CONTINUATION, true, b"lo"));
+        let split = buf.split_off(3);
+        assert_eq!(Frame::parse(&mut buf, 100)?, None);
+        buf.extend(split);
+        let first = Frame::parse(&mut buf, 100)?.unwrap();
+        assert_eq!(
+            (first.fin, first.opcode, &first.payload[..]),
+            (false, OP_TEXT, &b"hel"[..])
+        );
+        let second = Frame::parse(&mut buf, 100)?.unwrap();
+        assert_eq!((second.fin, &second.payload[..]), (true, &b"lo"[..]));
+        assert!(buf.is_empty());
+
+        let mut unmasked = Frame::encode(OP_TEXT, b"hi");
+        assert!(Frame::parse(&mut unmasked, 100).is_err());
+        let mut too_big = masked(OP_TEXT, true, &[b'x'; 20]);
+        assert!(Frame::parse(&mut too_big, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(Frame::encode(OP_TEXT, b"hi"), vec![0x81, 2, b'h', b'i']);
+        let long = Frame::encode(OP_TEXT, &[0; 300]);
+        assert_eq!(&long[..4], &[0x81, 126, 0x01, 0x2C]);
+        assert_eq!(long.len(), 304);
+    }
+}
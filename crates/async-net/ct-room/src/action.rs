@@ -0,0 +1,521 @@
+use std::str::FromStr;
+
+use anyverr::{AnyError, AnyResult};
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+const MAX_NICK_LEN: usize = 24;
+
+/// 一条指令的名字、用法和说明，`.help` 和用法错误都从这里取
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "create",
+        usage: ".create <name> [topic]",
+        help: "Create a room and join it as its owner.",
+    },
+    CommandSpec {
+        name: "secure",
+        usage: ".secure <name> [topic]",
+        help: "Create an end-to-end encrypted room; members need its passphrase and \
+               a client that seals messages.",
+    },
+    CommandSpec {
+        name: "join",
+        usage: ".join <name|id>",
+        help: "Join a room by name or by the ID shown by .list.",
+    },
+    CommandSpec {
+        name: "list",
+        usage: ".list",
+        help: "List the active rooms.",
+    },
+    CommandSpec {
+        name: "msg",
+        usage: ".msg <user> <text>",
+        help: "Send a private message; it waits in a mailbox while the user is offline.",
+    },
+    CommandSpec {
+        name: "nick",
+        usage: ".nick <name>",
+        help: "Change your nickname; your room is told about it.",
+    },
+    CommandSpec {
+        name: "who",
+        usage: ".who",
+        help: "List the members of the room and how long they have been idle.",
+    },
+    CommandSpec {
+        name: "typing",
+        usage: ".typing",
+        help: "Tell the room you are typing; it fades out after a few seconds.",
+    },
+    CommandSpec {
+        name: "topic",
+        usage: ".topic [text]",
+        help: "Show the topic of the room, or set it.",
+    },
+    CommandSpec {
+        name: "kick",
+        usage: ".kick <user>",
+        help: "Remove a member from the room. Owner only.",
+    },
+    CommandSpec {
+        name: "ban",
+        usage: ".ban <user> [ip]",
+        help: "Keep a user out of the room by nickname, online or not; with ip, \
+               their current IP too. Owner only.",
+    },
+    CommandSpec {
+        name: "lock",
+        usage: ".lock",
+        help: "Stop new members from joining. Owner only.",
+    },
+    CommandSpec {
+        name: "unlock",
+        usage: ".unlock",
+        help: "Let new members join again. Owner only.",
+    },
+    CommandSpec {
+        name: "resume",
+        usage: ".resume <token>",
+        help: "Send instead of a nickname to pick up a dropped session, with its room \
+               and the messages you missed.",
+    },
+    CommandSpec {
+        name: "help",
+        usage: ".help [command]",
+        help: "Show all commands, or the details of one.",
+    },
+    CommandSpec {
+        name: "quit",
+        usage: ".quit",
+        help: "Leave the room, or disconnect when in the lobby.",
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    let name = name.strip_prefix('.').unwrap_or(name);
+    COMMANDS.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+/// `.help` 的内容，`cmd` 为空时列出所有指令
+pub fn help(cmd: Option<&str>) -> AnyResult<String> {
+    match cmd {
+        None => {
+            let mut text = "Commands:\n".to_string();
+            for c in COMMANDS {
+                text.push_str(&format!("  {:<24} {}\n", c.usage, c.help));
+            }
+            text.push_str(
+                "Anything else is said in the room; \
+                 start a line with .. to say something beginning with a dot.\n",
+            );
+            Ok(text)
+        }
+        Some(cmd) => find_command(cmd)
+            .map(|c| format!("{}\n  {}\n", c.usage, c.help))
+            .ok_or_else(|| unknown_command(cmd)),
+    }
+}
+
+fn unknown_command(cmd: &str) -> AnyError {
+    AnyError::quick(
+        format!(
+            "there is no command .{}, see .help",
+            cmd.strip_prefix('.').unwrap_or(cmd)
+        ),
+        anyverr::ErrKind::EntityAbsence,
+    )
+}
+
+/// 把错误按种类说成一句话发给用户
+pub fn describe(e: &AnyError) -> String {
+    let prefix = match e.kind() {
+        anyverr::ErrKind::ValueValidation => "Invalid input",
+        anyverr::ErrKind::RuleViolation => "Not allowed",
+        anyverr::ErrKind::EntityAbsence => "Not found",
+        _ => "Error",
+    };
+    format!("{}: {}\n", prefix, e.message())
+}
+
+/// 客户端发来的一行
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// 在房间里说的话
+    Say(String),
+    Command(Action),
+}
+
+impl FromStr for Input {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `..` 开头的行去掉一个点之后原样发出去；指令前面可以有空白，普通消息不裁剪
+        let start = s.trim_start();
+        if let Some(text) = start.strip_prefix('.').filter(|t| t.starts_with('.')) {
+            Ok(Input::Say(text.to_string()))
+        } else if start.starts_with('.') {
+            s.parse().map(Input::Command)
+        } else {
+            Ok(Input::Say(s.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Create {
+        name: String,
+        topic: Option<String>,
+        /// 端到端加密的房间只转发密文
+        encrypted: bool,
+    },
+    /// 房间名或 ID
+    Join(String),
+    Quit,
+    List,
+    /// 不带参数时查看话题
+    Topic(Option<String>),
+    Kick(String),
+    /// `ip` 时连同对方当前的 IP 一起封禁
+    Ban {
+        user: String,
+        ip: bool,
+    },
+    Lock,
+    Unlock,
+    Msg {
+        to: String,
+        text: String,
+    },
+    Nick(String),
+    Who,
+    Typing,
+    /// 登录时用会话令牌代替昵称
+    Resume(String),
+    Help(Option<String>),
+}
+
+impl FromStr for Action {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let Some(line) = s.strip_prefix('.') else {
+            return Err(AnyError::quick(
+                "commands start with '.'",
+                anyverr::ErrKind::ValueValidation,
+            ));
+        };
+        let tokens = tokenize(line)?;
+        let Some((cmd, args)) = tokens.split_first() else {
+            return Err(unknown_command(""));
+        };
+        // 只有指令本身不区分大小写，参数保持原样
+        let spec = find_command(&cmd.text).ok_or_else(|| unknown_command(&cmd.text))?;
+        let usage = || {
+            AnyError::quick(
+                format!("expected {}", spec.usage),
+                anyverr::ErrKind::ValueValidation,
+            )
+        };
+        let arity = |min: usize, max: usize| {
+            if (min..=max).contains(&args.len()) {
+                Ok(())
+            } else {
+                Err(usage())
+            }
+        };
+        // 最后一个参数可以带空格：单独一个带引号的参数取引号里的内容，否则取这一行剩下的原文
+        let rest = |i: usize| {
+            args.get(i).map(|first| match args.len() - i {
+                1 if first.quoted => first.text.clone(),
+                _ => line[first.start..].trim_end().to_string(),
+            })
+        };
+
+        match spec.name {
+            "create" | "secure" => {
+                arity(1, usize::MAX)?;
+                let name = args[0].text.clone();
+                validate_room_name(&name)?;
+                Ok(Action::Create {
+                    name,
+                    topic: rest(1),
+                    encrypted: spec.name == "secure",
+                })
+            }
+            "join" => {
+                arity(1, 1)?;
+                Ok(Action::Join(args[0].text.clone()))
+            }
+            "list" => arity(0, 0).map(|_| Action::List),
+            "msg" => {
+                arity(2, usize::MAX)?;
+                let to = args[0].text.clone();
+                validate_nick(&to)?;
+                Ok(Action::Msg {
+                    to,
+                    text: rest(1).unwrap_or_default(),
+                })
+            }
+            "nick" => {
+                arity(1, 1)?;
+                let nick = args[0].text.clone();
+                validate_nick(&nick)?;
+                Ok(Action::Nick(nick))
+            }
+            "who" => arity(0, 0).map(|_| Action::Who),
+            "typing" => arity(0, 0).map(|_| Action::Typing),
+            "topic" => Ok(Action::Topic(rest(0))),
+            "kick" => {
+                arity(1, 1)?;
+                let user = args[0].text.clone();
+                validate_nick(&user)?;
+                Ok(Action::Kick(user))
+            }
+            "ban" => {
+                arity(1, 2)?;
+                let user = args[0].text.clone();
+                validate_nick(&user)?;
+                let ip = match args.get(1) {
+                    Some(arg) if arg.text.eq_ignore_ascii_case("ip") => true,
+                    Some(_) => return Err(usage()),
+                    None => false,
+                };
+                Ok(Action::Ban { user, ip })
+            }
+            "lock" => arity(0, 0).map(|_| Action::Lock),
+            "unlock" => arity(0, 0).map(|_| Action::Unlock),
+            "resume" => {
+                arity(1, 1)?;
+                Ok(Action::Resume(args[0].text.clone()))
+            }
+            "help" => {
+                arity(0, 1)?;
+                Ok(Action::Help(args.first().map(|a| a.text.clone())))
+            }
+            "quit" => arity(0, 0).map(|_| Action::Quit),
+            _ => Err(unknown_command(&cmd.text)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Token {
+    text: String,
+    /// 在这一行里的起始字节位置
+    start: usize,
+    quoted: bool,
+}
+
+/// 按空白切分，`"` 括起来的部分算一个参数，里面可以用 `\"` 和 `\\`
+fn tokenize(line: &str) -> AnyResult<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut text = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => text.push(c),
+                        None => return Err(unterminated()),
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(unterminated()),
+                }
+            }
+            tokens.push(Token {
+                text,
+                start,
+                quoted: true,
+            });
+        } else {
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+            tokens.push(Token {
+                text,
+                start,
+                quoted: false,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn unterminated() -> AnyError {
+    AnyError::quick("unterminated quote", anyverr::ErrKind::ValueValidation)
+}
+
+/// 房间名不能是纯数字，否则会和 ID 混淆
+pub fn validate_room_name(name: &str) -> AnyResult<()> {
+    let valid = name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && !name.chars().all(|c| c.is_ascii_digit());
+    if valid {
+        Ok(())
+    } else {
+        Err(AnyError::quick(
+            format!(
+                "room names are up to {} letters, digits, '-' or '_', and not just digits",
+                MAX_ROOM_NAME_LEN
+            ),
+            anyverr::ErrKind::ValueValidation,
+        ))
+    }
+}
+
+pub fn validate_nick(nick: &str) -> AnyResult<()> {
+    let valid = !nick.is_empty()
+        && nick.len() <= MAX_NICK_LEN
+        && nick
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(AnyError::quick(
+            format!(
+                "nicknames are 1 to {} letters, digits, '-' or '_'",
+                MAX_NICK_LEN
+            ),
+            anyverr::ErrKind::ValueValidation,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_action() {
+        let parse = |s: &str| Action::from_str(s).ok();
+        assert_eq!(
+            parse(".CREATE Rust Weekly  sync"),
+            Some(Action::Create {
+                name: "Rust".into(),
+                topic: Some("Weekly  sync".into()),
+                encrypted: false,
+            })
+        );
+        assert_eq!(
+            parse(r#".create rust "Say \"hi\" \\ wave""#),
+            Some(Action::Create {
+                name: "rust".into(),
+                topic: Some(r#"Say "hi" \ wave"#.into()),
+                encrypted: false,
+            })
+        );
+        assert_eq!(
+            parse(".secure ops"),
+            Some(Action::Create {
+                name: "ops".into(),
+                topic: None,
+                encrypted: true,
+            })
+        );
+        assert_eq!(parse(".join rust"), Some(Action::Join("rust".into())));
+        assert_eq!(parse(".topic"), Some(Action::Topic(None)));
+        assert_eq!(
+            parse(".topic Release Day"),
+            Some(Action::Topic(Some("Release Day".into())))
+        );
+        assert_eq!(parse(".kick Bob"), Some(Action::Kick("Bob".into())));
+        assert_eq!(
+            parse(".ban Bob"),
+            Some(Action::Ban {
+                user: "Bob".into(),
+                ip: false
+            })
+        );
+        assert_eq!(
+            parse(".ban bob IP"),
+            Some(Action::Ban {
+                user: "bob".into(),
+                ip: true
+            })
+        );
+        assert_eq!(parse(".ban bob forever"), None);
+        assert_eq!(
+            parse(".msg bob see you  at 5"),
+            Some(Action::Msg {
+                to: "bob".into(),
+                text: "see you  at 5".into()
+            })
+        );
+        assert_eq!(parse(".nick Carol"), Some(Action::Nick("Carol".into())));
+        assert_eq!(parse(".who"), Some(Action::Who));
+        assert_eq!(parse(".typing"), Some(Action::Typing));
+        assert_eq!(
+            parse(".resume 0f1e2d"),
+            Some(Action::Resume("0f1e2d".into()))
+        );
+        assert_eq!(parse(".Help kick"), Some(Action::Help(Some("kick".into()))));
+        assert_eq!(parse(".msg bob"), None);
+        assert_eq!(parse(".create 42"), None);
+        assert_eq!(parse(".kick 127.0.0.1:4000"), None);
+        assert_eq!(parse(".join a b"), None);
+        assert_eq!(parse(".list all"), None);
+        assert_eq!(parse(".nick a b"), None);
+        assert_eq!(parse(".resume"), None);
+        assert_eq!(parse(".dance"), None);
+        assert_eq!(parse("hello"), None);
+    }
+
+    #[test]
+    fn test_parse_input() -> AnyResult<()> {
+        assert_eq!(
+            "Hello World".parse::<Input>()?,
+            Input::Say("Hello World".into())
+        );
+        assert_eq!("...and so".parse::<Input>()?, Input::Say("..and so".into()));
+        assert_eq!(
+            "    let x = 1;  ".parse::<Input>()?,
+            Input::Say("    let x = 1;  ".into())
+        );
+        assert_eq!("  .quit".parse::<Input>()?, Input::Command(Action::Quit));
+        assert_eq!(".quit".parse::<Input>()?, Input::Command(Action::Quit));
+
+        let err = |s: &str| describe(&s.parse::<Input>().unwrap_err());
+        assert_eq!(err(".kick"), "Invalid input: expected .kick <user>\n");
+        assert_eq!(err(".topic \"open"), "Invalid input: unterminated quote\n");
+        assert_eq!(
+            err(".dance"),
+            "Not found: there is no command .dance, see .help\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_help() -> AnyResult<()> {
+        let all = help(None)?;
+        assert!(COMMANDS.iter().all(|c| all.contains(c.usage)));
+        assert_eq!(
+            help(Some(".ban"))?,
+            ".ban <user> [ip]\n  Keep a user out of the room by nickname, online or not; \
+             with ip, their current IP too. Owner only.\n"
+        );
+        assert!(help(Some("dance")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use anyverr::{AnyError, AnyResult};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{Directory, LineReader, MAX_LINE_LEN, RoomHandle};
+
+/// 管理接口的一条请求，每行一个 JSON 对象，例如 `{"cmd":"members","room":"rust"}`
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Rooms,
+    /// 房间名或 ID
+    Members {
+        room: String,
+    },
+    Users,
+    /// 发给所有在线用户，包括大厅里的
+    Notice {
+        text: String,
+    },
+    CloseRoom {
+        room: String,
+    },
+    Disconnect {
+        user: String,
+    },
+    Stats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomStatus {
+    pub id: u64,
+    pub name: String,
+    pub members: usize,
+    pub locked: bool,
+    pub encrypted: bool,
+    pub topic: Option<String>,
+}
+
+impl From<&RoomHandle> for RoomStatus {
+    fn from(room: &RoomHandle) -> Self {
+        let info = room.info();
+        Self {
+            id: room.id(),
+            name: room.name().to_string(),
+            members: info.members,
+            locked: info.locked,
+            encrypted: info.encrypted,
+            topic: info.topic,
+        }
+    }
+}
+
+/// 在 `listener` 上逐行回答管理请求；只应该监听本机地址
+pub(crate) async fn serve(listener: TcpListener, directory: Arc<Directory>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("admin: failed to accept: {}", e);
+                continue;
+            }
+        };
+        let directory = directory.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &directory).await {
+                eprintln!("admin: error handling {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, directory: &Directory) -> AnyResult<()> {
+    let (s_rx, mut s_tx) = io::split(stream);
+    let mut lines = LineReader::new(s_rx, MAX_LINE_LEN);
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(req) => handle_request(directory, req).await,
+            Err(e) => Err(AnyError::quick(
+                format!("bad request: {}", e),
+                anyverr::ErrKind::ValueValidation,
+            )),
+        };
+        let reply = match reply {
+            Ok(data) => json!({ "ok": true, "data": data }),
+            Err(e) => json!({
+                "ok": false,
+                "kind": format!("{:?}", e.kind()),
+                "error": e.message(),
+            }),
+        };
+        let mut reply = reply.to_string();
+        reply.push('\n');
+        s_tx.write_all(reply.as_bytes())
+            .await
+            .map_err(AnyError::wrap)?;
+    }
+    Ok(())
+}
+
+async fn handle_request(directory: &Directory, req: AdminRequest) -> AnyResult<Value> {
+    match req {
+        AdminRequest::Rooms => {
+            let rooms = directory.rooms();
+            to_value(&rooms.iter().map(RoomStatus::from).collect::<Vec<_>>())
+        }
+        AdminRequest::Members { room } => to_value(&find_room(directory, &room)?.members().await?),
+        AdminRequest::Users => {
+            let users = directory
+                .users()
+                .into_iter()
+                .map(|u| json!({ "nick": u.nick, "addr": u.addr }))
+                .collect::<Vec<_>>();
+            Ok(Value::Array(users))
+        }
+        AdminRequest::Notice { text } => {
+            let delivered = directory.notify_all(&text);
+            println!("admin: notice sent to {} user(s)", delivered);
+            Ok(json!({ "delivered": delivered }))
+        }
+        AdminRequest::CloseRoom { room } => {
+            let room = find_room(directory, &room)?;
+            let members = room.close().await?;
+            println!("admin: closed room {}", room.id());
+            Ok(json!({ "id": room.id(), "members": members }))
+        }
+        AdminRequest::Disconnect { user } => {
+            directory.disconnect(&user)?;
+            println!("admin: disconnected {}", user);
+            Ok(json!({ "user": user }))
+        }
+        AdminRequest::Stats => to_value(&directory.stats()),
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> AnyResult<Value> {
+    serde_json::to_value(value).map_err(AnyError::wrap)
+}
+
+fn find_room(directory: &Directory, key: &str) -> AnyResult<RoomHandle> {
+    directory.find_room(key).ok_or_else(|| {
+        AnyError::quick(
+            format!("there is no room {}", key),
+            anyverr::ErrKind::EntityAbsence,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::MsgKind;
+
+    #[test]
+    fn test_parse_request() {
+        let parse = |s: &str| serde_json::from_str::<AdminRequest>(s).ok();
+        assert_eq!(parse(r#"{"cmd":"stats"}"#), Some(AdminRequest::Stats));
+        assert_eq!(
+            parse(r#"{"cmd":"close_room","room":"rust"}"#),
+            Some(AdminRequest::CloseRoom {
+                room: "rust".into()
+            })
+        );
+        assert_eq!(parse(r#"{"cmd":"members"}"#), None);
+        assert_eq!(parse(r#"{"cmd":"shutdown"}"#), None);
+    }
+
+    #[tokio::test]
+    async fn test_handle_request() -> AnyResult<()> {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let directory = Arc::new(Directory::default());
+        let (alice, mut alice_rx) = mpsc::unbounded_channel();
+        let alice = Arc::new(alice);
+        let (bob, mut bob_rx) = mpsc::unbounded_channel();
+        directory.register("alice", addr, alice.clone())?;
+        directory.register("bob", addr, Arc::new(bob))?;
+        directory.create_room("rust".into(), None, false, "alice", alice)?;
+
+        let rooms = handle_request(&directory, AdminRequest::Rooms).await?;
+        assert_eq!(rooms[0]["name"], "rust");
+        assert_eq!(rooms[0]["members"], 1);
+        let members = handle_request(
+            &directory,
+            AdminRequest::Members {
+                room: "RUST".into(),
+            },
+        )
+        .await?;
+        assert_eq!(members[0]["nick"], "alice");
+        assert_eq!(members[0]["owner"], true);
+
+        let notice = AdminRequest::Notice {
+            text: "restarting soon".into(),
+        };
+        assert_eq!(handle_request(&directory, notice).await?["delivered"], 2);
+        assert_eq!(bob_rx.recv().await.unwrap().msg(), "*** restarting soon\n");
+
+        let stats = handle_request(&directory, AdminRequest::Stats).await?;
+        assert_eq!(stats["rooms"], 1);
+        assert_eq!(stats["users_online"], 2);
+
+        let close = AdminRequest::CloseRoom {
+            room: "rust".into(),
+        };
+        assert_eq!(handle_request(&directory, close).await?["members"], 1);
+        assert_eq!(alice_rx.recv().await.unwrap().kind, MsgKind::Server);
+        assert_eq!(alice_rx.recv().await.unwrap().kind, MsgKind::Kicked);
+        let close = AdminRequest::CloseRoom {
+            room: "rust".into(),
+        };
+        let err = handle_request(&directory, close).await.unwrap_err();
+        assert_eq!(err.kind(), anyverr::ErrKind::EntityAbsence);
+
+        let disconnect = |user: &str| AdminRequest::Disconnect { user: user.into() };
+        handle_request(&directory, disconnect("Bob")).await?;
+        assert_eq!(bob_rx.recv().await.unwrap().kind, MsgKind::Disconnect);
+        assert!(
+            handle_request(&directory, disconnect("carol"))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! ct-room 的参考客户端，在本地加解密端到端加密房间里的消息
+//!
+//! `.key <room> <passphrase>` 只在本地生效，不会发给服务器。设置之后普通的行先加密再发送，
+//! 收到的 `[user]: e2e:...` 解密后再显示；`.key` 不带参数时停止加密。密文里带着房间、
+//! 发送者和序号，对不上的、或者序号不比同一发送者上一条大的消息都不显示。
+//!
+//! 用法：`ct-room-client [addr] [--secure]`，服务器开启了加密传输时要加 `--secure`。
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyverr::{AnyError, AnyResult};
+use ct_room::{LineReader, Role, RoomKey, SEALED_PREFIX, Stream, secure};
+use tokio::{
+    io::{self, AsyncWriteExt, ReadHalf},
+    net::TcpStream,
+};
+
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// 输入和输出两边共用的加密状态
+#[derive(Default)]
+struct E2e {
+    key: Option<RoomKey>,
+    /// 服务器确认过的昵称，加密时作为发送者
+    nick: Option<String>,
+    /// 每个发送者最后一条消息的序号，用来识别重放
+    last_seen: HashMap<String, u64>,
+}
+
+type SharedKey = Arc<Mutex<E2e>>;
+
+#[tokio::main]
+async fn main() -> AnyResult<()> {
+    let (mut addr, mut secure_transport) = ("127.0.0.1:59414".to_string(), false);
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--secure" => secure_transport = true,
+            _ => addr = arg,
+        }
+    }
+    let stream = TcpStream::connect(&addr).await.map_err(AnyError::wrap)?;
+    let stream: Stream = if secure_transport {
+        Box::new(secure(stream, Role::Initiator).await?)
+    } else {
+        Box::new(stream)
+    };
+    let (s_rx, mut s_tx) = io::split(stream);
+    let key = SharedKey::default();
+
+    // 服务器断开时整个客户端跟着退出
+    let mut printer = tokio::spawn(print_incoming(s_rx, key.clone()));
+    let mut stdin = LineReader::new(io::stdin(), MAX_LINE_LEN);
+    loop {
+        let line = tokio::select! {
+            line = stdin.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            _ = &mut printer => return Ok(()),
+        };
+        let out = match outgoing(&line, &key) {
+            Ok(Some(out)) => out,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", ct_room::describe(&e).trim_end());
+                continue;
+            }
+        };
+        s_tx.write_all(format!("{}\n", out).as_bytes())
+            .await
+            .map_err(AnyError::wrap)?;
+    }
+    Ok(())
+}
+
+/// 把输入的一行变成要发给服务器的一行；本地指令返回 `None`
+fn outgoing(line: &str, key: &SharedKey) -> AnyResult<Option<String>> {
+    let trimmed = line.trim();
+    if let Some(args) = trimmed.strip_prefix(".key") {
+        if !args.is_empty() && !args.starts_with(' ') {
+            // 比如 .keyboard，交给服务器处理
+            return Ok(Some(line.to_string()));
+        }
+        let mut args = args.trim().splitn(2, ' ');
+        let mut e2e = key.lock().unwrap();
+        e2e.last_seen.clear();
+        e2e.key = match (args.next().filter(|a| !a.is_empty()), args.next()) {
+            (Some(room), Some(passphrase)) => {
+                let room_key = RoomKey::derive(room, passphrase.trim())?;
+                println!("(messages are now sealed for {})", room);
+                Some(room_key)
+            }
+            (None, _) => {
+                println!("(messages are no longer sealed)");
+                None
+            }
+            _ => {
+                return Err(AnyError::quick(
+                    "expected .key <room> <passphrase>",
+                    anyverr::ErrKind::ValueValidation,
+                ));
+            }
+        };
+        return Ok(None);
+    }
+    // 指令原样发送，`..` 开头的是以点开头的普通消息
+    let text = match trimmed.strip_prefix('.') {
+        Some(rest) if !rest.starts_with('.') => return Ok(Some(line.to_string())),
+        Some(rest) => rest,
+        None => line,
+    };
+    let e2e = &mut *key.lock().unwrap();
+    match (&mut e2e.key, &e2e.nick) {
+        (Some(room_key), Some(nick)) => room_key.seal(nick, text).map(Some),
+        (Some(_), None) => Err(AnyError::quick(
+            "log in before sending sealed messages",
+            anyverr::ErrKind::RuleViolation,
+        )),
+        (None, _) => Ok(Some(line.to_string())),
+    }
+}
+
+async fn print_incoming(s_rx: ReadHalf<Stream>, key: SharedKey) -> AnyResult<()> {
+    let mut lines = LineReader::new(s_rx, MAX_LINE_LEN);
+    while let Some(line) = lines.next_line().await? {
+        println!("{}", incoming(&line, &key));
+    }
+    println!("(disconnected)");
+    Ok(())
+}
+
+/// 解开 `[user]: e2e:...`，其余的行原样显示
+fn incoming(line: &str, key: &SharedKey) -> String {
+    let mut e2e = key.lock().unwrap();
+    if let Some(nick) = own_nick(line) {
+        e2e.nick = Some(nick.to_string());
+    }
+    let Some((user, sealed)) = line
+        .strip_prefix('[')
+        .and_then(|line| line.split_once("]: "))
+        .filter(|(_, data)| data.starts_with(SEALED_PREFIX))
+    else {
+        return line.to_string();
+    };
+    let Some(room_key) = &e2e.key else {
+        return format!("[{}]: (encrypted, set the passphrase with .key)", user);
+    };
+    let opened = match room_key.open(user, sealed) {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == anyverr::ErrKind::RuleViolation => {
+            return format!("[{}]: (rejected: {})", user, e.message());
+        }
+        Err(_) => return format!("[{}]: (cannot decrypt, wrong passphrase?)", user),
+    };
+    let last = e2e.last_seen.entry(user.to_string()).or_default();
+    if opened.seq <= *last {
+        return format!("[{}]: (rejected: replayed message)", user);
+    }
+    *last = opened.seq;
+    format!("[{}]: {}", user, opened.text)
+}
+
+/// 服务器确认昵称的几种回复：登录、恢复会话和改名
+fn own_nick(line: &str) -> Option<&str> {
+    if let Some(rest) = line.strip_prefix("Hi ") {
+        return rest.strip_suffix("! You are in the lobby.");
+    }
+    if let Some(rest) = line.strip_prefix("Welcome back ") {
+        return rest.split_once('!').map(|(nick, _)| nick);
+    }
+    line.strip_prefix("You are now known as ")?
+        .strip_suffix('.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outgoing_and_incoming() -> AnyResult<()> {
+        let key = SharedKey::default();
+        assert_eq!(outgoing("hi", &key)?.as_deref(), Some("hi"));
+        assert_eq!(outgoing(".key ops hunter2", &key)?, None);
+        assert!(outgoing(".key ops", &key).is_err());
+        assert_eq!(outgoing(".who", &key)?.as_deref(), Some(".who"));
+        // 还不知道自己的昵称
+        assert!(outgoing("hi", &key).is_err());
+
+        incoming("Hi bob! You are in the lobby.", &key);
+        let sealed = outgoing("..hi", &key)?.unwrap();
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert_eq!(incoming(&format!("[bob]: {}", sealed), &key), "[bob]: .hi");
+        assert_eq!(
+            incoming(&format!("[bob]: {}", sealed), &key),
+            "[bob]: (rejected: replayed message)"
+        );
+        assert_eq!(
+            incoming(&format!("[mallory]: {}", sealed), &key),
+            "[mallory]: (rejected: sealed by bob, not mallory)"
+        );
+        assert_eq!(
+            incoming("* bob has joined the room.", &key),
+            "* bob has joined the room."
+        );
+
+        incoming("You are now known as carol.", &key);
+        let sealed = outgoing("hey", &key)?.unwrap();
+        assert_eq!(
+            incoming(&format!("[carol]: {}", sealed), &key),
+            "[carol]: hey"
+        );
+
+        outgoing(".key", &key)?;
+        assert_eq!(
+            incoming(&format!("[bob]: {}", sealed), &key),
+            "[bob]: (encrypted, set the passphrase with .key)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_own_nick() {
+        assert_eq!(own_nick("Hi bob! You are in the lobby."), Some("bob"));
+        assert_eq!(
+            own_nick("Welcome back bob! You are in room rust (#3)."),
+            Some("bob")
+        );
+        assert_eq!(own_nick("You are now known as carol."), Some("carol"));
+        assert_eq!(own_nick("[bob]: Hi there! You are in the lobby."), None);
+    }
+}
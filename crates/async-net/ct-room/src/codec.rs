@@ -0,0 +1,86 @@
+use anyverr::{AnyError, AnyResult};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+/// 按 `\n` 分行读取，不管 TCP 把数据切成几段
+///
+/// 和 chat-room 的 `session::read_line` 是同一个做法，有意各留一份：两个示例互不依赖，
+/// 为这二十来行单独建一个公共 crate 不值得。改其中一份时记得对照另一份。
+#[derive(Debug)]
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    /// 还没读到行尾的半行
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R, max_len: usize) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    /// 读一行，不含行尾的 `\r\n`；连接关闭时返回 `None`
+    ///
+    /// 可以放在 `select!` 里：被取消时已经读到的半行留在缓冲区，下次接着读。
+    pub async fn next_line(&mut self) -> AnyResult<Option<String>> {
+        // 多读一个字节，才能区分正好 max_len 的行和超长的行
+        let limit = (self.max_len + 1).saturating_sub(self.buf.len()) as u64;
+        let n = (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.buf)
+            .await
+            .map_err(AnyError::wrap)?;
+        if n == 0 && self.buf.is_empty() {
+            return Ok(None);
+        }
+        if self.buf.last() != Some(&b'\n') && self.buf.len() > self.max_len {
+            return Err(AnyError::quick(
+                format!("lines are limited to {} bytes", self.max_len),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        // 其余没有行尾的情况是连接关闭前的最后一行
+        let line = String::from_utf8_lossy(&self.buf)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        self.buf.clear();
+        Ok(Some(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_split_and_joined_lines() -> AnyResult<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut lines = LineReader::new(server, 8);
+        client.write_all(b".jo").await.map_err(AnyError::wrap)?;
+        // 半行时读取超时被取消，数据不能丢
+        let pending = tokio::time::timeout(std::time::Duration::from_millis(20), lines.next_line());
+        assert!(pending.await.is_err());
+        client
+            .write_all(b"in a\r\nhi\nbye")
+            .await
+            .map_err(AnyError::wrap)?;
+        assert_eq!(lines.next_line().await?.as_deref(), Some(".join a"));
+        assert_eq!(lines.next_line().await?.as_deref(), Some("hi"));
+        client
+            .write_all(b"\n123456789\n")
+            .await
+            .map_err(AnyError::wrap)?;
+        assert_eq!(lines.next_line().await?.as_deref(), Some("bye"));
+        assert!(lines.next_line().await.is_err());
+
+        let mut lines = LineReader::new(&b"last"[..], 8);
+        assert_eq!(lines.next_line().await?.as_deref(), Some("last"));
+        assert_eq!(lines.next_line().await?, None);
+        Ok(())
+    }
+}
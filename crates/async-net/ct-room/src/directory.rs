@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyverr::{AnyError, AnyResult};
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{Msg, RoomHandle, action::validate_nick, room::Room, session::Sessions};
+
+/// 每个信箱最多保存的离线私信数
+const MAILBOX_LEN: usize = 20;
+/// 最多为多少个离线用户保存信箱
+const MAX_MAILBOXES: usize = 1024;
+
+/// 一个在线用户，无论在大厅还是房间里都通过 `sender` 收消息
+#[derive(Debug, Clone)]
+pub struct User {
+    pub nick: String,
+    pub addr: SocketAddr,
+    pub sender: Arc<UnboundedSender<Msg>>,
+}
+
+#[derive(Debug, Default)]
+struct Users {
+    /// 在线用户，键为小写昵称
+    online: HashMap<String, User>,
+    /// 离线用户的私信，键为小写昵称，上线时一次性投递
+    mailboxes: HashMap<String, VecDeque<Msg>>,
+}
+
+/// 服务启动以来的计数，管理接口的 `stats` 读取
+#[derive(Debug)]
+pub(crate) struct Counters {
+    started: Instant,
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    chat_messages: AtomicU64,
+    private_messages: AtomicU64,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            chat_messages: AtomicU64::new(0),
+            private_messages: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Counters {
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_chat_message(&self) {
+        self.chat_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_private_message(&self) {
+        self.private_messages.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub rooms: usize,
+    pub users_online: usize,
+    /// 断线后还在等 `.resume` 的会话，这些用户仍算在线
+    pub parked_sessions: usize,
+    pub mailboxes: usize,
+    pub connections: u64,
+    pub open_connections: u64,
+    pub chat_messages: u64,
+    pub private_messages: u64,
+}
+
+/// 房间 ID 到房间 actor 的映射，以及在线用户表
+///
+/// 锁只在查表时持有，房间内的广播都在各自的 actor 里进行，互不争用。
+#[derive(Debug, Default)]
+pub struct Directory {
+    rooms: RwLock<HashMap<u64, RoomHandle>>,
+    users: Mutex<Users>,
+    pub(crate) counters: Counters,
+    pub(crate) sessions: Sessions,
+}
+
+impl Directory {
+    /// 断线的会话保留 `resume_grace`，为零时不保留
+    pub fn new(resume_grace: Duration) -> Self {
+        Self {
+            rooms: RwLock::default(),
+            users: Mutex::default(),
+            counters: Counters::default(),
+            sessions: Sessions::new(resume_grace),
+        }
+    }
+
+    /// 创建房间并让 `owner` 作为房主加入，房间名不区分大小写地唯一
+    pub fn create_room(
+        self: &Arc<Self>,
+        name: String,
+        topic: Option<String>,
+        encrypted: bool,
+        owner: &str,
+        sender: Arc<UnboundedSender<Msg>>,
+    ) -> AnyResult<RoomHandle> {
+        let mut rooms = self.rooms.write().unwrap();
+        if rooms.values().any(|r| r.name().eq_ignore_ascii_case(&name)) {
+            return Err(AnyError::quick(
+                format!("room {} already exists, use .join {} instead", name, name),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let room = Room::spawn(name, topic, encrypted, owner, sender, Arc::downgrade(self));
+        rooms.insert(room.id(), room.clone());
+        Ok(room)
+    }
+
+    /// 按名称（不区分大小写）或数字 ID 查找房间
+    pub fn find_room(&self, key: &str) -> Option<RoomHandle> {
+        let rooms = self.rooms.read().unwrap();
+        if let Ok(id) = key.parse::<u64>()
+            && let Some(room) = rooms.get(&id)
+        {
+            return Some(room.clone());
+        }
+        rooms
+            .values()
+            .find(|r| r.name().eq_ignore_ascii_case(key))
+            .cloned()
+    }
+
+    /// 所有房间，按 ID 排序
+    pub fn rooms(&self) -> Vec<RoomHandle> {
+        let mut rooms = self
+            .rooms
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        rooms.sort_by_key(|r| r.id());
+        rooms
+    }
+
+    /// 由房间 actor 在最后一个人离开时调用
+    pub(crate) fn remove_room(&self, id: u64) {
+        self.rooms.write().unwrap().remove(&id);
+    }
+
+    /// 登记一个上线的用户，返回离线期间收到的私信
+    pub fn register(
+        &self,
+        nick: &str,
+        addr: SocketAddr,
+        sender: Arc<UnboundedSender<Msg>>,
+    ) -> AnyResult<Vec<Msg>> {
+        validate_nick(nick)?;
+        let key = nick.to_lowercase();
+        let mut users = self.users.lock().unwrap();
+        if users.online.contains_key(&key) {
+            return Err(AnyError::quick(
+                format!("{} is already taken", nick),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        users.online.insert(
+            key.clone(),
+            User {
+                nick: nick.to_string(),
+                addr,
+                sender,
+            },
+        );
+        Ok(users.mailboxes.remove(&key).map_or(vec![], Vec::from))
+    }
+
+    pub fn unregister(&self, nick: &str) {
+        self.users
+            .lock()
+            .unwrap()
+            .online
+            .remove(&nick.to_lowercase());
+    }
+
+    /// 把在线用户 `old` 改名为 `new`，只改大小写也可以；返回新昵称的离线私信
+    pub fn rename(&self, old: &str, new: &str) -> AnyResult<Vec<Msg>> {
+        validate_nick(new)?;
+        let (old_key, new_key) = (old.to_lowercase(), new.to_lowercase());
+        let mut users = self.users.lock().unwrap();
+        if old_key != new_key && users.online.contains_key(&new_key) {
+            return Err(AnyError::quick(
+                format!("{} is already taken", new),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let Some(mut user) = users.online.remove(&old_key) else {
+            return Err(AnyError::quick(
+                format!("{} is not online", old),
+                anyverr::ErrKind::EntityAbsence,
+            ));
+        };
+        user.nick = new.to_string();
+        users.online.insert(new_key.clone(), user);
+        Ok(users.mailboxes.remove(&new_key).map_or(vec![], Vec::from))
+    }
+
+    /// 会话换到了新的连接上
+    pub(crate) fn relocate(&self, nick: &str, addr: SocketAddr) {
+        if let Some(user) = self
+            .users
+            .lock()
+            .unwrap()
+            .online
+            .get_mut(&nick.to_lowercase())
+        {
+            user.addr = addr;
+        }
+    }
+
+    /// 所有在线用户，按昵称排序
+    pub fn users(&self) -> Vec<User> {
+        let mut users = self
+            .users
+            .lock()
+            .unwrap()
+            .online
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        users.sort_by_key(|u| u.nick.to_lowercase());
+        users
+    }
+
+    pub fn find_user(&self, nick: &str) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .online
+            .get(&nick.to_lowercase())
+            .cloned()
+    }
+
+    /// 把私信投递给 `to`，对方在线时返回 `true`，不在线时放进信箱并返回 `false`
+    pub fn deliver(&self, to: &str, msg: Msg) -> AnyResult<bool> {
+        validate_nick(to)?;
+        let key = to.to_lowercase();
+        let mut users = self.users.lock().unwrap();
+        // 对方可能刚断开，发送失败时同样放进信箱
+        let msg = match users.online.get(&key) {
+            Some(user) => match user.sender.send(msg) {
+                Ok(()) => {
+                    self.counters.add_private_message();
+                    return Ok(true);
+                }
+                Err(e) => e.0,
+            },
+            None => msg,
+        };
+        if !users.mailboxes.contains_key(&key) && users.mailboxes.len() >= MAX_MAILBOXES {
+            return Err(AnyError::quick(
+                "too many offline mailboxes, try again later",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let mailbox = users.mailboxes.entry(key).or_default();
+        if mailbox.len() >= MAILBOX_LEN {
+            return Err(AnyError::quick(
+                format!("{}'s mailbox is full", to),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        mailbox.push_back(msg);
+        self.counters.add_private_message();
+        Ok(false)
+    }
+
+    /// 给所有在线用户发一条服务器通知，返回收到的人数
+    pub fn notify_all(&self, text: &str) -> usize {
+        let users = self.users.lock().unwrap();
+        users
+            .online
+            .values()
+            .filter(|u| u.sender.send(Msg::server(text.to_string())).is_ok())
+            .count()
+    }
+
+    /// 让 `nick` 的连接自行断开，离开房间和下线都由连接自己完成
+    pub fn disconnect(&self, nick: &str) -> AnyResult<()> {
+        let user = self.find_user(nick).ok_or_else(|| {
+            AnyError::quick(
+                format!("{} is not online", nick),
+                anyverr::ErrKind::EntityAbsence,
+            )
+        })?;
+        let _ = user.sender.send(Msg::disconnect(
+            "You were disconnected by the server.".into(),
+        ));
+        Ok(())
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        let counters = &self.counters;
+        let (users_online, mailboxes) = {
+            let users = self.users.lock().unwrap();
+            (users.online.len(), users.mailboxes.len())
+        };
+        StatsSnapshot {
+            uptime_secs: counters.started.elapsed().as_secs(),
+            rooms: self.rooms.read().unwrap().len(),
+            users_online,
+            parked_sessions: self.sessions.parked(),
+            mailboxes,
+            connections: counters.connections.load(Ordering::Relaxed),
+            open_connections: counters.open_connections.load(Ordering::Relaxed),
+            chat_messages: counters.chat_messages.load(Ordering::Relaxed),
+            private_messages: counters.private_messages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn test_mailbox() -> AnyResult<()> {
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let directory = Directory::default();
+        for i in 0..MAILBOX_LEN {
+            assert!(!directory.deliver("Bob", Msg::private("alice", format!("#{}", i)))?);
+        }
+        assert!(
+            directory
+                .deliver("bob", Msg::private("alice", "full".into()))
+                .is_err()
+        );
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mailbox = directory.register("bob", addr, Arc::new(tx))?;
+        assert_eq!(mailbox.len(), MAILBOX_LEN);
+        assert_eq!(mailbox[0], Msg::private("alice", "#0".into()));
+        assert!(directory.users.lock().unwrap().mailboxes.is_empty());
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(directory.register("BOB", addr, Arc::new(tx)).is_err());
+        assert!(directory.deliver("BOB", Msg::private("alice", "hi".into()))?);
+        assert_eq!(rx.try_recv().unwrap().msg(), "[alice -> you]: hi\n");
+
+        assert!(directory.rename("bob", "alice")?.is_empty());
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(directory.register("carol", addr, Arc::new(tx)).is_ok());
+        assert!(directory.rename("alice", "Carol").is_err());
+        assert!(directory.rename("alice", "Alice").is_ok());
+        assert_eq!(directory.find_user("alice").unwrap().nick, "Alice");
+        assert!(directory.find_user("bob").is_none());
+
+        directory.unregister("alice");
+        drop(rx);
+        assert!(!directory.deliver("alice", Msg::private("bob", "later".into()))?);
+        Ok(())
+    }
+}
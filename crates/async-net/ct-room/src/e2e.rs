@@ -0,0 +1,211 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyverr::{AnyError, AnyResult};
+use en_de::{Cipher, KEY_LEN, derive_key};
+use serde::{Deserialize, Serialize};
+
+/// 加密后的消息正文以此开头，后面是随机 nonce 和密文的十六进制
+pub const SEALED_PREFIX: &str = "e2e:";
+/// 加入加密房间时附在欢迎语后面
+pub(crate) const E2E_NOTICE: &str = "This room is end-to-end encrypted: the server only relays \
+                                     messages sealed with the room passphrase.\n";
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// 实际加密的内容
+///
+/// 房间和发送者跟正文一起加密，转发的服务器没法把密文挪到别的房间，也没法安到别人名下；
+/// 序号让客户端认出被重放的旧消息。
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    room: String,
+    from: String,
+    /// 同一发送者一条比一条大，见 [`RoomKey::next_seq`]
+    seq: u64,
+    /// 发送时的 Unix 时间，微秒，仅供参考，不用来判断先后
+    at: u64,
+    text: String,
+}
+
+/// 解密并核对过房间和发送者的消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opened {
+    /// 同一发送者的消息一条比一条大，不大的就是重放
+    pub seq: u64,
+    /// 发送者给的 Unix 时间，微秒；对方的时钟可能往回调，不能用来判断先后
+    pub at: u64,
+    pub text: String,
+}
+
+/// 端到端加密房间的密钥，只存在于客户端
+///
+/// 成员用同一个口令和房间名各自派生出同一把密钥，服务器只转发密文。
+pub struct RoomKey {
+    /// 小写的房间名
+    room: String,
+    key: [u8; KEY_LEN],
+    /// 上一条消息的序号
+    last_seq: u64,
+}
+
+impl RoomKey {
+    /// 盐取自小写的房间名，同一口令在不同房间得到不同的密钥
+    pub fn derive(room: &str, passphrase: &str) -> AnyResult<Self> {
+        let room = room.to_lowercase();
+        let salt = format!("ct-room/{}", room);
+        Ok(Self {
+            key: derive_key(passphrase.as_bytes(), salt.as_bytes())?,
+            room,
+            last_seq: 0,
+        })
+    }
+
+    /// 以 `from` 的身份加密 `text`，每条消息用新的随机 nonce
+    pub fn seal(&mut self, from: &str, text: &str) -> AnyResult<String> {
+        let at = now_micros();
+        let envelope = Envelope {
+            room: self.room.clone(),
+            from: from.to_string(),
+            seq: self.next_seq(at),
+            at,
+            text: text.to_string(),
+        };
+        let plain = serde_json::to_vec(&envelope).map_err(AnyError::wrap)?;
+        let sealed = Cipher::XChaCha20Poly1305.encrypt(&plain, &self.key, None)?;
+        Ok(format!("{}{}", SEALED_PREFIX, to_hex(&sealed)))
+    }
+
+    /// 解密服务器标成 `from` 发来的消息
+    ///
+    /// 解不开时返回 `ValueValidation`，密文里的房间或发送者对不上时返回 `RuleViolation`。
+    pub fn open(&self, from: &str, sealed: &str) -> AnyResult<Opened> {
+        let data = sealed
+            .strip_prefix(SEALED_PREFIX)
+            .and_then(from_hex)
+            .ok_or_else(|| {
+                AnyError::quick("not a sealed message", anyverr::ErrKind::ValueValidation)
+            })?;
+        // 口令不对和密文损坏都只是解不开；对得上之后的问题才是有人动了手脚
+        let envelope = Cipher::XChaCha20Poly1305
+            .decrypt(&data, &self.key, None)
+            .ok()
+            .and_then(|plain| serde_json::from_slice::<Envelope>(&plain).ok())
+            .ok_or_else(|| {
+                AnyError::quick(
+                    "cannot decrypt, wrong passphrase?",
+                    anyverr::ErrKind::ValueValidation,
+                )
+            })?;
+        if envelope.room != self.room {
+            return Err(AnyError::quick(
+                format!("sealed for room {}, not {}", envelope.room, self.room),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        if envelope.from != from {
+            return Err(AnyError::quick(
+                format!("sealed by {}, not {}", envelope.from, from),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        Ok(Opened {
+            seq: envelope.seq,
+            at: envelope.at,
+            text: envelope.text,
+        })
+    }
+
+    /// 从当前的微秒数起步，之后每条至少加一：重启客户端也接得上，时钟往回调也不会变小
+    fn next_seq(&mut self, now: u64) -> u64 {
+        self.last_seq = now.max(self.last_seq + 1);
+        self.last_seq
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// 服务器只检查格式，看不到也不需要看到内容
+pub fn is_sealed(data: &str) -> bool {
+    data.strip_prefix(SEALED_PREFIX)
+        .and_then(from_hex)
+        .is_some_and(|data| data.len() >= NONCE_LEN + TAG_LEN)
+}
+
+pub(crate) fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() -> AnyResult<()> {
+        let mut alice = RoomKey::derive("Rust", "correct horse")?;
+        let bob = RoomKey::derive("rust", "correct horse")?;
+        let sealed = alice.seal("alice", "meet at 5")?;
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("meet"));
+        assert_eq!(bob.open("alice", &sealed)?.text, "meet at 5");
+        // 随机 nonce，同样的内容每次密文都不同
+        assert_ne!(sealed, alice.seal("alice", "meet at 5")?);
+
+        let eve = RoomKey::derive("rust", "battery staple")?;
+        assert!(eve.open("alice", &sealed).is_err());
+        let mut tampered = sealed.clone();
+        let last = if sealed.ends_with("00") { "01" } else { "00" };
+        tampered.replace_range(sealed.len() - 2.., last);
+        assert!(bob.open("alice", &tampered).is_err());
+
+        assert!(!is_sealed("meet at 5"));
+        assert!(!is_sealed("e2e:abc"));
+        assert!(!is_sealed("e2e:00ff"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bound_to_room_and_sender() -> AnyResult<()> {
+        let mut alice = RoomKey::derive("rust", "correct horse")?;
+        let sealed = alice.seal("alice", "meet at 5")?;
+        let first = alice.open("alice", &sealed)?;
+        // 服务器把 alice 的密文说成是 mallory 发的
+        assert!(alice.open("mallory", &sealed).is_err());
+        let again = alice.seal("alice", "again")?;
+        assert!(first.seq < alice.open("alice", &again)?.seq);
+
+        // 两个房间碰巧用了同一把密钥，密文也不能挪过去
+        let other = RoomKey {
+            room: "ops".into(),
+            key: alice.key,
+            last_seq: 0,
+        };
+        assert!(other.open("alice", &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seq_survives_clock_steps() -> AnyResult<()> {
+        let mut key = RoomKey::derive("rust", "correct horse")?;
+        assert_eq!(key.next_seq(1_000), 1_000);
+        // 时钟往回调了，序号照样往上走
+        assert_eq!(key.next_seq(400), 1_001);
+        assert_eq!(key.next_seq(1_001), 1_002);
+        assert_eq!(key.next_seq(5_000), 5_000);
+        Ok(())
+    }
+}
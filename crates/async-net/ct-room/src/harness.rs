@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyverr::{AnyError, AnyResult};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    task::JoinSet,
+    time,
+};
+
+use crate::{Config, LineReader, serve};
+
+/// 等一行回复最多等多久
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// 最后一个人离开后，房间最迟多久从 `.list` 里消失
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// 在 127.0.0.1 的随机端口上启动服务器，返回它的地址；`config` 里的地址和端口不用
+pub async fn spawn_local(config: Config) -> AnyResult<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(AnyError::wrap)?;
+    let addr = listener.local_addr().map_err(AnyError::wrap)?;
+    tokio::spawn(async move {
+        if let Err(e) = serve(listener, config).await {
+            eprintln!("Server stopped: {}", e);
+        }
+    });
+    Ok(addr)
+}
+
+/// 模拟的客户端，按行收发，一致性测试和压测都用它
+pub struct Bot {
+    pub name: String,
+    lines: LineReader<OwnedReadHalf>,
+    tx: OwnedWriteHalf,
+}
+
+impl Bot {
+    /// 连上服务器并读到欢迎语，还没有登录
+    pub async fn connect(addr: SocketAddr, name: &str) -> AnyResult<Self> {
+        let stream = TcpStream::connect(addr).await.map_err(AnyError::wrap)?;
+        let (rx, tx) = stream.into_split();
+        let mut bot = Self {
+            name: name.to_string(),
+            lines: LineReader::new(rx, MAX_LINE_LEN),
+            tx,
+        };
+        bot.expect("Welcome!").await?;
+        Ok(bot)
+    }
+
+    /// 连上服务器并以 `nick` 登录
+    pub async fn login(addr: SocketAddr, nick: &str) -> AnyResult<Self> {
+        let mut bot = Self::connect(addr, nick).await?;
+        bot.send(nick).await?;
+        bot.expect(&format!("Hi {}!", nick)).await?;
+        Ok(bot)
+    }
+
+    pub async fn send(&mut self, line: &str) -> AnyResult<()> {
+        self.tx
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .map_err(AnyError::wrap)
+    }
+
+    /// 读下一行，连接关闭时返回 `None`
+    pub async fn next_line(&mut self) -> AnyResult<Option<String>> {
+        match time::timeout(REPLY_TIMEOUT, self.lines.next_line()).await {
+            Ok(line) => line,
+            Err(_) => Err(self.failure("timed out waiting for a line")),
+        }
+    }
+
+    /// 跳过其他行，直到读到以 `prefix` 开头的一行；先读到错误回复时直接失败
+    pub async fn expect(&mut self, prefix: &str) -> AnyResult<String> {
+        loop {
+            let Some(line) = self.next_line().await? else {
+                return Err(self.failure(&format!("disconnected while waiting for {:?}", prefix)));
+            };
+            if line.starts_with(prefix) {
+                return Ok(line);
+            }
+            if is_error(&line) {
+                return Err(self.failure(&format!("got {:?} instead of {:?}", line, prefix)));
+            }
+        }
+    }
+
+    /// 服务器应该关闭连接，之前的行都跳过
+    pub async fn expect_closed(&mut self) -> AnyResult<()> {
+        while self.next_line().await?.is_some() {}
+        Ok(())
+    }
+
+    /// 用 `.list` 查看现有的房间名
+    pub async fn rooms(&mut self) -> AnyResult<Vec<String>> {
+        // `.list` 的行数不定，后面跟一个 `.help list` 作为结尾
+        self.send(".list").await?;
+        self.send(".help list").await?;
+        let mut rooms = vec![];
+        loop {
+            let Some(line) = self.next_line().await? else {
+                return Err(self.failure("disconnected while listing rooms"));
+            };
+            if line == ".list" {
+                return Ok(rooms);
+            }
+            // `  #3 rust (2 users) - topic`
+            if let Some(summary) = line.strip_prefix("  #")
+                && let Some(name) = summary.split(' ').nth(1)
+            {
+                rooms.push(name.to_string());
+            }
+        }
+    }
+
+    fn failure(&self, msg: &str) -> AnyError {
+        failure(&self.name, msg)
+    }
+}
+
+fn failure(name: &str, msg: &str) -> AnyError {
+    AnyError::quick(
+        format!("{}: {}", name, msg),
+        anyverr::ErrKind::RuleViolation,
+    )
+}
+
+/// `describe` 给出的错误回复
+fn is_error(line: &str) -> bool {
+    ["Invalid input: ", "Not allowed: ", "Not found: ", "Error: "]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// 压测的规模，客户端轮流分到各个房间
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub clients: usize,
+    pub rooms: usize,
+    /// 每个客户端发多少条消息
+    pub messages: usize,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            clients: 50,
+            rooms: 5,
+            messages: 100,
+        }
+    }
+}
+
+/// 压测的结果，投递、顺序和空房间的清理都检查过之后才有
+#[derive(Debug, Clone)]
+pub struct LoadReport {
+    pub clients: usize,
+    pub rooms: usize,
+    pub sent: usize,
+    /// 一条消息送到房间里的每个其他成员各算一次
+    pub delivered: usize,
+    /// 从开始发送到所有消息都送达
+    pub elapsed: Duration,
+    pub latency_p50: Duration,
+    pub latency_p99: Duration,
+    pub latency_max: Duration,
+}
+
+impl LoadReport {
+    /// 每秒送达的消息数
+    pub fn throughput(&self) -> f64 {
+        self.delivered as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} clients in {} rooms sent {} messages, {} deliveries in {:.2?}",
+            self.clients, self.rooms, self.sent, self.delivered, self.elapsed
+        )?;
+        writeln!(f, "throughput: {:.0} deliveries/s", self.throughput())?;
+        write!(
+            f,
+            "latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            self.latency_p50, self.latency_p99, self.latency_max
+        )
+    }
+}
+
+/// 房间里收到的一条压测消息
+#[derive(Debug, Clone, PartialEq)]
+struct Received {
+    sender: String,
+    seq: usize,
+    latency: Duration,
+}
+
+/// 让 `options.clients` 个客户端在 `addr` 上建房、加入、聊天再离开，检查每条消息都按顺序送达
+///
+/// 房间名是 `load0`、`load1`……，服务器上不能已经有同名的房间。
+pub async fn run_load(addr: SocketAddr, options: &LoadOptions) -> AnyResult<LoadReport> {
+    if options.rooms == 0 || options.clients < options.rooms {
+        return Err(AnyError::quick(
+            "every room needs at least one client",
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    let names = (0..options.rooms)
+        .map(|r| format!("load{}", r))
+        .collect::<Vec<_>>();
+
+    // 每个房间的第一个客户端建房，其余的加入
+    let mut rooms = (0..options.rooms).map(|_| vec![]).collect::<Vec<_>>();
+    for i in 0..options.clients {
+        let r = i % options.rooms;
+        let mut bot = Bot::login(addr, &format!("bot{}", i)).await?;
+        if i < options.rooms {
+            bot.send(&format!(".create {}", names[r])).await?;
+            bot.expect("Successfully created").await?;
+        } else {
+            bot.send(&format!(".join {}", names[r])).await?;
+            bot.expect("Successfully joined").await?;
+        }
+        rooms[r].push(bot);
+    }
+
+    // 所有人同时发送，同时接收
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for (r, bots) in rooms.into_iter().enumerate() {
+        let expected = (bots.len() - 1) * options.messages;
+        for bot in bots {
+            tasks.spawn(chat(bot, r, options.messages, expected, start));
+        }
+    }
+    let mut rooms = (0..options.rooms).map(|_| vec![]).collect::<Vec<_>>();
+    while let Some(res) = tasks.join_next().await {
+        let (r, bot, received) = res.map_err(AnyError::wrap)??;
+        rooms[r].push((bot, received));
+    }
+    let elapsed = start.elapsed();
+
+    let mut latencies = vec![];
+    for members in &rooms {
+        check_room(
+            &members
+                .iter()
+                .map(|(bot, received)| (bot.name.as_str(), received.as_slice()))
+                .collect::<Vec<_>>(),
+        )?;
+        for (_, received) in members {
+            latencies.extend(received.iter().map(|m| m.latency));
+        }
+    }
+    latencies.sort();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    let report = LoadReport {
+        clients: options.clients,
+        rooms: options.rooms,
+        sent: options.clients * options.messages,
+        delivered: latencies.len(),
+        elapsed,
+        latency_p50: percentile(50),
+        latency_p99: percentile(99),
+        latency_max: latencies.last().copied().unwrap_or_default(),
+    };
+
+    // 所有人离开之后房间都要消失
+    let mut bots = rooms
+        .into_iter()
+        .flatten()
+        .map(|(bot, _)| bot)
+        .collect::<Vec<_>>();
+    for bot in &mut bots {
+        bot.send(".quit").await?;
+        bot.expect("You have left the room.").await?;
+    }
+    let deadline = Instant::now() + CLEANUP_TIMEOUT;
+    loop {
+        let left = bots[0]
+            .rooms()
+            .await?
+            .into_iter()
+            .filter(|name| names.contains(name))
+            .collect::<Vec<_>>();
+        if left.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            return Err(AnyError::quick(
+                format!("empty rooms were not cleaned up: {}", left.join(", ")),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    for bot in &mut bots {
+        bot.send(".quit").await?;
+        bot.expect("Goodbye!").await?;
+        bot.expect_closed().await?;
+    }
+    Ok(report)
+}
+
+/// 发出 `messages` 条消息，同时收下其他成员的 `expected` 条
+async fn chat(
+    bot: Bot,
+    room: usize,
+    messages: usize,
+    expected: usize,
+    start: Instant,
+) -> AnyResult<(usize, Bot, Vec<Received>)> {
+    let Bot {
+        name,
+        mut lines,
+        mut tx,
+    } = bot;
+    // 消息里带着序号和发出的时刻
+    let send = async {
+        for seq in 0..messages {
+            let line = format!("{} {}\n", seq, start.elapsed().as_micros());
+            tx.write_all(line.as_bytes())
+                .await
+                .map_err(AnyError::wrap)?;
+        }
+        AnyResult::Ok(())
+    };
+    let recv = async {
+        let mut received = Vec::with_capacity(expected);
+        while received.len() < expected {
+            let line = time::timeout(REPLY_TIMEOUT, lines.next_line())
+                .await
+                .map_err(|_| {
+                    failure(
+                        &name,
+                        &format!(
+                            "timed out after {} of {} messages",
+                            received.len(),
+                            expected
+                        ),
+                    )
+                })??
+                .ok_or_else(|| failure(&name, "disconnected while chatting"))?;
+            // 其余的是加入、离开之类的通知
+            if let Some(msg) = parse_chat(&line, start) {
+                received.push(msg);
+            }
+        }
+        AnyResult::Ok(received)
+    };
+    let ((), received) = tokio::try_join!(send, recv)?;
+    Ok((room, Bot { name, lines, tx }, received))
+}
+
+/// `[bot3]: 17 123456`
+fn parse_chat(line: &str, start: Instant) -> Option<Received> {
+    let (sender, data) = line.strip_prefix('[')?.split_once("]: ")?;
+    let (seq, sent) = data.split_once(' ')?;
+    let sent = Duration::from_micros(sent.parse().ok()?);
+    Some(Received {
+        sender: sender.to_string(),
+        seq: seq.parse().ok()?,
+        latency: start.elapsed().saturating_sub(sent),
+    })
+}
+
+/// 每个成员收到的都只有同房间其他人的消息，每个发送者的消息按序号排列；
+/// 房间按同一个顺序广播，所以任意两个成员收到的第三方消息顺序也一样
+fn check_room(members: &[(&str, &[Received])]) -> AnyResult<()> {
+    for &(name, received) in members {
+        let mut next = HashMap::new();
+        for msg in received {
+            let sender = msg.sender.as_str();
+            if sender == name || !members.iter().any(|&(member, _)| member == sender) {
+                return Err(failure(
+                    name,
+                    &format!(
+                        "got a message from {}, who is not another member of the room",
+                        sender
+                    ),
+                ));
+            }
+            let expected = next.entry(sender).or_insert(0);
+            if msg.seq != *expected {
+                return Err(failure(
+                    name,
+                    &format!(
+                        "got message #{} from {} while expecting #{}",
+                        msg.seq, sender, expected
+                    ),
+                ));
+            }
+            *expected += 1;
+        }
+    }
+
+    let Some((&(first, first_received), rest)) = members.split_first() else {
+        return Ok(());
+    };
+    for &(name, received) in rest {
+        let third_party = |received: &[Received]| {
+            received
+                .iter()
+                .filter(|m| m.sender != first && m.sender != name)
+                .map(|m| (m.sender.clone(), m.seq))
+                .collect::<Vec<_>>()
+        };
+        if third_party(first_received) != third_party(received) {
+            return Err(failure(
+                name,
+                &format!(
+                    "saw the room's messages in a different order than {}",
+                    first
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load() -> AnyResult<()> {
+        let addr = spawn_local(Config::default()).await?;
+        let options = LoadOptions {
+            clients: 9,
+            rooms: 3,
+            messages: 30,
+        };
+        let report = run_load(addr, &options).await?;
+        assert_eq!(report.sent, 9 * 30);
+        // 每个房间 3 人，每条消息送到另外 2 人
+        assert_eq!(report.delivered, 9 * 30 * 2);
+        assert!(report.latency_p50 <= report.latency_max);
+
+        // 房间都清理掉了，同样的房间名可以再来一轮
+        run_load(addr, &options).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_room() {
+        let start = Instant::now();
+        let msgs = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|line| parse_chat(line, start).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert!(parse_chat("* bot1 has joined the room.", start).is_none());
+        assert!(parse_chat("[bot1]: hello there", start).is_none());
+
+        let a = msgs(&["[b]: 0 0", "[c]: 0 0", "[b]: 1 0", "[c]: 1 0"]);
+        let b = msgs(&["[a]: 0 0", "[c]: 0 0", "[c]: 1 0"]);
+        let c = msgs(&["[b]: 0 0", "[a]: 0 0", "[b]: 1 0"]);
+        assert!(check_room(&[("a", &a), ("b", &b), ("c", &c)]).is_ok());
+
+        // 同一个人的消息乱序
+        let swapped = msgs(&["[b]: 1 0", "[c]: 0 0", "[b]: 0 0", "[c]: 1 0"]);
+        assert!(check_room(&[("a", &swapped), ("b", &b), ("c", &c)]).is_err());
+        // 收到了不在房间里的人的消息
+        assert!(check_room(&[("a", &a), ("b", &b)]).is_err());
+        // 两个成员看到的第三方顺序不同
+        let d = msgs(&["[b]: 0 0", "[c]: 0 0", "[b]: 1 0", "[c]: 1 0"]);
+        let e = msgs(&["[c]: 0 0", "[b]: 0 0", "[b]: 1 0", "[c]: 1 0"]);
+        assert!(check_room(&[("a", &d), ("e", &e), ("b", &[]), ("c", &[])]).is_err());
+    }
+}
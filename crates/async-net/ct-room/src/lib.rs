@@ -1,26 +1,59 @@
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    str::FromStr,
-    sync::{self, Arc, LazyLock, atomic::AtomicU64},
-};
+mod action;
+mod admin;
+mod codec;
+mod directory;
+mod e2e;
+#[cfg(feature = "load")]
+mod harness;
+mod msg;
+mod room;
+mod session;
+mod transport;
+
+use std::{collections::VecDeque, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
 use anyverr::{AnyError, AnyResult};
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    net::{TcpListener, TcpStream},
-    sync::{
-        Mutex,
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-    },
+    io::{self, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpListener,
+    sync::mpsc,
+    time,
 };
 
+use session::{Client, MAX_MISSED, Missed};
+
+pub use action::*;
+pub use admin::{AdminRequest, RoomStatus};
+pub use codec::LineReader;
+pub use directory::*;
+pub use e2e::{Opened, RoomKey, SEALED_PREFIX, is_sealed};
+pub use en_de::Role;
+#[cfg(feature = "load")]
+pub use harness::{Bot, LoadOptions, LoadReport, run_load, spawn_local};
+pub use msg::*;
+pub use room::{LeaveReason, MemberInfo, RoomHandle, RoomInfo};
+pub use transport::{Conn, MAX_FRAME_LEN, Stream, secure};
+
+/// `ct-room --admin` 时管理接口使用的端口
+pub const DEFAULT_ADMIN_PORT: u16 = 59415;
+
 // 配置结构体
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    ip: String,
-    port: u16,
+    pub ip: String,
+    pub port: u16,
+    /// 管理接口的端口，只监听 127.0.0.1，按行收发 JSON；默认 `None`，不开启
+    ///
+    /// 管理接口没有任何认证，本机的任何用户都能踢人、关房间，只在信得过本机用户时开启。
+    pub admin_port: Option<u16>,
+    /// 每个连接先做 X25519 握手，之后只收发加密的帧；客户端要用 `ct-room-client --secure`
+    ///
+    /// 握手不认证双方，只防被动的窃听，防不了中间人，见 [`secure`]。
+    pub secure_transport: bool,
+    /// 断线后会话保留多少秒，期间可以用 `.resume <token>` 回到原来的房间；为 0 时不保留
+    pub resume_grace_secs: u64,
 }
 
 impl Default for Config {
@@ -28,160 +61,63 @@ impl Default for Config {
         Self {
             ip: "127.0.0.1".into(),
             port: 59414,
+            admin_port: None,
+            secure_transport: false,
+            resume_grace_secs: session::RESUME_GRACE.as_secs(),
         }
     }
 }
 
-// --- 核心状态与数据结构 (无变动) ---
-
-#[derive(Debug, Clone, Default)]
-pub struct AppState {
-    pub rooms: HashMap<u64, Room>,
-}
-
-impl AppState {
-    pub fn new_room(&mut self) -> u64 {
-        let room = Room::new();
-        let id = room.id;
-        self.rooms.insert(id, room);
-        id
-    }
-
-    pub fn user_exists(&self, user: SocketAddr) -> Option<u64> {
-        self.rooms
-            .values()
-            .find(|r| r.user_exists(user))
-            .map(|r| r.id)
-    }
-}
-
-static ROOM_ID: LazyLock<AtomicU64> = sync::LazyLock::new(|| AtomicU64::new(0));
-
-fn fetch_latest_room_id() -> u64 {
-    ROOM_ID.fetch_add(1, sync::atomic::Ordering::SeqCst)
-}
-
-#[derive(Debug, Clone)]
-pub struct Room {
-    id: u64,
-    users: Vec<SocketAddr>,
-    senders: HashMap<SocketAddr, Arc<mpsc::UnboundedSender<Msg>>>,
-}
-
-impl Room {
-    pub fn new() -> Self {
-        Self {
-            id: fetch_latest_room_id(),
-            users: vec![],
-            senders: HashMap::new(),
-        }
-    }
-
-    pub fn add_user(&mut self, user: &SocketAddr) {
-        if !self.users.contains(user) {
-            self.users.push(*user);
-        }
-    }
+/// 一行最多多少字节，超过就断开连接
+const MAX_LINE_LEN: usize = 2048;
 
-    pub fn remove_user(&mut self, user: &SocketAddr) {
-        self.users.retain(|u| u != user);
-        self.senders.remove(user);
-    }
-
-    pub fn update_sender(&mut self, user: &SocketAddr, sender: Arc<UnboundedSender<Msg>>) {
-        self.senders.insert(*user, sender);
-    }
-
-    pub fn user_exists(&self, user: SocketAddr) -> bool {
-        self.users.iter().any(|i| i == &user)
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct Msg {
-    pub user: SocketAddr,
-    pub data: String,
-}
-
-impl Msg {
-    pub fn msg(&self) -> String {
-        Msg::to_string(self.user, self.data.clone())
-    }
-    pub fn to_string(user: SocketAddr, data: String) -> String {
-        format!("[{}]: {}", user, data)
-    }
-}
-
-// 指令解析
-
-type RoomID = u64;
-
-#[derive(Debug, PartialEq)]
-pub enum Action {
-    Create,
-    Join(RoomID),
-    Quit,
-    List,
-}
-
-impl FromStr for Action {
-    type Err = AnyError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.trim();
-        let parse_join_str = |s: &str| {
-            let mut parts = s.trim().splitn(2, ' ');
-            parts.next();
-            if let Some(num_str) = parts.next() {
-                u64::from_str(num_str.trim()).map_err(AnyError::wrap)
-            } else {
-                Err(AnyError::quick(
-                    "Join command requires a room ID.",
-                    anyverr::ErrKind::ValueValidation,
-                ))
-            }
-        };
-
-        match s.to_lowercase() {
-            s if s == ".create" => Ok(Action::Create),
-            s if s.starts_with(".join") => parse_join_str(&s).map(Action::Join),
-            s if s == ".quit" => Ok(Action::Quit),
-            s if s == ".list" => Ok(Action::List),
-            _ => Err(AnyError::quick(
-                "Not a command. Commands start with '.'",
-                anyverr::ErrKind::ValueValidation,
-            )),
-        }
-    }
-}
+type Lines = LineReader<ReadHalf<Stream>>;
+type Writer = WriteHalf<Stream>;
 
 /// 连接状态
 enum State {
     Lobby,
-    Chatting(ChatSession),
+    /// 所在的房间记在 `Client::room` 里
+    Chatting,
+    /// 连接断了或者被接管了，会话留给 `.resume`
+    Detached,
+    /// 用户退出或者被管理员断开，会话随之结束
     Shutdown,
 }
 
-/// 聊天会话所需的数据
-struct ChatSession {
-    room_id: u64,
-    receiver: UnboundedReceiver<Msg>,
-}
-
 pub async fn run(config: Config) -> AnyResult<()> {
     let socket_addr_str = format!("{}:{}", config.ip, config.port);
     let socket_addr = SocketAddr::from_str(&socket_addr_str).map_err(AnyError::wrap)?;
     let tcp_listener = TcpListener::bind(socket_addr)
         .await
         .map_err(AnyError::wrap)?;
+    serve(tcp_listener, config).await
+}
+
+/// 在已经绑定好的 `tcp_listener` 上提供服务，`config` 里的地址和端口不再使用
+pub async fn serve(tcp_listener: TcpListener, config: Config) -> AnyResult<()> {
     println!(
         "TCP server listening on {}",
         tcp_listener.local_addr().map_err(AnyError::wrap)?
     );
 
-    let app_state = Arc::new(Mutex::new(AppState::default()));
+    let directory = Arc::new(Directory::new(Duration::from_secs(
+        config.resume_grace_secs,
+    )));
+
+    if let Some(port) = config.admin_port {
+        let admin_listener = TcpListener::bind(("127.0.0.1", port))
+            .await
+            .map_err(AnyError::wrap)?;
+        println!(
+            "Admin socket listening on {}",
+            admin_listener.local_addr().map_err(AnyError::wrap)?
+        );
+        tokio::spawn(admin::serve(admin_listener, directory.clone()));
+    }
 
     loop {
-        let (stream, user) = match tcp_listener.accept().await {
+        let (stream, addr) = match tcp_listener.accept().await {
             Ok(t) => t,
             Err(e) => {
                 eprintln!("Failed to accept connection: {}", e);
@@ -189,98 +125,345 @@ pub async fn run(config: Config) -> AnyResult<()> {
             }
         };
 
-        let app_state = app_state.clone();
+        let directory = directory.clone();
+        let secure_transport = config.secure_transport;
         tokio::spawn(async move {
-            println!("New connection from: {}", user);
-            if let Err(e) = handle_connection(stream, user, app_state).await {
-                eprintln!("Error handling connection for {}: {}", user, e);
+            println!("New connection from: {}", addr);
+            directory.counters.connection_opened();
+            let stream: AnyResult<Stream> = if secure_transport {
+                secure(stream, Role::Responder)
+                    .await
+                    .map(|s| Box::new(s) as Stream)
+            } else {
+                Ok(Box::new(stream))
+            };
+            let res = match stream {
+                Ok(stream) => handle_connection(stream, addr, directory.clone()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                eprintln!("Error handling connection for {}: {}", addr, e);
             }
-            println!("Connection closed for: {}", user);
+            directory.counters.connection_closed();
+            println!("Connection closed for: {}", addr);
         });
     }
 }
 
 /// 连接处理器，现在是一个状态机驱动器
 async fn handle_connection(
-    stream: TcpStream,
-    user: SocketAddr,
-    app_state: Arc<Mutex<AppState>>,
+    stream: Stream,
+    addr: SocketAddr,
+    directory: Arc<Directory>,
 ) -> AnyResult<()> {
-    let (mut s_rx, mut s_tx) = io::split(stream);
+    let (s_rx, mut s_tx) = io::split(stream);
+    let mut lines = LineReader::new(s_rx, MAX_LINE_LEN);
+    let res = match handle_login(&mut lines, &mut s_tx, addr, &directory).await {
+        Ok(Some(mut client)) => {
+            println!("{} logged in as {}", addr, client.nick);
+            let res = run_states(&mut client, &mut lines, &mut s_tx, &directory).await;
+            // 只有主动退出才马上结束会话，其余情况都等一等 `.resume`
+            match res {
+                Ok(State::Shutdown) => end_session(client, &directory).await,
+                _ => park_session(client, &directory).await,
+            }
+            res.map(|_| ())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &res {
+        // 尽量告诉客户端为什么断开，比如行太长
+        let _ = s_tx.write_all(describe(e).as_bytes()).await;
+    }
+    res
+}
+
+/// 会话结束：离开房间、下线，令牌随之作废
+async fn end_session(mut client: Client, directory: &Directory) {
+    if let Some(room) = client.room.take() {
+        room.leave(&client.nick, LeaveReason::Disconnect).await;
+    }
+    directory.unregister(&client.nick);
+    directory.sessions.end(&client.token);
+}
+
+/// 连接断了但会话还在：仍然留在房间里、算作在线，消息攒在会话里，宽限期过后才结束
+async fn park_session(client: Client, directory: &Arc<Directory>) {
+    let grace = directory.sessions.grace();
+    if grace.is_zero() {
+        return end_session(client, directory).await;
+    }
+    if let Some(room) = &client.room {
+        room.away(&client.nick, true).await;
+    }
+    let token = client.token.clone();
+    let epoch = directory.sessions.park(client);
+    let directory = directory.clone();
+    tokio::spawn(async move {
+        time::sleep(grace).await;
+        if let Some(client) = directory.sessions.expire(&token, epoch).await {
+            println!("Session of {} expired", client.nick);
+            end_session(client, &directory).await;
+        }
+    });
+}
+
+/// 选一个昵称登录，然后投递离线私信；也可以用 `.resume` 恢复断开的会话。
+/// 客户端在登录前断开时返回 `None`
+async fn handle_login(
+    lines: &mut Lines,
+    s_tx: &mut Writer,
+    addr: SocketAddr,
+    directory: &Directory,
+) -> AnyResult<Option<Client>> {
     s_tx.write_all(
-        b"Welcome! You are in the lobby.\nCommands: .create, .join [id], .list, .quit\n",
+        b"Welcome! Pick a nickname, or send .resume <token> to pick up a dropped session:\n",
     )
     .await
     .map_err(AnyError::wrap)?;
-
-    // 初始状态为 Lobby
-    let mut state = State::Lobby;
-
+    let (tx, receiver) = mpsc::unbounded_channel::<Msg>();
+    let sender = Arc::new(tx);
     loop {
-        let app_state = app_state.clone();
-        state = match state {
-            State::Lobby => handle_lobby_state(&mut s_rx, &mut s_tx, user, app_state).await?,
-            State::Chatting(session) => {
-                handle_chatting_state(session, &mut s_rx, &mut s_tx, user, app_state).await?
+        let Some(line) = lines.next_line().await? else {
+            return Ok(None);
+        };
+        if let Ok(Input::Command(Action::Resume(token))) = line.parse::<Input>() {
+            match directory.sessions.resume(&token).await {
+                Some(client) => return resume_session(client, s_tx, addr, directory).await,
+                None => {
+                    let e = AnyError::quick(
+                        "no session to resume for that token, it may have expired; pick a nickname",
+                        anyverr::ErrKind::EntityAbsence,
+                    );
+                    s_tx.write_all(describe(&e).as_bytes())
+                        .await
+                        .map_err(AnyError::wrap)?;
+                    continue;
+                }
             }
-            State::Shutdown => {
-                // 如果任何状态处理器要求关机，则跳出循环
-                break;
+        }
+        let nick = line.trim().to_string();
+        let mailbox = match directory.register(&nick, addr, sender.clone()) {
+            Ok(mailbox) => mailbox,
+            Err(e) => {
+                s_tx.write_all(describe(&e).as_bytes())
+                    .await
+                    .map_err(AnyError::wrap)?;
+                continue;
             }
         };
+
+        let token = directory.sessions.start(sender.clone());
+        let mut welcome = format!("Hi {}! You are in the lobby.\n{}", nick, help(None)?);
+        let grace = directory.sessions.grace();
+        if !grace.is_zero() {
+            welcome.push_str(&format!(
+                "Your session token is {}. If your connection drops, reconnect and send \
+                 .resume {} within {}s.\n",
+                token,
+                token,
+                grace.as_secs()
+            ));
+        }
+        if !mailbox.is_empty() {
+            welcome.push_str(&format!("You have {} offline message(s):\n", mailbox.len()));
+            for msg in &mailbox {
+                welcome.push_str(&msg.msg());
+            }
+        }
+        s_tx.write_all(welcome.as_bytes())
+            .await
+            .map_err(AnyError::wrap)?;
+        return Ok(Some(Client {
+            nick,
+            addr,
+            token,
+            sender,
+            receiver,
+            missed: Missed::default(),
+            room: None,
+        }));
     }
+}
 
-    Ok(())
+/// 把会话接到新连接上，补发断线期间错过的消息
+async fn resume_session(
+    mut client: Client,
+    s_tx: &mut Writer,
+    addr: SocketAddr,
+    directory: &Directory,
+) -> AnyResult<Option<Client>> {
+    println!("{} resumed the session of {}", addr, client.nick);
+    client.addr = addr;
+    directory.relocate(&client.nick, addr);
+
+    let mut room_id = client.room.as_ref().map(|r| r.id());
+    // 断线期间已经攒下的在前，之后才到的还在 receiver 里
+    let parked = std::mem::take(&mut client.missed);
+    let mut parked_msgs = parked.msgs.into_iter();
+    let (mut missed, mut dropped) = (VecDeque::new(), parked.dropped);
+    while let Some(msg) = parked_msgs
+        .next()
+        .or_else(|| client.receiver.try_recv().ok())
+    {
+        match msg.kind {
+            // 放回去交给状态机，写出这条之后结束会话
+            MsgKind::Disconnect => {
+                let _ = client.sender.send(msg);
+                break;
+            }
+            // 旧连接没来得及处理的接管通知
+            MsgKind::Takeover => continue,
+            // 已经离开的房间里的消息
+            _ if msg.room.is_some() && msg.room != room_id => continue,
+            MsgKind::Kicked => {
+                client.room = None;
+                room_id = None;
+            }
+            _ => {}
+        }
+        if missed.len() == MAX_MISSED {
+            missed.pop_front();
+            dropped += 1;
+        }
+        missed.push_back(msg);
+    }
+
+    let mut welcome = match &client.room {
+        Some(room) => format!(
+            "Welcome back {}! You are in room {} (#{}).\n",
+            client.nick,
+            room.name(),
+            room.id()
+        ),
+        None => format!("Welcome back {}! You are in the lobby.\n", client.nick),
+    };
+    if missed.is_empty() {
+        welcome.push_str("You did not miss any messages.\n");
+    } else {
+        welcome.push_str(&format!(
+            "You missed {} message(s):\n",
+            missed.len() + dropped
+        ));
+        if dropped > 0 {
+            welcome.push_str(&format!("({} earlier message(s) dropped)\n", dropped));
+        }
+        for msg in &missed {
+            welcome.push_str(&msg.msg());
+        }
+    }
+    s_tx.write_all(welcome.as_bytes())
+        .await
+        .map_err(AnyError::wrap)?;
+    if let Some(room) = &client.room {
+        room.away(&client.nick, false).await;
+    }
+    Ok(Some(client))
+}
+
+/// 返回连接结束时的状态，`Shutdown` 表示会话也一起结束
+async fn run_states(
+    client: &mut Client,
+    lines: &mut Lines,
+    s_tx: &mut Writer,
+    directory: &Arc<Directory>,
+) -> AnyResult<State> {
+    // 恢复的会话可能直接回到房间里
+    let mut state = if client.room.is_some() {
+        State::Chatting
+    } else {
+        State::Lobby
+    };
+
+    loop {
+        state = match state {
+            State::Lobby => handle_lobby_state(client, lines, s_tx, directory).await?,
+            State::Chatting => handle_chatting_state(client, lines, s_tx, directory).await?,
+            // 如果任何状态处理器要求断开，则跳出循环
+            State::Detached | State::Shutdown => return Ok(state),
+        };
+    }
 }
 
 /// 处理用户在大厅时的逻辑
 async fn handle_lobby_state(
-    s_rx: &mut ReadHalf<TcpStream>,
-    s_tx: &mut WriteHalf<TcpStream>,
-    user: SocketAddr,
-    app_state: Arc<Mutex<AppState>>,
+    client: &mut Client,
+    lines: &mut Lines,
+    s_tx: &mut Writer,
+    directory: &Arc<Directory>,
 ) -> AnyResult<State> {
-    let mut buf = [0u8; 128];
-    let len = match s_rx.read(&mut buf).await {
-        Ok(0) => return Ok(State::Shutdown), // 客户端断开
-        Ok(n) => n,
-        Err(e) => return Err(AnyError::wrap(e)),
+    let line = tokio::select! {
+        // 大厅里只收私信和服务器通知，之前房间里还没读完的消息直接丢掉
+        Some(msg) = client.receiver.recv() => {
+            if msg.room.is_none() {
+                s_tx.write_all(msg.msg().as_bytes())
+                    .await
+                    .map_err(AnyError::wrap)?;
+            }
+            match msg.kind {
+                MsgKind::Disconnect => return Ok(State::Shutdown),
+                MsgKind::Takeover => return Ok(State::Detached),
+                _ => return Ok(State::Lobby),
+            }
+        }
+        line = lines.next_line() => match line? {
+            Some(line) => line,
+            None => return Ok(State::Detached), // 客户端断开
+        },
     };
+    if line.trim().is_empty() {
+        return Ok(State::Lobby);
+    }
 
-    let input_str = String::from_utf8_lossy(&buf[..len]);
-    let action = match Action::from_str(&input_str) {
-        Ok(a) => a,
-        Err(e) => {
-            s_tx.write_all(format!("Invalid command: {}\n", e).as_bytes())
+    let reply = match line.parse::<Input>() {
+        Ok(Input::Command(Action::Quit)) => {
+            s_tx.write_all(b"Goodbye!\n")
                 .await
                 .map_err(AnyError::wrap)?;
-            return Ok(State::Lobby); // 保持在大厅状态
+            return Ok(State::Shutdown); // 转换到关机状态
         }
+        Ok(Input::Command(Action::Create {
+            name,
+            topic,
+            encrypted,
+        })) => directory
+            .create_room(name, topic, encrypted, &client.nick, client.sender.clone())
+            .map(|room| {
+                let mut msg = format!(
+                    "Successfully created and joined room: {} (#{}). You are its owner.\n",
+                    room.name(),
+                    room.id()
+                );
+                if encrypted {
+                    msg.push_str(e2e::E2E_NOTICE);
+                }
+                client.room = Some(room);
+                msg
+            }),
+        Ok(Input::Command(Action::Join(key))) => handle_join(client, directory, &key).await,
+        Ok(Input::Command(Action::List)) => Ok(handle_list(directory)),
+        Ok(Input::Command(Action::Msg { to, text })) => {
+            handle_msg(directory, &client.nick, &to, text)
+        }
+        Ok(Input::Command(Action::Nick(nick))) => handle_nick(client, directory, &nick).await,
+        Ok(Input::Command(Action::Help(cmd))) => help(cmd.as_deref()),
+        Ok(Input::Command(Action::Resume(_))) => Err(already_logged_in()),
+        Ok(_) => Err(AnyError::quick(
+            "you are not in a room, use .create or .join first",
+            anyverr::ErrKind::RuleViolation,
+        )),
+        Err(e) => Err(e),
     };
 
-    if action == Action::Quit {
-        s_tx.write_all(b"Goodbye!\n")
-            .await
-            .map_err(AnyError::wrap)?;
-        return Ok(State::Shutdown); // 转换到关机状态
-    }
-
-    let room_state = match action {
-        Action::Create => handle_create(app_state, user).await,
-        Action::Join(room_id) => handle_join(app_state, user, room_id).await,
-        Action::List => handle_list(app_state).await,
-        Action::Quit => unreachable!(),
-    };
-
-    if let Some(msg) = room_state.message {
-        s_tx.write_all(msg.as_bytes())
-            .await
-            .map_err(AnyError::wrap)?;
-    }
+    let reply = reply.unwrap_or_else(|e| describe(&e));
+    s_tx.write_all(reply.as_bytes())
+        .await
+        .map_err(AnyError::wrap)?;
 
-    if let (Some(room_id), Some(receiver)) = (room_state.room_id, room_state.receiver) {
+    if client.room.is_some() {
         // 成功加入房间，转换到 Chatting 状态
-        Ok(State::Chatting(ChatSession { room_id, receiver }))
+        Ok(State::Chatting)
     } else {
         // 否则，继续停留在 Lobby 状态
         Ok(State::Lobby)
@@ -289,185 +472,204 @@ async fn handle_lobby_state(
 
 /// 处理用户在聊天室时的逻辑
 async fn handle_chatting_state(
-    mut session: ChatSession,
-    s_rx: &mut ReadHalf<TcpStream>,
-    s_tx: &mut WriteHalf<TcpStream>,
-    user: SocketAddr,
-    app_state: Arc<Mutex<AppState>>,
+    client: &mut Client,
+    lines: &mut Lines,
+    s_tx: &mut Writer,
+    directory: &Arc<Directory>,
 ) -> AnyResult<State> {
-    let mut read_buf = [0u8; 2048];
+    let Some(room_id) = client.room.as_ref().map(|r| r.id()) else {
+        return Ok(State::Lobby);
+    };
 
     tokio::select! {
-        // 监听来自房间其他用户的消息
-        Some(msg) = session.receiver.recv() => {
-            if msg.user != user {
-                if s_tx.write_all(msg.msg().as_bytes()).await.is_err() {
-                    handle_quit(app_state, user, session.room_id).await;
-                    return Ok(State::Shutdown); // 写入失败，关闭连接
+        // 监听来自房间其他用户的消息和私信
+        Some(msg) = client.receiver.recv() => {
+            // 之前房间里还没读完的消息直接丢掉
+            if msg.room.is_some_and(|room| room != room_id) {
+                return Ok(State::Chatting);
+            }
+            if s_tx.write_all(msg.msg().as_bytes()).await.is_err() {
+                return Ok(State::Detached); // 写入失败，关闭连接
+            }
+            match msg.kind {
+                // 已经被移出房间，直接回到大厅
+                MsgKind::Kicked => {
+                    client.room = None;
+                    return Ok(State::Lobby);
                 }
+                MsgKind::Disconnect => return Ok(State::Shutdown),
+                MsgKind::Takeover => return Ok(State::Detached),
+                _ => {}
             }
-            Ok(State::Chatting(session)) // 保持在聊天状态
+            Ok(State::Chatting) // 保持在聊天状态
         }
 
-        // 监听当前用户的键盘输入
-        result = s_rx.read(&mut read_buf) => {
-            match result {
-                Ok(0) | Err(_) => { // 客户端断开
-                    handle_quit(app_state, user, session.room_id).await;
-                    Ok(State::Shutdown)
-                },
-                Ok(len) => {
-                    let data = String::from_utf8_lossy(&read_buf[..len]);
-                    if data.trim() == ".quit" {
-                        handle_quit(app_state, user, session.room_id).await;
-                        s_tx.write_all(b"You have left the room. Returning to lobby.\n").await.map_err(AnyError::wrap)?;
-                     return Ok(State::Lobby); // 转换回大厅状态
-                    }
-
-                    let msg = Msg { user, data: data.into_owned() };
-                    let senders = {
-                        let state = app_state.lock().await;
-                        state.rooms.get(&session.room_id).map_or(HashMap::new(), |r| r.senders.clone())
-                    };
-                    for s in senders.values() {
-                        let _ = s.send(msg.clone());
+        // 监听当前用户的输入
+        line = lines.next_line() => {
+            let Some(line) = line? else {
+                return Ok(State::Detached); // 客户端断开
+            };
+            // 只跳过空白行；消息原样转发，缩进的代码之类不能被裁掉
+            if line.trim().is_empty() {
+                return Ok(State::Chatting);
+            }
+            match line.parse::<Input>() {
+                Ok(Input::Say(text)) => {
+                    if let Some(room) = &client.room {
+                        // 加密房间里的明文多半是客户端没设口令，拒绝转发以免泄露
+                        if room.info().encrypted && !is_sealed(&text) {
+                            let e = AnyError::quick(
+                                "this room is end-to-end encrypted, only sealed messages are relayed",
+                                anyverr::ErrKind::RuleViolation,
+                            );
+                            s_tx.write_all(describe(&e).as_bytes())
+                                .await
+                                .map_err(AnyError::wrap)?;
+                            return Ok(State::Chatting);
+                        }
+                        room.say(&client.nick, text).await;
+                        directory.counters.add_chat_message();
                     }
-                    Ok(State::Chatting(session)) // 保持在聊天状态
+                    Ok(State::Chatting) // 保持在聊天状态
+                }
+                Ok(Input::Command(action)) => {
+                    handle_room_command(client, action, s_tx, directory).await
+                }
+                Err(e) => {
+                    s_tx.write_all(describe(&e).as_bytes())
+                        .await
+                        .map_err(AnyError::wrap)?;
+                    Ok(State::Chatting)
                 }
             }
         }
     }
 }
 
+/// 处理用户在房间里输入的指令
+async fn handle_room_command(
+    client: &mut Client,
+    action: Action,
+    s_tx: &mut Writer,
+    directory: &Arc<Directory>,
+) -> AnyResult<State> {
+    let Some(room) = client.room.clone() else {
+        return Ok(State::Lobby);
+    };
+    let user = client.nick.as_str();
+    let reply = match action {
+        Action::Quit => {
+            room.leave(user, LeaveReason::Quit).await;
+            client.room = None;
+            s_tx.write_all(b"You have left the room. Returning to lobby.\n")
+                .await
+                .map_err(AnyError::wrap)?;
+            return Ok(State::Lobby); // 转换回大厅状态
+        }
+        Action::List => Ok(handle_list(directory)),
+        Action::Topic(topic) => room.topic(user, topic).await,
+        Action::Kick(target) => room.kick(user, &target, false, None).await,
+        Action::Ban { user: target, ip } => match ip.then(|| directory.find_user(&target)) {
+            // 只有在线的人才知道 IP
+            Some(None) => Err(AnyError::quick(
+                format!("{} is not online, ban the nickname alone", target),
+                anyverr::ErrKind::EntityAbsence,
+            )),
+            found => {
+                let target_ip = found.flatten().map(|u| u.addr.ip());
+                room.kick(user, &target, true, target_ip).await
+            }
+        },
+        Action::Lock => room.lock(user, true).await,
+        Action::Unlock => room.lock(user, false).await,
+        Action::Msg { to, text } => handle_msg(directory, user, &to, text),
+        Action::Nick(nick) => handle_nick(client, directory, &nick).await,
+        Action::Typing => {
+            room.typing(user).await;
+            return Ok(State::Chatting);
+        }
+        Action::Who => room.who().await,
+        Action::Help(cmd) => help(cmd.as_deref()),
+        Action::Resume(_) => Err(already_logged_in()),
+        Action::Create { .. } | Action::Join(_) => Err(AnyError::quick(
+            "you are already in a room, use .quit to leave first",
+            anyverr::ErrKind::RuleViolation,
+        )),
+    };
+    let reply = reply.unwrap_or_else(|e| describe(&e));
+    s_tx.write_all(reply.as_bytes())
+        .await
+        .map_err(AnyError::wrap)?;
+    Ok(State::Chatting)
+}
+
 // Action Handlers
 
-#[derive(Debug)]
-struct RoomState {
-    room_id: Option<u64>,
-    receiver: Option<UnboundedReceiver<Msg>>,
-    sender: Option<Arc<UnboundedSender<Msg>>>,
-    message: Option<String>,
+fn already_logged_in() -> AnyError {
+    AnyError::quick(
+        "you are already logged in, .resume only works in place of a nickname",
+        anyverr::ErrKind::RuleViolation,
+    )
 }
 
-impl RoomState {
-    pub fn empty() -> Self {
-        Self {
-            room_id: None,
-            receiver: None,
-            sender: None,
-            message: None,
-        }
-    }
-    pub fn new(room_id: Option<u64>) -> Self {
-        Self {
-            room_id,
-            receiver: None,
-            sender: None,
-            message: None,
-        }
-    }
-    pub fn with_receiver(mut self, receiver: Option<UnboundedReceiver<Msg>>) -> Self {
-        self.receiver = receiver;
-        self
-    }
-    pub fn with_sender(mut self, sender: Option<Arc<UnboundedSender<Msg>>>) -> Self {
-        self.sender = sender;
-        self
-    }
-    pub fn with_message(mut self, message: Option<String>) -> Self {
-        self.message = message;
-        self
-    }
+async fn handle_join(client: &mut Client, directory: &Directory, key: &str) -> AnyResult<String> {
+    let room = directory.find_room(key).ok_or_else(|| {
+        AnyError::quick(
+            format!("there is no room {}, see .list", key),
+            anyverr::ErrKind::EntityAbsence,
+        )
+    })?;
+    let msg = room
+        .join(&client.nick, client.addr.ip(), client.sender.clone())
+        .await?;
+    client.room = Some(room);
+    Ok(msg)
 }
 
-async fn handle_list(app_state: Arc<Mutex<AppState>>) -> RoomState {
-    let state = app_state.lock().await;
-    let rooms_id = state
-        .rooms
-        .keys()
-        .map(|id| id.to_string())
-        .collect::<Vec<_>>()
-        .join(", ");
-    let msg = if rooms_id.is_empty() {
+fn handle_list(directory: &Directory) -> String {
+    let rooms = directory.rooms();
+    if rooms.is_empty() {
         "No active rooms.\n".to_string()
     } else {
-        format!("Active rooms: [ {} ]\n", rooms_id)
-    };
-    RoomState::empty().with_message(Some(msg))
+        let lines = rooms
+            .iter()
+            .map(|r| format!("  {}\n", r.summary()))
+            .collect::<String>();
+        format!("Active rooms:\n{}", lines)
+    }
 }
 
-async fn handle_join(app_state: Arc<Mutex<AppState>>, user: SocketAddr, room_id: u64) -> RoomState {
-    let mut state = app_state.lock().await;
-    if state.user_exists(user).is_some() {
-        return RoomState::empty().with_message(Some(
-            "You are already in a room. Use .quit to leave first.\n".to_string(),
-        ));
+/// 改昵称，房间里的其他人会收到通知；新昵称的离线私信随回复一起送达
+async fn handle_nick(client: &mut Client, directory: &Directory, nick: &str) -> AnyResult<String> {
+    let mailbox = directory.rename(&client.nick, nick)?;
+    if let Some(room) = &client.room {
+        room.rename(&client.nick, nick).await;
     }
-    if let Some(room) = state.rooms.get_mut(&room_id) {
-        let (tx, rx) = mpsc::unbounded_channel::<Msg>();
-        let arc_tx = Arc::new(tx);
-        let join_msg = Msg {
-            user,
-            data: "has joined the room.\n".to_string(),
-        };
-        for sender in room.senders.values() {
-            let _ = sender.send(join_msg.clone());
+    client.nick = nick.to_string();
+    let mut reply = format!("You are now known as {}.\n", nick);
+    if !mailbox.is_empty() {
+        reply.push_str(&format!("You have {} offline message(s):\n", mailbox.len()));
+        for msg in &mailbox {
+            reply.push_str(&msg.msg());
         }
-        room.add_user(&user);
-        room.update_sender(&user, arc_tx.clone());
-        let success_msg = format!(
-            "Successfully joined room: {}. You can start chatting.\n",
-            room_id
-        );
-        return RoomState::new(Some(room_id))
-            .with_receiver(Some(rx))
-            .with_sender(Some(arc_tx))
-            .with_message(Some(success_msg));
-    } else {
-        return RoomState::empty().with_message(Some("Room not found.\n".to_string()));
     }
+    Ok(reply)
 }
 
-async fn handle_create(app_state: Arc<Mutex<AppState>>, user: SocketAddr) -> RoomState {
-    let mut state = app_state.lock().await;
-    if state.user_exists(user).is_some() {
-        return RoomState::empty().with_message(Some(
-            "You are already in a room. Use .quit to leave first.\n".to_string(),
+/// 私信直接发到对方的连接上，不管对方在哪个房间；不在线时留在信箱里
+fn handle_msg(directory: &Directory, user: &str, to: &str, text: String) -> AnyResult<String> {
+    if to.eq_ignore_ascii_case(user) {
+        return Err(AnyError::quick(
+            "you cannot message yourself",
+            anyverr::ErrKind::RuleViolation,
         ));
     }
-    let room_id = state.new_room();
-    let (tx, rx) = mpsc::unbounded_channel::<Msg>();
-    let arc_tx = Arc::new(tx);
-    let room = state.rooms.get_mut(&room_id).unwrap();
-    room.add_user(&user);
-    room.update_sender(&user, arc_tx.clone());
-    let msg = format!(
-        "Successfully created and joined room: {}. You can start chatting.\n",
-        room_id
-    );
-    RoomState::new(Some(room_id))
-        .with_receiver(Some(rx))
-        .with_sender(Some(arc_tx))
-        .with_message(Some(msg))
-}
-
-async fn handle_quit(app_state: Arc<Mutex<AppState>>, user: SocketAddr, room_id: u64) {
-    let mut state = app_state.lock().await;
-    if let Some(room) = state.rooms.get_mut(&room_id) {
-        if room.user_exists(user) {
-            println!("User {} quit from room {}", user, room_id);
-            room.remove_user(&user);
-            let leave_msg = Msg {
-                user,
-                data: "has left the room.\n".to_string(),
-            };
-            for sender in room.senders.values() {
-                let _ = sender.send(leave_msg.clone());
-            }
-            if room.users.is_empty() {
-                println!("Room {} is empty, removing it.", room_id);
-                state.rooms.remove(&room_id);
-            }
-        }
+    if directory.deliver(to, Msg::private(user, text))? {
+        Ok(format!("Message sent to {}.\n", to))
+    } else {
+        Ok(format!(
+            "{} is offline, the message will be delivered later.\n",
+            to
+        ))
     }
 }
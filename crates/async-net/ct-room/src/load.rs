@@ -0,0 +1,43 @@
+//! ct-room 的压测工具，同时检查消息的送达和顺序
+//!
+//! 用法：`ct-room-load [--addr <addr>] [--clients N] [--rooms N] [--messages N]`。
+//! 不给 `--addr` 时在随机端口上启动一个进程内的服务器。
+//! 要打开 `load` feature 才会编译：`cargo run -p ct-room --features load --bin ct-room-load`。
+
+use anyverr::{AnyError, AnyResult};
+use ct_room::{Config, LoadOptions, run_load, spawn_local};
+
+#[tokio::main]
+async fn main() -> AnyResult<()> {
+    let (mut addr, mut options) = (None, LoadOptions::default());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| {
+                AnyError::quick(
+                    format!("{} needs a value", arg),
+                    anyverr::ErrKind::ValueValidation,
+                )
+            })
+        };
+        match arg.as_str() {
+            "--addr" => addr = Some(value()?.parse().map_err(AnyError::wrap)?),
+            "--clients" => options.clients = value()?.parse().map_err(AnyError::wrap)?,
+            "--rooms" => options.rooms = value()?.parse().map_err(AnyError::wrap)?,
+            "--messages" => options.messages = value()?.parse().map_err(AnyError::wrap)?,
+            _ => {
+                return Err(AnyError::quick(
+                    format!("unknown argument {}", arg),
+                    anyverr::ErrKind::ValueValidation,
+                ));
+            }
+        }
+    }
+    let addr = match addr {
+        Some(addr) => addr,
+        None => spawn_local(Config::default()).await?,
+    };
+    let report = run_load(addr, &options).await?;
+    println!("{}", report);
+    Ok(())
+}
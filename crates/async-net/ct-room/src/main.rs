@@ -1,9 +1,15 @@
 use anyverr::AnyResult;
-use ct_room::Config;
+use ct_room::{Config, DEFAULT_ADMIN_PORT};
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let config = Config::default();
+    let flag = |name: &str| std::env::args().any(|arg| arg == name);
+    let config = Config {
+        secure_transport: flag("--secure"),
+        // 管理接口没有认证，要明确加 `--admin` 才开启
+        admin_port: flag("--admin").then_some(DEFAULT_ADMIN_PORT),
+        ..Config::default()
+    };
     ct_room::run(config).await?;
     Ok(())
 }
@@ -0,0 +1,88 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgKind {
+    /// 成员发言
+    Chat,
+    /// 房间的系统通知
+    Notice,
+    /// 收到的人已被移出房间
+    Kicked,
+    /// 发给单个用户的私信
+    Private,
+    /// 管理员发给所有人的通知，大厅里也能收到
+    Server,
+    /// 管理员断开了这个连接，收到的人写完这条就关闭
+    Disconnect,
+    /// 会话被另一个连接用 `.resume` 接管，收到的人写完这条就关闭，会话留给新连接
+    Takeover,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Msg {
+    pub user: String,
+    pub data: String,
+    pub kind: MsgKind,
+    /// 消息所属的房间，私信没有房间
+    pub room: Option<u64>,
+}
+
+impl Msg {
+    pub fn chat(room: u64, user: &str, data: String) -> Self {
+        Self {
+            user: user.to_string(),
+            data,
+            kind: MsgKind::Chat,
+            room: Some(room),
+        }
+    }
+
+    pub fn notice(room: u64, user: &str, data: String) -> Self {
+        Self {
+            kind: MsgKind::Notice,
+            ..Self::chat(room, user, data)
+        }
+    }
+
+    pub fn private(user: &str, data: String) -> Self {
+        Self {
+            user: user.to_string(),
+            data,
+            kind: MsgKind::Private,
+            room: None,
+        }
+    }
+
+    pub fn server(data: String) -> Self {
+        Self {
+            kind: MsgKind::Server,
+            ..Self::private("server", data)
+        }
+    }
+
+    pub fn disconnect(data: String) -> Self {
+        Self {
+            kind: MsgKind::Disconnect,
+            ..Self::private("server", data)
+        }
+    }
+
+    pub fn takeover(data: String) -> Self {
+        Self {
+            kind: MsgKind::Takeover,
+            ..Self::private("server", data)
+        }
+    }
+
+    pub fn msg(&self) -> String {
+        match self.kind {
+            MsgKind::Chat => Msg::to_string(&self.user, &self.data),
+            MsgKind::Notice | MsgKind::Kicked | MsgKind::Disconnect | MsgKind::Takeover => {
+                format!("* {}\n", self.data)
+            }
+            MsgKind::Server => format!("*** {}\n", self.data),
+            MsgKind::Private => format!("[{} -> you]: {}\n", self.user, self.data),
+        }
+    }
+    pub fn to_string(user: &str, data: &str) -> String {
+        format!("[{}]: {}\n", user, data)
+    }
+}
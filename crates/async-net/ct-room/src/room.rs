@@ -0,0 +1,952 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{self, Arc, LazyLock, Weak, atomic::AtomicU64},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyverr::{AnyError, AnyResult};
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    oneshot, watch,
+};
+
+use crate::{Directory, Msg, MsgKind, e2e::E2E_NOTICE};
+
+/// 每个房间 actor 的指令队列长度，满了之后发送方会等待
+const ROOM_QUEUE: usize = 256;
+/// `.typing` 的状态持续多久，再次输入 `.typing` 会续上
+const TYPING_FADE: Duration = Duration::from_secs(5);
+/// 这么久没动静才在 `.who` 里显示空闲时间
+const IDLE_AFTER: Duration = Duration::from_secs(10);
+
+/// 房间 ID 里启动时刻左移的位数，每秒的运行时间留出约一百万个 ID
+const ROOM_ID_SHIFT: u32 = 20;
+
+/// 从启动时的 Unix 秒数左移 [`ROOM_ID_SHIFT`] 位开始编号，重启之后不会再发出上次用过的 ID，
+/// `.join <id>` 也就不会进错房间
+static ROOM_ID: LazyLock<AtomicU64> = sync::LazyLock::new(|| {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    AtomicU64::new(secs << ROOM_ID_SHIFT)
+});
+
+fn fetch_latest_room_id() -> u64 {
+    ROOM_ID.fetch_add(1, sync::atomic::Ordering::SeqCst)
+}
+
+/// 发给房间 actor 的指令，需要回复的带着一个 oneshot
+#[derive(Debug)]
+enum RoomCmd {
+    Join {
+        user: String,
+        ip: IpAddr,
+        sender: Arc<UnboundedSender<Msg>>,
+        reply: oneshot::Sender<AnyResult<String>>,
+    },
+    Leave {
+        user: String,
+        reason: LeaveReason,
+    },
+    Rename {
+        old: String,
+        new: String,
+    },
+    Typing {
+        user: String,
+    },
+    /// 成员断线等待恢复，或者已经恢复
+    Away {
+        user: String,
+        away: bool,
+    },
+    Who {
+        reply: oneshot::Sender<String>,
+    },
+    Members {
+        reply: oneshot::Sender<Vec<MemberInfo>>,
+    },
+    /// 管理员关闭房间，所有成员回到大厅
+    Close {
+        reply: oneshot::Sender<usize>,
+    },
+    Say {
+        user: String,
+        data: String,
+    },
+    Topic {
+        user: String,
+        topic: Option<String>,
+        reply: oneshot::Sender<AnyResult<String>>,
+    },
+    Kick {
+        user: String,
+        target: String,
+        ban: bool,
+        /// `.ban <user> ip` 时对方当前的 IP，一起封禁
+        target_ip: Option<IpAddr>,
+        reply: oneshot::Sender<AnyResult<String>>,
+    },
+    Lock {
+        user: String,
+        locked: bool,
+        reply: oneshot::Sender<AnyResult<String>>,
+    },
+}
+
+/// 成员为什么离开房间，决定了其他人看到的通知
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    Quit,
+    Disconnect,
+}
+
+/// 房间对外公布的概况，`.list` 直接读取，不必打扰 actor
+#[derive(Debug, Clone, Default)]
+pub struct RoomInfo {
+    pub members: usize,
+    pub locked: bool,
+    pub encrypted: bool,
+    pub topic: Option<String>,
+}
+
+/// 一个成员的状态，`.who` 和管理接口都用它
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MemberInfo {
+    pub nick: String,
+    pub owner: bool,
+    pub idle_secs: u64,
+    pub typing: bool,
+    /// 断线了，会话还在等 `.resume`
+    pub away: bool,
+}
+
+/// 房间 actor 的句柄，可以随意克隆
+#[derive(Debug, Clone)]
+pub struct RoomHandle {
+    id: u64,
+    name: Arc<str>,
+    tx: mpsc::Sender<RoomCmd>,
+    info: watch::Receiver<RoomInfo>,
+}
+
+impl RoomHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        self.info.borrow().clone()
+    }
+
+    /// 房间的一行概要，用于 `.list`
+    pub fn summary(&self) -> String {
+        let info = self.info.borrow();
+        let mut summary = format!("#{} {} ({} users", self.id, self.name, info.members);
+        if info.locked {
+            summary.push_str(", locked");
+        }
+        if info.encrypted {
+            summary.push_str(", encrypted");
+        }
+        summary.push(')');
+        if let Some(topic) = &info.topic {
+            summary.push_str(&format!(" - {}", topic));
+        }
+        summary
+    }
+
+    /// 加入房间，成功时返回欢迎语
+    pub async fn join(
+        &self,
+        user: &str,
+        ip: IpAddr,
+        sender: Arc<UnboundedSender<Msg>>,
+    ) -> AnyResult<String> {
+        self.request(|reply| RoomCmd::Join {
+            user: user.to_string(),
+            ip,
+            sender,
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn leave(&self, user: &str, reason: LeaveReason) {
+        let _ = self
+            .tx
+            .send(RoomCmd::Leave {
+                user: user.to_string(),
+                reason,
+            })
+            .await;
+    }
+
+    pub async fn rename(&self, old: &str, new: &str) {
+        let _ = self
+            .tx
+            .send(RoomCmd::Rename {
+                old: old.to_string(),
+                new: new.to_string(),
+            })
+            .await;
+    }
+
+    /// 标记 `user` 正在输入，几秒后自动消失
+    pub async fn typing(&self, user: &str) {
+        let _ = self
+            .tx
+            .send(RoomCmd::Typing {
+                user: user.to_string(),
+            })
+            .await;
+    }
+
+    /// `user` 断线后仍然留在房间里，等它恢复会话或者宽限期结束
+    pub async fn away(&self, user: &str, away: bool) {
+        let _ = self
+            .tx
+            .send(RoomCmd::Away {
+                user: user.to_string(),
+                away,
+            })
+            .await;
+    }
+
+    /// 成员列表以及各自空闲了多久
+    pub async fn who(&self) -> AnyResult<String> {
+        self.request(|reply| RoomCmd::Who { reply }).await
+    }
+
+    pub async fn members(&self) -> AnyResult<Vec<MemberInfo>> {
+        self.request(|reply| RoomCmd::Members { reply }).await
+    }
+
+    /// 请出所有成员，房间随之关闭；返回被请出的人数
+    pub async fn close(&self) -> AnyResult<usize> {
+        self.request(|reply| RoomCmd::Close { reply }).await
+    }
+
+    pub async fn say(&self, user: &str, data: String) {
+        let _ = self
+            .tx
+            .send(RoomCmd::Say {
+                user: user.to_string(),
+                data,
+            })
+            .await;
+    }
+
+    /// 查看或设置话题，房间里的任何人都可以设置
+    pub async fn topic(&self, user: &str, topic: Option<String>) -> AnyResult<String> {
+        self.request(|reply| RoomCmd::Topic {
+            user: user.to_string(),
+            topic,
+            reply,
+        })
+        .await?
+    }
+
+    /// 房主把成员移出房间；`ban` 时封禁这个昵称，给了 `target_ip` 时连同这个 IP
+    pub async fn kick(
+        &self,
+        user: &str,
+        target: &str,
+        ban: bool,
+        target_ip: Option<IpAddr>,
+    ) -> AnyResult<String> {
+        self.request(|reply| RoomCmd::Kick {
+            user: user.to_string(),
+            target: target.to_string(),
+            ban,
+            target_ip,
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn lock(&self, user: &str, locked: bool) -> AnyResult<String> {
+        self.request(|reply| RoomCmd::Lock {
+            user: user.to_string(),
+            locked,
+            reply,
+        })
+        .await?
+    }
+
+    async fn request<T>(&self, cmd: impl FnOnce(oneshot::Sender<T>) -> RoomCmd) -> AnyResult<T> {
+        let (reply, rx) = oneshot::channel();
+        // 房间已经关闭时两步都会失败
+        let closed = || AnyError::quick("the room was closed", anyverr::ErrKind::EntityAbsence);
+        self.tx.send(cmd(reply)).await.map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())
+    }
+}
+
+/// 房间 actor 自己持有的状态，只在它自己的任务里修改
+#[derive(Debug)]
+pub(crate) struct Room {
+    id: u64,
+    name: Arc<str>,
+    topic: Option<String>,
+    /// 房主；离开时移交给在房间里待得最久的成员
+    owner: Option<String>,
+    /// 上锁的房间不再接受新成员
+    locked: bool,
+    /// 成员自己加解密，服务器只转发密文
+    encrypted: bool,
+    /// 被封禁的昵称，统一成小写；不在线的人也可以封禁
+    banned: HashSet<String>,
+    /// 额外封禁的 IP，换个昵称重新连接也进不来；同一个 IP 后面可能不止一个人
+    banned_ips: HashSet<IpAddr>,
+    /// 按加入的先后排列
+    members: Vec<Member>,
+    info: watch::Sender<RoomInfo>,
+}
+
+#[derive(Debug)]
+struct Member {
+    nick: String,
+    sender: Arc<UnboundedSender<Msg>>,
+    /// 最后一次说话或者输入指令的时间
+    last_active: Instant,
+    /// 正在输入的状态到这个时间为止
+    typing_until: Option<Instant>,
+    /// 断线了，发给它的消息攒在会话里
+    away: bool,
+}
+
+impl Room {
+    /// 启动一个以 `owner` 为唯一成员的房间 actor
+    pub(crate) fn spawn(
+        name: String,
+        topic: Option<String>,
+        encrypted: bool,
+        owner: &str,
+        sender: Arc<UnboundedSender<Msg>>,
+        directory: Weak<Directory>,
+    ) -> RoomHandle {
+        let (mut room, info) = Room::new(name, topic, owner);
+        room.encrypted = encrypted;
+        room.add_user(owner, sender);
+        let (tx, rx) = mpsc::channel(ROOM_QUEUE);
+        let handle = RoomHandle {
+            id: room.id,
+            name: room.name.clone(),
+            tx,
+            info,
+        };
+        tokio::spawn(room.run(rx, directory));
+        handle
+    }
+
+    fn new(name: String, topic: Option<String>, owner: &str) -> (Self, watch::Receiver<RoomInfo>) {
+        let (info, info_rx) = watch::channel(RoomInfo {
+            topic: topic.clone(),
+            ..RoomInfo::default()
+        });
+        let room = Self {
+            id: fetch_latest_room_id(),
+            name: name.into(),
+            topic,
+            owner: Some(owner.to_string()),
+            locked: false,
+            encrypted: false,
+            banned: HashSet::new(),
+            banned_ips: HashSet::new(),
+            members: vec![],
+            info,
+        };
+        (room, info_rx)
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<RoomCmd>, directory: Weak<Directory>) {
+        while let Some(cmd) = rx.recv().await {
+            self.handle(cmd);
+            if self.members.is_empty() {
+                break;
+            }
+        }
+        // 先从目录里摘掉，这样就不会再有新的指令；已经排队的直接丢弃，请求方会收到 Room not found
+        println!("Room {} is empty, removing it.", self.id);
+        if let Some(directory) = directory.upgrade() {
+            directory.remove_room(self.id);
+        }
+        rx.close();
+        while rx.recv().await.is_some() {}
+    }
+
+    fn handle(&mut self, cmd: RoomCmd) {
+        match cmd {
+            RoomCmd::Join {
+                user,
+                ip,
+                sender,
+                reply,
+            } => {
+                let _ = reply.send(self.join(&user, ip, sender));
+            }
+            RoomCmd::Leave { user, reason } => self.leave(&user, reason),
+            RoomCmd::Rename { old, new } => self.rename(&old, &new),
+            RoomCmd::Typing { user } => self.typing(&user, Instant::now()),
+            RoomCmd::Away { user, away } => self.away(&user, away),
+            RoomCmd::Who { reply } => {
+                let _ = reply.send(self.who(Instant::now()));
+            }
+            RoomCmd::Members { reply } => {
+                let _ = reply.send(self.members(Instant::now()));
+            }
+            RoomCmd::Close { reply } => {
+                let _ = reply.send(self.close());
+            }
+            RoomCmd::Say { user, data } => {
+                if let Some(member) = self.member_mut(&user) {
+                    member.last_active = Instant::now();
+                    member.typing_until = None;
+                    let msg = Msg::chat(self.id, &user, data);
+                    self.broadcast_except(msg, Some(&user));
+                }
+            }
+            RoomCmd::Topic { user, topic, reply } => {
+                self.touch(&user);
+                let _ = reply.send(self.topic(&user, topic));
+            }
+            RoomCmd::Kick {
+                user,
+                target,
+                ban,
+                target_ip,
+                reply,
+            } => {
+                self.touch(&user);
+                let _ = reply.send(self.kick(&user, &target, ban, target_ip));
+            }
+            RoomCmd::Lock {
+                user,
+                locked,
+                reply,
+            } => {
+                self.touch(&user);
+                let _ = reply.send(self.lock(&user, locked));
+            }
+        }
+    }
+
+    fn join(
+        &mut self,
+        user: &str,
+        ip: IpAddr,
+        sender: Arc<UnboundedSender<Msg>>,
+    ) -> AnyResult<String> {
+        if self.banned.contains(&user.to_ascii_lowercase()) || self.banned_ips.contains(&ip) {
+            return Err(AnyError::quick(
+                "you are banned from this room",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        if self.locked {
+            return Err(AnyError::quick(
+                "the room is locked",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        self.broadcast(Msg::notice(
+            self.id,
+            user,
+            format!("{} has joined the room.", user),
+        ));
+        self.add_user(user, sender);
+        let mut welcome = format!(
+            "Successfully joined room: {} (#{}). You can start chatting.\n",
+            self.name, self.id
+        );
+        if let Some(topic) = &self.topic {
+            welcome.push_str(&format!("Topic: {}\n", topic));
+        }
+        if self.encrypted {
+            welcome.push_str(E2E_NOTICE);
+        }
+        Ok(welcome)
+    }
+
+    fn leave(&mut self, user: &str, reason: LeaveReason) {
+        if !self.user_exists(user) {
+            return;
+        }
+        println!("User {} quit from room {}", user, self.id);
+        let was_owner = self.is_owner(user);
+        self.remove_user(user);
+        let notice = match reason {
+            LeaveReason::Quit => format!("{} has left the room.", user),
+            LeaveReason::Disconnect => format!("{} disconnected.", user),
+        };
+        self.broadcast(Msg::notice(self.id, user, notice));
+        if was_owner && let Some(owner) = &self.owner {
+            self.broadcast(Msg::notice(
+                self.id,
+                user,
+                format!("{} is now the owner of the room.", owner),
+            ));
+        }
+    }
+
+    fn rename(&mut self, old: &str, new: &str) {
+        let was_owner = self.is_owner(old);
+        let Some(member) = self.member_mut(old) else {
+            return;
+        };
+        member.nick = new.to_string();
+        member.last_active = Instant::now();
+        if was_owner {
+            self.owner = Some(new.to_string());
+        }
+        // 改名的人自己已经收到了回复
+        self.broadcast_except(
+            Msg::notice(self.id, new, format!("{} is now known as {}.", old, new)),
+            Some(new),
+        );
+    }
+
+    /// 只在开始输入时通知一次，状态续期时不再打扰其他人
+    fn typing(&mut self, user: &str, now: Instant) {
+        let Some(member) = self.member_mut(user) else {
+            return;
+        };
+        let was_typing = member.typing_until.is_some_and(|until| until > now);
+        member.typing_until = Some(now + TYPING_FADE);
+        member.last_active = now;
+        if !was_typing {
+            self.broadcast_except(
+                Msg::notice(self.id, user, format!("{} is typing...", user)),
+                Some(user),
+            );
+        }
+    }
+
+    fn away(&mut self, user: &str, away: bool) {
+        let Some(member) = self.member_mut(user) else {
+            return;
+        };
+        if member.away == away {
+            return;
+        }
+        member.away = away;
+        member.typing_until = None;
+        let notice = if away {
+            format!("{} lost connection and may be back soon.", user)
+        } else {
+            member.last_active = Instant::now();
+            format!("{} is back.", user)
+        };
+        self.broadcast_except(Msg::notice(self.id, user, notice), Some(user));
+    }
+
+    fn who(&self, now: Instant) -> String {
+        let mut text = format!("Members of {} ({}):\n", self.name, self.members.len());
+        for member in self.members(now) {
+            let mut status = vec![];
+            if member.owner {
+                status.push("owner".to_string());
+            }
+            let idle = Duration::from_secs(member.idle_secs);
+            if idle >= IDLE_AFTER {
+                status.push(format!("idle {}", format_idle(idle)));
+            } else {
+                status.push("active".to_string());
+            }
+            if member.typing {
+                status.push("typing".to_string());
+            }
+            if member.away {
+                status.push("away".to_string());
+            }
+            text.push_str(&format!("  {} ({})\n", member.nick, status.join(", ")));
+        }
+        text
+    }
+
+    fn members(&self, now: Instant) -> Vec<MemberInfo> {
+        self.members
+            .iter()
+            .map(|m| MemberInfo {
+                nick: m.nick.clone(),
+                owner: self.is_owner(&m.nick),
+                idle_secs: now.saturating_duration_since(m.last_active).as_secs(),
+                typing: m.typing_until.is_some_and(|until| until > now),
+                away: m.away,
+            })
+            .collect()
+    }
+
+    /// 成员都收到 `Kicked`，回到大厅；actor 随后发现房间空了就退出
+    fn close(&mut self) -> usize {
+        let members = std::mem::take(&mut self.members);
+        for member in &members {
+            let _ = member.sender.send(Msg {
+                kind: MsgKind::Kicked,
+                ..Msg::notice(
+                    self.id,
+                    "server",
+                    format!("The room {} was closed by the server.", self.name),
+                )
+            });
+        }
+        self.owner = None;
+        self.publish_info();
+        members.len()
+    }
+
+    fn topic(&mut self, user: &str, topic: Option<String>) -> AnyResult<String> {
+        match topic {
+            None => match &self.topic {
+                Some(topic) => Ok(format!("Topic: {}\n", topic)),
+                None => Ok("No topic set.\n".to_string()),
+            },
+            Some(topic) => {
+                self.broadcast(Msg::notice(
+                    self.id,
+                    user,
+                    format!("{} set the topic: {}", user, topic),
+                ));
+                self.topic = Some(topic);
+                self.publish_info();
+                Ok("Topic updated.\n".to_string())
+            }
+        }
+    }
+
+    fn kick(
+        &mut self,
+        user: &str,
+        target: &str,
+        ban: bool,
+        target_ip: Option<IpAddr>,
+    ) -> AnyResult<String> {
+        let verb = if ban { "banned" } else { "kicked" };
+        self.check_owner(user)?;
+        if target.eq_ignore_ascii_case(user) {
+            return Err(AnyError::quick(
+                format!("you cannot be {} by yourself", verb),
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        if ban {
+            // 昵称和 `validate_nick` 一样只有 ASCII，转成小写就不分大小写了
+            self.banned.insert(target.to_ascii_lowercase());
+            self.banned_ips.extend(target_ip);
+        }
+        let Some(target) = self.find_user(target) else {
+            return if ban {
+                Ok(format!("{} is banned from the room.\n", target))
+            } else {
+                Err(AnyError::quick(
+                    format!("{} is not in this room", target),
+                    anyverr::ErrKind::EntityAbsence,
+                ))
+            };
+        };
+        if let Some(member) = self.members.iter().find(|m| m.nick == target) {
+            let _ = member.sender.send(Msg {
+                kind: MsgKind::Kicked,
+                ..Msg::notice(
+                    self.id,
+                    user,
+                    format!("You were {} from {} by {}.", verb, self.name, user),
+                )
+            });
+        }
+        self.remove_user(&target);
+        self.broadcast(Msg::notice(
+            self.id,
+            user,
+            format!("{} was {} by {}.", target, verb, user),
+        ));
+        Ok(format!("{} {}.\n", target, verb))
+    }
+
+    fn lock(&mut self, user: &str, locked: bool) -> AnyResult<String> {
+        self.check_owner(user)?;
+        self.locked = locked;
+        self.publish_info();
+        let verb = if locked { "locked" } else { "unlocked" };
+        self.broadcast(Msg::notice(
+            self.id,
+            user,
+            format!("{} {} the room.", user, verb),
+        ));
+        Ok(format!("Room {}.\n", verb))
+    }
+
+    fn add_user(&mut self, user: &str, sender: Arc<UnboundedSender<Msg>>) {
+        match self.member_mut(user) {
+            Some(member) => member.sender = sender,
+            None => self.members.push(Member {
+                nick: user.to_string(),
+                sender,
+                last_active: Instant::now(),
+                typing_until: None,
+                away: false,
+            }),
+        }
+        self.publish_info();
+    }
+
+    fn remove_user(&mut self, user: &str) {
+        self.members.retain(|m| m.nick != user);
+        if self.is_owner(user) {
+            self.owner = self.members.first().map(|m| m.nick.clone());
+        }
+        self.publish_info();
+    }
+
+    fn user_exists(&self, user: &str) -> bool {
+        self.members.iter().any(|m| m.nick == user)
+    }
+
+    fn member_mut(&mut self, user: &str) -> Option<&mut Member> {
+        self.members.iter_mut().find(|m| m.nick == user)
+    }
+
+    fn touch(&mut self, user: &str) {
+        if let Some(member) = self.member_mut(user) {
+            member.last_active = Instant::now();
+        }
+    }
+
+    /// 不区分大小写地查找成员，返回其昵称
+    fn find_user(&self, user: &str) -> Option<String> {
+        self.members
+            .iter()
+            .find(|m| m.nick.eq_ignore_ascii_case(user))
+            .map(|m| m.nick.clone())
+    }
+
+    fn is_owner(&self, user: &str) -> bool {
+        self.owner.as_deref() == Some(user)
+    }
+
+    fn check_owner(&self, user: &str) -> AnyResult<()> {
+        if self.is_owner(user) {
+            Ok(())
+        } else {
+            Err(AnyError::quick(
+                "only the room owner can do that",
+                anyverr::ErrKind::RuleViolation,
+            ))
+        }
+    }
+
+    /// 发给房间里的所有人
+    fn broadcast(&self, msg: Msg) {
+        self.broadcast_except(msg, None);
+    }
+
+    fn broadcast_except(&self, msg: Msg, except: Option<&str>) {
+        for member in &self.members {
+            if Some(member.nick.as_str()) != except {
+                let _ = member.sender.send(msg.clone());
+            }
+        }
+    }
+
+    /// 每次成员、锁或话题变化后更新对外的概况，赶在回复请求之前
+    fn publish_info(&self) {
+        self.info.send_if_modified(|info| {
+            let next = RoomInfo {
+                members: self.members.len(),
+                locked: self.locked,
+                encrypted: self.encrypted,
+                topic: self.topic.clone(),
+            };
+            let modified = info.members != next.members
+                || info.locked != next.locked
+                || info.encrypted != next.encrypted
+                || info.topic != next.topic;
+            *info = next;
+            modified
+        });
+    }
+}
+
+/// 把空闲时间格式化成 `42s`、`3m 12s`、`2h 3m` 这样
+fn format_idle(idle: Duration) -> String {
+    let secs = idle.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn channel() -> (Arc<UnboundedSender<Msg>>, UnboundedReceiver<Msg>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Arc::new(tx), rx)
+    }
+
+    #[test]
+    fn test_owner_transfer() {
+        let (mut room, _info) = Room::new("rust".into(), None, "alice");
+        room.add_user("alice", channel().0);
+        room.add_user("bob", channel().0);
+        room.remove_user("alice");
+        assert!(room.is_owner("bob"));
+        room.remove_user("bob");
+        assert_eq!(room.owner, None);
+    }
+
+    #[test]
+    fn test_presence() {
+        let (mut room, _info) = Room::new("rust".into(), None, "alice");
+        let (alice, mut alice_rx) = channel();
+        let (bob, mut bob_rx) = channel();
+        room.add_user("alice", alice);
+        room.add_user("bob", bob);
+        let start = Instant::now();
+
+        room.typing("bob", start);
+        assert_eq!(alice_rx.try_recv().unwrap().data, "bob is typing...");
+        assert!(bob_rx.try_recv().is_err());
+        // 状态还没消失时续期不再通知
+        room.typing("bob", start + Duration::from_secs(2));
+        assert!(alice_rx.try_recv().is_err());
+        room.typing("bob", start + Duration::from_secs(8));
+        assert_eq!(alice_rx.try_recv().unwrap().data, "bob is typing...");
+
+        room.rename("alice", "carol");
+        assert_eq!(
+            bob_rx.try_recv().unwrap().data,
+            "alice is now known as carol."
+        );
+        assert!(room.is_owner("carol"));
+        assert!(alice_rx.try_recv().is_err());
+
+        room.members[0].last_active = start;
+        let who = room.who(start + Duration::from_secs(9));
+        assert_eq!(
+            who,
+            "Members of rust (2):\n  carol (owner, active)\n  bob (active, typing)\n"
+        );
+        let who = room.who(start + Duration::from_secs(200));
+        assert_eq!(
+            who,
+            "Members of rust (2):\n  carol (owner, idle 3m 20s)\n  bob (idle 3m 12s)\n"
+        );
+
+        // 断线的成员留在房间里，其他人看到它暂时离开和回来
+        room.away("bob", true);
+        room.away("bob", true);
+        assert_eq!(
+            alice_rx.try_recv().unwrap().data,
+            "bob lost connection and may be back soon."
+        );
+        assert!(alice_rx.try_recv().is_err());
+        assert!(room.members(start)[1].away);
+        room.away("bob", false);
+        assert_eq!(alice_rx.try_recv().unwrap().data, "bob is back.");
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_room_ids_survive_restarts() {
+        // 上一次运行发出的 ID 都小于这次启动时刻对应的起点
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let id = fetch_latest_room_id();
+        assert!(id >= (started - 60) << ROOM_ID_SHIFT);
+        assert!(fetch_latest_room_id() > id);
+    }
+
+    #[test]
+    fn test_ban() {
+        let (mut room, _info) = Room::new("rust".into(), None, "alice");
+        room.add_user("alice", channel().0);
+        room.add_user("bob", channel().0);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        // 按昵称封禁，同一个 IP 上的其他人不受影响
+        assert!(room.kick("alice", "Bob", true, None).is_ok());
+        assert!(room.find_user("bob").is_none());
+        assert!(room.join("BOB", other, channel().0).is_err());
+        assert!(room.join("carol", IP, channel().0).is_ok());
+
+        // 不在线的人也能封禁
+        assert_eq!(
+            room.kick("alice", "dave", true, None).unwrap(),
+            "dave is banned from the room.\n"
+        );
+        assert!(room.join("dave", other, channel().0).is_err());
+
+        // 连 IP 一起封禁时换个昵称也进不来
+        assert!(room.kick("alice", "carol", true, Some(IP)).is_ok());
+        assert!(room.join("erin", IP, channel().0).is_err());
+        assert!(room.join("erin", other, channel().0).is_ok());
+    }
+
+    #[test]
+    fn test_format_idle() {
+        assert_eq!(format_idle(Duration::from_secs(42)), "42s");
+        assert_eq!(format_idle(Duration::from_secs(192)), "3m 12s");
+        assert_eq!(format_idle(Duration::from_secs(7380)), "2h 3m");
+    }
+
+    #[tokio::test]
+    async fn test_room_actor() -> AnyResult<()> {
+        let directory = Arc::new(Directory::default());
+        let (alice, mut alice_rx) = channel();
+        let (bob, mut bob_rx) = channel();
+        let room = directory.create_room("rust".into(), None, false, "alice", alice.clone())?;
+        assert!(
+            directory
+                .create_room("RUST".into(), None, false, "bob", bob.clone())
+                .is_err()
+        );
+
+        let found = directory.find_room("Rust").unwrap();
+        found.join("bob", IP, bob).await?;
+        assert_eq!(
+            alice_rx.recv().await.unwrap().data,
+            "bob has joined the room."
+        );
+        found.say("bob", "hi".into()).await;
+        assert_eq!(
+            alice_rx.recv().await.unwrap(),
+            Msg::chat(room.id(), "bob", "hi".into())
+        );
+        assert!(bob_rx.try_recv().is_err());
+        assert!(room.lock("bob", true).await.is_err());
+        assert_eq!(room.lock("alice", true).await?, "Room locked.\n");
+        assert!(room.summary().ends_with("(2 users, locked)"));
+        assert_eq!(bob_rx.recv().await.unwrap().data, "alice locked the room.");
+
+        room.leave("alice", LeaveReason::Disconnect).await;
+        assert_eq!(bob_rx.recv().await.unwrap().data, "alice disconnected.");
+        assert_eq!(
+            bob_rx.recv().await.unwrap().data,
+            "bob is now the owner of the room."
+        );
+        room.leave("bob", LeaveReason::Quit).await;
+        // 最后一个人离开后房间关闭并从目录里消失
+        assert!(room.topic("bob", None).await.is_err());
+        assert!(directory.find_room("rust").is_none());
+        Ok(())
+    }
+}
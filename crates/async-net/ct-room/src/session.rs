@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{
+        Notify,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time,
+};
+
+use crate::{Msg, MsgKind, RoomHandle, e2e::to_hex};
+
+/// 断线后会话默认保留多久
+pub(crate) const RESUME_GRACE: Duration = Duration::from_secs(60);
+/// 接管仍然在线的旧连接时，最多等它交出会话多久
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(2);
+/// 断线期间最多攒多少条消息，恢复会话时也最多补发这么多，更早的只告诉条数
+pub(crate) const MAX_MISSED: usize = 100;
+
+/// 一个已登录的会话，断线后连同没读的消息一起保留，`.resume` 时交给新的连接
+#[derive(Debug)]
+pub(crate) struct Client {
+    pub nick: String,
+    pub addr: SocketAddr,
+    /// `.resume` 用的令牌
+    pub token: String,
+    pub sender: Arc<UnboundedSender<Msg>>,
+    /// 整个会话期间不变，房间消息和私信都从这里来
+    pub receiver: UnboundedReceiver<Msg>,
+    /// 断线期间从 `receiver` 里取出来的消息，恢复时先补发这些
+    pub missed: Missed,
+    pub room: Option<RoomHandle>,
+}
+
+/// 断线期间攒下的消息，最多 [`MAX_MISSED`] 条，满了丢掉最早的并记下条数
+#[derive(Debug, Default)]
+pub(crate) struct Missed {
+    pub msgs: VecDeque<Msg>,
+    pub dropped: usize,
+}
+
+impl Missed {
+    fn push(&mut self, msg: Msg) {
+        if self.msgs.len() >= MAX_MISSED {
+            // 被踢和被断开决定了恢复后的状态，不能丢
+            let oldest = self
+                .msgs
+                .iter()
+                .position(|m| !matches!(m.kind, MsgKind::Kicked | MsgKind::Disconnect));
+            if let Some(i) = oldest {
+                self.msgs.remove(i);
+                self.dropped += 1;
+            }
+        }
+        self.msgs.push_back(msg);
+    }
+}
+
+/// 断线的会话交给一个任务，由它把消息从无界的 `receiver` 挪进有上限的 [`Missed`]
+#[derive(Debug)]
+struct Parked {
+    task: JoinHandle<Client>,
+    stop: oneshot::Sender<()>,
+    /// 区分同一会话的多次断线，旧的过期计时不会结束新的一次
+    epoch: u64,
+}
+
+impl Parked {
+    fn spawn(mut client: Client, epoch: u64) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = &mut stopped => break,
+                    Some(msg) = client.receiver.recv() => client.missed.push(msg),
+                }
+            }
+            client
+        });
+        Self { task, stop, epoch }
+    }
+
+    /// 停下任务，取回会话；还没挪走的消息留在 `receiver` 里
+    async fn unpark(self) -> Option<Client> {
+        let _ = self.stop.send(());
+        self.task.await.ok()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// 有连接的会话，接管时通过它通知旧连接
+    live: HashMap<String, Arc<UnboundedSender<Msg>>>,
+    parked: HashMap<String, Parked>,
+    epoch: u64,
+}
+
+/// 按令牌登记所有会话
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    inner: Mutex<Inner>,
+    parked_notify: Notify,
+    grace: Duration,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(RESUME_GRACE)
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new(grace: Duration) -> Self {
+        Self {
+            inner: Mutex::default(),
+            parked_notify: Notify::new(),
+            grace,
+        }
+    }
+
+    /// 为零时断线立刻结束会话
+    pub(crate) fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// 登记一个新会话，返回它的令牌
+    pub(crate) fn start(&self, sender: Arc<UnboundedSender<Msg>>) -> String {
+        let token = to_hex(&en_de::random_bytes::<16>());
+        self.inner
+            .lock()
+            .unwrap()
+            .live
+            .insert(token.clone(), sender);
+        token
+    }
+
+    pub(crate) fn end(&self, token: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.live.remove(token);
+        inner.parked.remove(token);
+    }
+
+    /// 连接断了，把会话留给 `.resume`；返回这一次断线的编号，过期时要用
+    pub(crate) fn park(&self, client: Client) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.epoch += 1;
+        let epoch = inner.epoch;
+        inner.live.remove(&client.token);
+        inner
+            .parked
+            .insert(client.token.clone(), Parked::spawn(client, epoch));
+        drop(inner);
+        self.parked_notify.notify_waiters();
+        epoch
+    }
+
+    /// 宽限期到了还没人恢复时取出会话，由调用方结束它
+    pub(crate) async fn expire(&self, token: &str, epoch: u64) -> Option<Client> {
+        let parked = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.parked.get(token)?.epoch != epoch {
+                return None;
+            }
+            inner.parked.remove(token)?
+        };
+        parked.unpark().await
+    }
+
+    pub(crate) fn parked(&self) -> usize {
+        self.inner.lock().unwrap().parked.len()
+    }
+
+    /// 取出 `token` 对应的会话，交给调用它的新连接
+    ///
+    /// 网络断了服务器未必马上察觉，所以会话还挂在旧连接上时，先让旧连接交出来再取。
+    pub(crate) async fn resume(&self, token: &str) -> Option<Client> {
+        let deadline = time::Instant::now() + TAKEOVER_TIMEOUT;
+        let mut asked = false;
+        loop {
+            // 先登记等待再检查，避免错过检查之后、等待之前的通知
+            let notified = self.parked_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let parked = {
+                let mut inner = self.inner.lock().unwrap();
+                let parked = inner.parked.remove(token);
+                if parked.is_none() {
+                    let old = inner.live.get(token)?;
+                    if !asked {
+                        let _ = old.send(Msg::takeover(
+                            "Your session was resumed from another connection.".into(),
+                        ));
+                        asked = true;
+                    }
+                }
+                parked
+            };
+            let Some(parked) = parked else {
+                time::timeout_at(deadline, notified).await.ok()?;
+                continue;
+            };
+            let client = parked.unpark().await?;
+            self.inner
+                .lock()
+                .unwrap()
+                .live
+                .insert(token.to_string(), client.sender.clone());
+            return Some(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::MsgKind;
+
+    fn client(sessions: &Sessions) -> (Client, UnboundedSender<Msg>) {
+        let (tx, receiver) = mpsc::unbounded_channel();
+        let sender = Arc::new(tx.clone());
+        let client = Client {
+            nick: "alice".into(),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            token: sessions.start(sender.clone()),
+            sender,
+            receiver,
+            missed: Missed::default(),
+            room: None,
+        };
+        (client, tx)
+    }
+
+    #[tokio::test]
+    async fn test_park_and_resume() {
+        let sessions = Sessions::default();
+        let (alice, tx) = client(&sessions);
+        let token = alice.token.clone();
+        assert_eq!(token.len(), 32);
+        assert!(sessions.resume("nope").await.is_none());
+
+        let first = sessions.park(alice);
+        tx.send(Msg::private("bob", "missed you".into())).unwrap();
+        // 让攒消息的任务先跑一轮
+        tokio::task::yield_now().await;
+        let alice = sessions.resume(&token).await.unwrap();
+        assert_eq!(alice.missed.msgs[0].data, "missed you");
+        assert_eq!(sessions.parked(), 0);
+
+        // 第一次断线的过期计时不影响第二次
+        let second = sessions.park(alice);
+        assert!(sessions.expire(&token, first).await.is_none());
+        assert!(sessions.expire(&token, second).await.is_some());
+        assert!(sessions.resume(&token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_missed_is_bounded() {
+        let sessions = Sessions::default();
+        let (alice, tx) = client(&sessions);
+        let token = alice.token.clone();
+        sessions.park(alice);
+        tx.send(Msg {
+            kind: MsgKind::Kicked,
+            ..Msg::notice(1, "bob", "You were kicked.".into())
+        })
+        .unwrap();
+        for i in 0..MAX_MISSED + 3 {
+            tx.send(Msg::private("bob", i.to_string())).unwrap();
+        }
+        tokio::task::yield_now().await;
+        let mut alice = sessions.resume(&token).await.unwrap();
+        assert!(alice.receiver.try_recv().is_err());
+
+        // 最早的私信被丢掉，被踢的通知留着
+        let missed = &alice.missed;
+        assert_eq!(missed.msgs.len(), MAX_MISSED);
+        assert_eq!(missed.dropped, 4);
+        assert_eq!(missed.msgs[0].kind, MsgKind::Kicked);
+        assert_eq!(missed.msgs[1].data, "4");
+    }
+
+    #[tokio::test]
+    async fn test_takeover() {
+        let sessions = Arc::new(Sessions::default());
+        let (mut alice, _tx) = client(&sessions);
+        let token = alice.token.clone();
+        // 模拟还没察觉断线的旧连接：收到接管通知后交出会话
+        let old = {
+            let sessions = sessions.clone();
+            tokio::spawn(async move {
+                let msg = alice.receiver.recv().await.unwrap();
+                assert_eq!(msg.kind, MsgKind::Takeover);
+                sessions.park(alice);
+            })
+        };
+        let alice = sessions.resume(&token).await.unwrap();
+        assert_eq!(alice.nick, "alice");
+        old.await.unwrap();
+    }
+}
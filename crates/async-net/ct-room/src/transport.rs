@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use anyverr::{AnyError, AnyResult};
+use en_de::{FrameEncryptor, Handshake, PUBLIC_KEY_LEN, Role, SessionKeys};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpStream, tcp::OwnedReadHalf},
+    time,
+};
+
+/// 一帧最多携带的明文字节数
+pub const MAX_FRAME_LEN: usize = 16 * 1024;
+/// ChaCha20Poly1305 的认证标签长度
+const TAG_LEN: usize = 16;
+/// 对方迟迟不发公钥时放弃握手
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 连接的一端，可以是明文的 TCP，也可以是 [`secure`] 返回的明文管道
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+pub type Stream = Box<dyn Conn>;
+
+/// 在 `stream` 上做一次 X25519 握手，之后收发的都是加密的帧
+///
+/// 返回的管道另一头由后台任务负责加解密，读写的都是明文；任何一帧被拒绝时连接随即关闭。
+///
+/// 握手不认证任何一方，只能防住被动的窃听，挡不住主动的中间人：它可以冒充服务器和客户端
+/// 分别握手并转发解密后的内容。要对服务器保密消息内容，用端到端加密的房间。
+pub async fn secure(mut stream: TcpStream, role: Role) -> AnyResult<DuplexStream> {
+    let handshake = Handshake::new(role);
+    stream
+        .write_all(&handshake.public_key())
+        .await
+        .map_err(AnyError::wrap)?;
+    let mut peer = [0u8; PUBLIC_KEY_LEN];
+    time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut peer))
+        .await
+        .map_err(|_| AnyError::quick("handshake timed out", anyverr::ErrKind::RuleViolation))?
+        .map_err(AnyError::wrap)?;
+    let keys = handshake.finish(&peer)?;
+
+    let (app, pipe) = io::duplex(MAX_FRAME_LEN);
+    tokio::spawn(async move {
+        if let Err(e) = pump(stream, pipe, keys).await {
+            eprintln!("Secure transport closed: {}", e);
+        }
+    });
+    Ok(app)
+}
+
+/// 把管道里的明文加密后发出去，把收到的帧解密后写回管道，两个方向都结束时返回
+async fn pump(stream: TcpStream, pipe: DuplexStream, keys: SessionKeys) -> AnyResult<()> {
+    let SessionKeys { mut send, mut recv } = keys;
+    let (mut net_rx, mut net_tx) = stream.into_split();
+    let (mut pipe_rx, mut pipe_tx) = io::split(pipe);
+
+    let outbound = async {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        loop {
+            let n = pipe_rx.read(&mut buf).await.map_err(AnyError::wrap)?;
+            if n == 0 {
+                break;
+            }
+            let frame = seal_frame(&mut send, &buf[..n])?;
+            net_tx.write_all(&frame).await.map_err(AnyError::wrap)?;
+        }
+        net_tx.shutdown().await.map_err(AnyError::wrap)
+    };
+    let inbound = async {
+        while let Some(frame) = read_frame(&mut net_rx).await? {
+            let data = recv.decrypt_frame(&frame)?;
+            pipe_tx.write_all(&data).await.map_err(AnyError::wrap)?;
+        }
+        pipe_tx.shutdown().await.map_err(AnyError::wrap)
+    };
+    // 一个方向出错就整个放弃，另一个方向随之被丢弃
+    tokio::try_join!(outbound, inbound)?;
+    Ok(())
+}
+
+/// 4 字节大端长度加上密文
+fn seal_frame(send: &mut FrameEncryptor, data: &[u8]) -> AnyResult<Vec<u8>> {
+    let sealed = send.encrypt_frame(data)?;
+    let mut frame = Vec::with_capacity(4 + sealed.len());
+    frame.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&sealed);
+    Ok(frame)
+}
+
+/// 读一帧的密文，对方在帧与帧之间关闭时返回 `None`
+async fn read_frame(net_rx: &mut OwnedReadHalf) -> AnyResult<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match net_rx.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(AnyError::wrap(e)),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if !(TAG_LEN..=MAX_FRAME_LEN + TAG_LEN).contains(&len) {
+        return Err(AnyError::quick(
+            format!("frame of {} bytes rejected", len),
+            anyverr::ErrKind::RuleViolation,
+        ));
+    }
+    let mut frame = vec![0u8; len];
+    net_rx
+        .read_exact(&mut frame)
+        .await
+        .map_err(AnyError::wrap)?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secure_transport() -> AnyResult<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        let addr = listener.local_addr().map_err(AnyError::wrap)?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.map_err(AnyError::wrap)?;
+            let mut app = secure(stream, Role::Responder).await?;
+            let mut buf = [0u8; 5];
+            app.read_exact(&mut buf).await.map_err(AnyError::wrap)?;
+            app.write_all(b"pong!").await.map_err(AnyError::wrap)?;
+            // 重放的帧让连接关闭，读到 EOF
+            let n = app.read(&mut buf).await.map_err(AnyError::wrap)?;
+            AnyResult::Ok((buf, n))
+        });
+
+        // 客户端手工握手，这样才能重放自己发过的帧
+        let mut stream = TcpStream::connect(addr).await.map_err(AnyError::wrap)?;
+        let handshake = Handshake::new(Role::Initiator);
+        stream
+            .write_all(&handshake.public_key())
+            .await
+            .map_err(AnyError::wrap)?;
+        let mut peer = [0u8; PUBLIC_KEY_LEN];
+        stream.read_exact(&mut peer).await.map_err(AnyError::wrap)?;
+        let SessionKeys { mut send, mut recv } = handshake.finish(&peer)?;
+
+        let frame = seal_frame(&mut send, b"ping!")?;
+        stream.write_all(&frame).await.map_err(AnyError::wrap)?;
+        let (mut net_rx, mut net_tx) = stream.into_split();
+        let reply = read_frame(&mut net_rx).await?.unwrap();
+        assert_eq!(recv.decrypt_frame(&reply)?, b"pong!");
+        assert!(!reply.windows(5).any(|w| w == b"pong!"));
+
+        net_tx.write_all(&frame).await.map_err(AnyError::wrap)?;
+        let (buf, n) = server.await.map_err(AnyError::wrap)??;
+        assert_eq!(&buf, b"ping!");
+        assert_eq!(n, 0);
+        assert!(read_frame(&mut net_rx).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! 按行收发检查服务器的行为，模拟客户端 `Bot` 来自 `load` feature
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyverr::AnyResult;
+use ct_room::{Bot, Config, spawn_local};
+use tokio::time;
+
+async fn server(resume_grace_secs: u64) -> AnyResult<SocketAddr> {
+    spawn_local(Config {
+        resume_grace_secs,
+        ..Config::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_lobby_and_chatting() -> AnyResult<()> {
+    let addr = server(0).await?;
+    let mut alice = Bot::login(addr, "alice").await?;
+    let mut taken = Bot::connect(addr, "taken").await?;
+    taken.send("Alice").await?;
+    taken.expect("Not allowed: Alice is already taken").await?;
+    let mut bob = Bot::login(addr, "bob").await?;
+
+    // 大厅里不能说话，也不能用房间里的指令
+    alice.send("hello").await?;
+    alice.expect("Not allowed: you are not in a room").await?;
+    alice.send(".who").await?;
+    alice.expect("Not allowed: you are not in a room").await?;
+    bob.send(".join rust").await?;
+    bob.expect("Not found: there is no room rust").await?;
+
+    alice.send(".create rust Release day").await?;
+    alice
+        .expect("Successfully created and joined room: rust")
+        .await?;
+    assert_eq!(bob.rooms().await?, ["rust"]);
+    bob.send(".join RUST").await?;
+    bob.expect("Successfully joined room: rust").await?;
+    bob.expect("Topic: Release day").await?;
+    alice.expect("* bob has joined the room.").await?;
+
+    // 不保留会话时，断开的连接直接离开房间
+    taken.send("carol").await?;
+    taken.expect("Hi carol!").await?;
+    taken.send(".join rust").await?;
+    taken.expect("Successfully joined room: rust").await?;
+    drop(taken);
+    alice.expect("* carol disconnected.").await?;
+
+    bob.send("hi alice").await?;
+    alice.expect("[bob]: hi alice").await?;
+    alice.send("..hi bob").await?;
+    bob.expect("[alice]: .hi bob").await?;
+    // 消息原样转发，不裁掉缩进
+    alice.send("    fn main() {}  ").await?;
+    assert_eq!(bob.expect("[alice]:").await?, "[alice]:     fn main() {}  ");
+    alice.send(".create other").await?;
+    alice
+        .expect("Not allowed: you are already in a room")
+        .await?;
+
+    // 房主离开后房间移交给下一个人
+    alice.send(".quit").await?;
+    alice
+        .expect("You have left the room. Returning to lobby.")
+        .await?;
+    bob.expect("* alice has left the room.").await?;
+    bob.expect("* bob is now the owner of the room.").await?;
+    alice.send("still there?").await?;
+    alice.expect("Not allowed: you are not in a room").await?;
+    alice.send(".msg bob psst").await?;
+    alice.expect("Message sent to bob.").await?;
+    bob.expect("[alice -> you]: psst").await?;
+
+    // 最后一个人离开，房间随之消失
+    bob.send(".quit").await?;
+    bob.expect("You have left the room.").await?;
+    let mut retries = 50;
+    while !bob.rooms().await?.is_empty() {
+        retries -= 1;
+        assert!(retries > 0, "the empty room was not removed");
+        time::sleep(Duration::from_millis(20)).await;
+    }
+    bob.send(".quit").await?;
+    bob.expect("Goodbye!").await?;
+    bob.expect_closed().await
+}
+
+#[tokio::test]
+async fn test_resume() -> AnyResult<()> {
+    let addr = server(60).await?;
+    let mut alice = Bot::connect(addr, "alice").await?;
+    alice.send("alice").await?;
+    let line = alice.expect("Your session token is ").await?;
+    let token = line["Your session token is ".len()..]
+        .split('.')
+        .next()
+        .unwrap()
+        .to_string();
+    alice.send(".create rust").await?;
+    alice.expect("Successfully created").await?;
+    let mut bob = Bot::login(addr, "bob").await?;
+    bob.send(".join rust").await?;
+    bob.expect("Successfully joined").await?;
+
+    // 断线的人留在房间里，消息替它攒着
+    drop(alice);
+    bob.expect("* alice lost connection and may be back soon.")
+        .await?;
+    bob.send("are you there?").await?;
+    bob.send(".msg alice call me").await?;
+    bob.expect("Message sent to alice.").await?;
+
+    let mut alice = Bot::connect(addr, "alice").await?;
+    alice.send(".resume nope").await?;
+    alice.expect("Not found: no session to resume").await?;
+    alice.send(&format!(".resume {}", token)).await?;
+    alice
+        .expect("Welcome back alice! You are in room rust")
+        .await?;
+    alice.expect("You missed 2 message(s):").await?;
+    alice.expect("[bob]: are you there?").await?;
+    alice.expect("[bob -> you]: call me").await?;
+    bob.expect("* alice is back.").await?;
+    alice.send("yes").await?;
+    bob.expect("[alice]: yes").await?;
+
+    // 主动退出之后令牌作废
+    alice.send(".quit").await?;
+    bob.expect("* alice has left the room.").await?;
+    alice.send(".quit").await?;
+    alice.expect("Goodbye!").await?;
+    let mut eve = Bot::connect(addr, "eve").await?;
+    eve.send(&format!(".resume {}", token)).await?;
+    eve.expect("Not found: no session to resume").await?;
+    Ok(())
+}
@@ -6,22 +6,34 @@ use serde::{Deserialize, Serialize};
 use crate::AnyResult;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub urls: Vec<String>,
+    /// 同时进行的请求数
     pub con: usize,
+    /// 单个请求的超时，毫秒
     pub timeout: u64,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            con: 10,
+            timeout: 3000,
+        }
+    }
+}
+
 impl Config {
     pub fn load(file: impl Into<PathBuf>) -> AnyResult<Config> {
         let mut file = OpenOptions::new()
             .read(true)
             .open(file.into())
-            .map_err(|e| AnyError::wrap(e))?;
+            .map_err(AnyError::wrap)?;
         let mut s = String::new();
-        file.read_to_string(&mut s).map_err(|e| AnyError::wrap(e))?;
-        let c: Config = serde_json::from_str(&s).map_err(|e| AnyError::wrap(e))?;
-        dbg!(&c);
+        file.read_to_string(&mut s).map_err(AnyError::wrap)?;
+        let c: Config = serde_json::from_str(&s).map_err(AnyError::wrap)?;
         Ok(c)
     }
 }
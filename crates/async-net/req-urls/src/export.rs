@@ -0,0 +1,146 @@
+use std::{borrow::Cow, io::Write, path::Path, str::FromStr};
+
+use anyverr::{AnyError, AnyResult};
+use serde::Serialize;
+
+/// 一个 URL 的请求结果
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UrlResult {
+    pub url: String,
+    /// 没有收到响应时为空
+    pub status: Option<u16>,
+    /// 响应体的字节数，没读完时为空
+    pub bytes: Option<u64>,
+    /// 从发出请求到读完响应体
+    pub latency_ms: f64,
+    /// 连接失败、超时或者状态码不是 2xx
+    pub error: Option<String>,
+}
+
+impl UrlResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// 结果的导出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// 每行一个 JSON 对象
+    JsonLines,
+    /// 带表头的 CSV
+    Csv,
+}
+
+impl ExportFormat {
+    /// `.csv` 导出为 CSV，其余的都是 JSON lines
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::JsonLines,
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "jsonl" | "json-lines" | "ndjson" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            _ => Err(AnyError::quick(
+                format!("unknown export format {}, expected jsonl or csv", s),
+                anyverr::ErrKind::ValueValidation,
+            )),
+        }
+    }
+}
+
+/// 把所有结果按 `format` 写到 `out`
+pub fn export(out: &mut impl Write, format: ExportFormat, results: &[UrlResult]) -> AnyResult<()> {
+    match format {
+        ExportFormat::JsonLines => {
+            for result in results {
+                serde_json::to_writer(&mut *out, result).map_err(AnyError::wrap)?;
+                writeln!(out).map_err(AnyError::wrap)?;
+            }
+        }
+        ExportFormat::Csv => {
+            writeln!(out, "url,status,bytes,latency_ms,error").map_err(AnyError::wrap)?;
+            for r in results {
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    csv_field(&r.url),
+                    r.status.map(|s| s.to_string()).unwrap_or_default(),
+                    r.bytes.map(|b| b.to_string()).unwrap_or_default(),
+                    r.latency_ms,
+                    csv_field(r.error.as_deref().unwrap_or_default()),
+                )
+                .map_err(AnyError::wrap)?;
+            }
+        }
+    }
+    out.flush().map_err(AnyError::wrap)
+}
+
+/// 含有逗号、引号或换行的字段用引号括起来
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() -> AnyResult<()> {
+        let results = [
+            UrlResult {
+                url: "http://a.test/".into(),
+                status: Some(200),
+                bytes: Some(512),
+                latency_ms: 12.5,
+                error: None,
+            },
+            UrlResult {
+                url: "http://b.test/?q=1,2".into(),
+                status: None,
+                bytes: None,
+                latency_ms: 3000.0,
+                error: Some("get url: \"timed out\"".into()),
+            },
+        ];
+
+        let mut out = vec![];
+        export(&mut out, ExportFormat::JsonLines, &results)?;
+        let out = String::from_utf8(out).map_err(AnyError::wrap)?;
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            r#"{"url":"http://a.test/","status":200,"bytes":512,"latency_ms":12.5,"error":null}"#
+        );
+        assert_eq!(lines.len(), 2);
+
+        let mut out = vec![];
+        export(&mut out, ExportFormat::Csv, &results)?;
+        assert_eq!(
+            String::from_utf8(out).map_err(AnyError::wrap)?,
+            "url,status,bytes,latency_ms,error\n\
+             http://a.test/,200,512,12.5,\n\
+             \"http://b.test/?q=1,2\",,,3000,\"get url: \"\"timed out\"\"\"\n"
+        );
+
+        assert_eq!(
+            ExportFormat::from_path(Path::new("out.csv")),
+            ExportFormat::Csv
+        );
+        assert_eq!("ndjson".parse::<ExportFormat>()?, ExportFormat::JsonLines);
+        Ok(())
+    }
+}
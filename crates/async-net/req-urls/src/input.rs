@@ -0,0 +1,170 @@
+use std::{path::Path, str::FromStr};
+
+use anyverr::{AnyError, AnyResult};
+use serde::Deserialize;
+
+/// URL 列表的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// 每行一个 URL，忽略空行和 `#` 开头的行
+    Lines,
+    /// 字符串数组、带 `url` 字段的对象数组，或者和配置文件一样的 `{"urls": [...]}`
+    Json,
+    /// 有 `url` 表头时取那一列，否则取第一列
+    Csv,
+}
+
+impl InputFormat {
+    /// 按扩展名判断格式，判断不了时按行读取
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension().and_then(|e| e.to_str());
+        match ext.map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("json") => Self::Json,
+            Some("csv") => Self::Csv,
+            _ => Self::Lines,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "lines" | "txt" => Ok(Self::Lines),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(AnyError::quick(
+                format!("unknown input format {}, expected lines, json or csv", s),
+                anyverr::ErrKind::ValueValidation,
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInput {
+    Urls(Vec<JsonUrl>),
+    Config { urls: Vec<JsonUrl> },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonUrl {
+    Plain(String),
+    Entry { url: String },
+}
+
+/// 从 `text` 里读出 URL 列表，保持原来的顺序
+pub fn parse_urls(text: &str, format: InputFormat) -> AnyResult<Vec<String>> {
+    let urls = match format {
+        InputFormat::Lines => text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect(),
+        InputFormat::Json => {
+            let (JsonInput::Urls(urls) | JsonInput::Config { urls }) =
+                serde_json::from_str(text).map_err(AnyError::wrap)?;
+            urls.into_iter()
+                .map(|u| match u {
+                    JsonUrl::Plain(url) | JsonUrl::Entry { url } => url,
+                })
+                .collect()
+        }
+        InputFormat::Csv => {
+            let rows = parse_csv(text)?;
+            let column = rows.first().and_then(|header| {
+                header
+                    .iter()
+                    .position(|name| name.trim().eq_ignore_ascii_case("url"))
+            });
+            rows.into_iter()
+                .skip(column.is_some() as usize)
+                .filter_map(|row| row.into_iter().nth(column.unwrap_or(0)))
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect()
+        }
+    };
+    Ok(urls)
+}
+
+/// 按 RFC 4180 切分，`"` 括起来的字段里可以有逗号、换行和写成 `""` 的引号；跳过空行
+fn parse_csv(text: &str) -> AnyResult<Vec<Vec<String>>> {
+    let (mut rows, mut row, mut field) = (vec![], vec![], String::new());
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                } else {
+                    row.clear();
+                }
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(AnyError::quick(
+            "unterminated quote in CSV input",
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    if !row.is_empty() || !field.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_urls() -> AnyResult<()> {
+        let expected = ["http://a.test/", "http://b.test/?q=1,2"];
+
+        let lines = "# deploy smoke check\nhttp://a.test/\n\n  http://b.test/?q=1,2  \n";
+        assert_eq!(parse_urls(lines, InputFormat::Lines)?, expected);
+
+        let json = r#"["http://a.test/", {"url": "http://b.test/?q=1,2", "note": "x"}]"#;
+        assert_eq!(parse_urls(json, InputFormat::Json)?, expected);
+        let config = r#"{"urls": ["http://a.test/", "http://b.test/?q=1,2"], "con": 2}"#;
+        assert_eq!(parse_urls(config, InputFormat::Json)?, expected);
+        assert!(parse_urls(r#"{"url": 1}"#, InputFormat::Json).is_err());
+
+        let csv = "name,URL\r\nhome,http://a.test/\r\n\r\n\"search, with \"\"q\"\"\",\"http://b.test/?q=1,2\"\r\n";
+        assert_eq!(parse_urls(csv, InputFormat::Csv)?, expected);
+        // 没有 url 表头时取第一列
+        let csv = "http://a.test/,home\nhttp://b.test/?q=1,2";
+        assert_eq!(
+            parse_urls(csv, InputFormat::Csv)?,
+            ["http://a.test/", "http://b.test/?q=1"]
+        );
+        assert!(parse_urls("\"http://a.test/", InputFormat::Csv).is_err());
+
+        assert_eq!(
+            InputFormat::from_path(Path::new("urls.CSV")),
+            InputFormat::Csv
+        );
+        assert_eq!("Json".parse::<InputFormat>()?, InputFormat::Json);
+        assert!("xml".parse::<InputFormat>().is_err());
+        Ok(())
+    }
+}
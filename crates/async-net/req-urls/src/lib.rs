@@ -1,4 +1,6 @@
 mod config;
+mod export;
+mod input;
 use std::{
     sync::Arc,
     time::{self, Duration},
@@ -7,72 +9,74 @@ use std::{
 use anyverr::{AnyError, AnyResult};
 // Include this in wherever you need `AnyError`.
 pub use config::*;
+pub use export::*;
+pub use input::*;
 use reqwest::Client;
 use tokio::{sync::Semaphore, task::JoinSet};
 
-pub async fn run(config: Config) -> AnyResult<()> {
+/// 请求 `config.urls` 里的每个 URL，结果和 URL 的顺序一致；单个请求失败记在结果里
+pub async fn run(config: Config) -> AnyResult<Vec<UrlResult>> {
     let urls = config.urls;
+    if urls.is_empty() {
+        return Err(AnyError::quick(
+            "no urls to request",
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
 
-    let concurancy = config.con.min(urls.len());
+    let concurancy = config.con.clamp(1, urls.len());
     let sem = Arc::new(Semaphore::new(concurancy));
     let client = reqwest::ClientBuilder::new()
         .timeout(Duration::from_millis(config.timeout))
         .no_proxy()
         .build()
-        .map_err(|e| AnyError::wrap(e))?;
+        .map_err(AnyError::wrap)?;
 
-    let timer = time::Instant::now();
+    let mut results = vec![None; urls.len()];
     let mut tasks = JoinSet::new();
 
-    for url in urls {
-        let premit = sem
-            .clone()
-            .acquire_owned()
-            .await
-            .map_err(|e| AnyError::wrap(e))?;
+    for (i, url) in urls.into_iter().enumerate() {
+        let premit = sem.clone().acquire_owned().await.map_err(AnyError::wrap)?;
         let client = client.clone();
-        let permit_count = concurancy - sem.available_permits();
         tasks.spawn(async move {
             let _p = premit;
-            tokio::time::sleep(Duration::from_millis(50)).await;
-            println!("{}", permit_count);
-            req_text(client, url).await
+            (i, request(client, url).await)
         });
     }
 
     while let Some(res) = tasks.join_next().await {
-        match res {
-            Ok(Ok(data)) => {
-                println!("{}", data.len());
-            }
-            Ok(Err(e)) => println!("Ok::Err: {e}"),
-            Err(e) => println!("Err::Err: {e}"),
-        }
+        let (i, result) = res.map_err(AnyError::wrap)?;
+        results[i] = Some(result);
     }
 
-    println!("elapsed: {}ms", timer.elapsed().as_millis());
-
-    Ok(())
+    Ok(results.into_iter().flatten().collect())
 }
 
-async fn req_text(client: Client, url: String) -> AnyResult<String> {
-    let resp = client.get(url).send().await.map_err(|e| {
-        AnyError::builder()
-            .message(format!("get url: {}", e.to_string()))
-            .build()
-    })?;
-
-    // 检查 HTTP 状态码，只有 200 状态码才会继续处理
-    if !resp.status().is_success() {
-        return Err(AnyError::builder()
-            .message(format!("HTTP error: {} - {}", resp.status(), resp.url()))
-            .build());
+async fn request(client: Client, url: String) -> UrlResult {
+    let timer = time::Instant::now();
+    let (mut status, mut bytes, mut error) = (None, None, None);
+    match client.get(&url).send().await {
+        Ok(resp) => {
+            status = Some(resp.status());
+            // 不是 2xx 的响应也读完，字节数可以用来对照
+            match resp.bytes().await {
+                Ok(body) => bytes = Some(body.len() as u64),
+                Err(e) => error = Some(format!("body: {}", e)),
+            }
+        }
+        Err(e) => error = Some(format!("get url: {}", e)),
+    }
+    // 检查 HTTP 状态码，只有 2xx 算成功
+    if let Some(status) = status
+        && !status.is_success()
+    {
+        error.get_or_insert_with(|| format!("HTTP error: {}", status));
+    }
+    UrlResult {
+        url,
+        status: status.map(|s| s.as_u16()),
+        bytes,
+        latency_ms: timer.elapsed().as_micros() as f64 / 1000.0,
+        error,
     }
-
-    let text = resp.text().await.map_err(|e| {
-        AnyError::builder()
-            .message(format!("text: {}", e.to_string()))
-            .build()
-    })?;
-    Ok(text)
 }
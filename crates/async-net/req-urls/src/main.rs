@@ -1,8 +1,113 @@
-use anyverr::AnyResult;
-use req_urls::Config;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+use anyverr::{AnyError, AnyResult};
+use req_urls::{Config, ExportFormat, InputFormat, export, parse_urls};
+
+const USAGE: &str = "Usage: req-urls [-c|--config=file.json] [-i|--input=file|-] \
+                     [-f|--format=lines|json|csv] [-n|--concurrency=N] [-t|--timeout=ms] \
+                     [-o|--output=file] [-e|--export=jsonl|csv]
+Without --config or --input the URL list is read from stdin, one per line.
+Results go to stdout as JSON lines unless --output or --export say otherwise;
+the exit code is 1 when any request failed.";
+
+#[derive(Debug, Default)]
+struct Args {
+    config: Option<PathBuf>,
+    /// `-` 表示标准输入
+    input: Option<PathBuf>,
+    format: Option<InputFormat>,
+    concurrency: Option<usize>,
+    timeout: Option<u64>,
+    output: Option<PathBuf>,
+    export: Option<ExportFormat>,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+    let mut args = Args::default();
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('c') | Long("config") => args.config = Some(parser.value()?.parse()?),
+            Short('i') | Long("input") => args.input = Some(parser.value()?.parse()?),
+            Short('f') | Long("format") => args.format = Some(parser.value()?.parse()?),
+            Short('n') | Long("concurrency") => args.concurrency = Some(parser.value()?.parse()?),
+            Short('t') | Long("timeout") => args.timeout = Some(parser.value()?.parse()?),
+            Short('o') | Long("output") => args.output = Some(parser.value()?.parse()?),
+            Short('e') | Long("export") => args.export = Some(parser.value()?.parse()?),
+            Long("help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(args)
+}
+
+/// 读取 `--input` 指定的 URL 列表，没有指定文件时读标准输入
+fn read_input(args: &Args) -> AnyResult<Vec<String>> {
+    let (text, format) = match &args.input {
+        Some(path) if path.as_os_str() != "-" => (
+            fs::read_to_string(path).map_err(AnyError::wrap)?,
+            args.format.unwrap_or_else(|| InputFormat::from_path(path)),
+        ),
+        _ => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .map_err(AnyError::wrap)?;
+            (text, args.format.unwrap_or(InputFormat::Lines))
+        }
+    };
+    parse_urls(&text, format)
+}
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let c = Config::load("./test/req_urls.json")?;
-    req_urls::run(c).await
+    let args = parse_args().map_err(AnyError::wrap)?;
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if args.input.is_some() || args.config.is_none() {
+        config.urls.extend(read_input(&args)?);
+    }
+    if let Some(con) = args.concurrency {
+        config.con = con;
+    }
+    if let Some(timeout) = args.timeout {
+        config.timeout = timeout;
+    }
+
+    let timer = Instant::now();
+    let results = req_urls::run(config).await?;
+
+    let format = args.export.unwrap_or_else(|| match &args.output {
+        Some(path) => ExportFormat::from_path(path),
+        None => ExportFormat::JsonLines,
+    });
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(AnyError::wrap)?),
+        None => Box::new(io::stdout()),
+    });
+    export(&mut out, format, &results)?;
+
+    // 统计写到 stderr，stdout 只有结果
+    let failed = results.iter().filter(|r| !r.is_ok()).count();
+    eprintln!(
+        "{} ok, {} failed, elapsed: {}ms",
+        results.len() - failed,
+        failed,
+        timer.elapsed().as_millis()
+    );
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
 }
@@ -0,0 +1,186 @@
+use anyverr::{AnyError, AnyResult};
+use serde::{Deserialize, Serialize};
+
+const LEN_PREFIX: usize = 4;
+
+/// How the byte stream of a connection is split into messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// Echo whatever a single read returns.
+    #[default]
+    Raw,
+    /// Frames end with `\n`; the newline is echoed back with the frame.
+    Line,
+    /// Frames start with a big-endian `u32` holding the payload length.
+    LengthPrefixed,
+}
+
+/// Splits buffered bytes into frames and encodes frames back onto the wire.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    framing: Framing,
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            framing,
+            max_frame_size,
+        }
+    }
+
+    /// Takes the next complete frame payload out of `buf`, if there is one.
+    pub fn decode(&self, buf: &mut Vec<u8>) -> AnyResult<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::Raw => {
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(std::mem::take(buf)))
+            }
+            Framing::Line => match buf.iter().position(|b| *b == b'\n') {
+                Some(pos) => {
+                    if pos > self.max_frame_size {
+                        return Err(self.oversized(pos));
+                    }
+                    let mut frame = buf.drain(..=pos).collect::<Vec<_>>();
+                    frame.pop();
+                    Ok(Some(frame))
+                }
+                None if buf.len() > self.max_frame_size => Err(self.oversized(buf.len())),
+                None => Ok(None),
+            },
+            Framing::LengthPrefixed => {
+                if buf.len() < LEN_PREFIX {
+                    return Ok(None);
+                }
+                let mut prefix = [0u8; LEN_PREFIX];
+                prefix.copy_from_slice(&buf[..LEN_PREFIX]);
+                let len = u32::from_be_bytes(prefix) as usize;
+                if len > self.max_frame_size {
+                    return Err(self.oversized(len));
+                }
+                if buf.len() < LEN_PREFIX + len {
+                    return Ok(None);
+                }
+                let frame = buf[LEN_PREFIX..LEN_PREFIX + len].to_vec();
+                buf.drain(..LEN_PREFIX + len);
+                Ok(Some(frame))
+            }
+        }
+    }
+
+    /// Appends `frame` to `out` in wire format.
+    pub fn encode(&self, frame: &[u8], out: &mut Vec<u8>) {
+        match self.framing {
+            Framing::Raw => out.extend_from_slice(frame),
+            Framing::Line => {
+                out.extend_from_slice(frame);
+                out.push(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                out.extend_from_slice(frame);
+            }
+        }
+    }
+
+    /// Appends an `ERR <reason>` message to `out`, telling the peer why it is being disconnected.
+    ///
+    /// Framed modes send it as a regular frame; raw mode ends it with a newline.
+    pub fn encode_error(&self, reason: &str, out: &mut Vec<u8>) {
+        let msg = format!("ERR {}", reason);
+        match self.framing {
+            Framing::Raw => {
+                out.extend_from_slice(msg.as_bytes());
+                out.push(b'\n');
+            }
+            _ => self.encode(msg.as_bytes(), out),
+        }
+    }
+
+    /// Checks what is left in `buf` once the peer has closed its side.
+    pub fn finish(&self, buf: &[u8]) -> AnyResult<()> {
+        if buf.is_empty() || self.framing == Framing::Raw {
+            return Ok(());
+        }
+        Err(AnyError::quick(
+            format!(
+                "malformed frame: closed with {} byte(s) of an incomplete {:?} frame",
+                buf.len(),
+                self.framing
+            ),
+            anyverr::ErrKind::ValueValidation,
+        ))
+    }
+
+    fn oversized(&self, len: usize) -> AnyError {
+        AnyError::quick(
+            format!(
+                "frame too large: {} byte(s) (max {})",
+                len, self.max_frame_size
+            ),
+            anyverr::ErrKind::RuleViolation,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_frames() -> AnyResult<()> {
+        let codec = FrameCodec::new(Framing::Line, 16);
+        let mut buf = b"hello\nwor".to_vec();
+        assert_eq!(codec.decode(&mut buf)?, Some(b"hello".to_vec()));
+        assert_eq!(codec.decode(&mut buf)?, None);
+        buf.extend_from_slice(b"ld\n");
+        assert_eq!(codec.decode(&mut buf)?, Some(b"world".to_vec()));
+        assert!(buf.is_empty());
+
+        let mut out = vec![];
+        codec.encode(b"world", &mut out);
+        assert_eq!(out, b"world\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_prefixed_frames() -> AnyResult<()> {
+        let codec = FrameCodec::new(Framing::LengthPrefixed, 16);
+        let mut buf = vec![];
+        codec.encode(b"abc", &mut buf);
+        codec.encode(b"", &mut buf);
+        buf.extend_from_slice(&[0, 0]);
+        assert_eq!(codec.decode(&mut buf)?, Some(b"abc".to_vec()));
+        assert_eq!(codec.decode(&mut buf)?, Some(vec![]));
+        assert_eq!(codec.decode(&mut buf)?, None);
+        assert!(codec.finish(&buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_frames() {
+        let line = FrameCodec::new(Framing::Line, 4);
+        assert!(line.decode(&mut b"12345".to_vec()).is_err());
+        assert!(line.decode(&mut b"12345\n".to_vec()).is_err());
+
+        let prefixed = FrameCodec::new(Framing::LengthPrefixed, 4);
+        assert!(prefixed.decode(&mut vec![0, 0, 0, 5]).is_err());
+    }
+
+    #[test]
+    fn test_error_frames() -> AnyResult<()> {
+        let mut out = vec![];
+        FrameCodec::new(Framing::Line, 4).encode_error("frame too large", &mut out);
+        assert_eq!(out, b"ERR frame too large\n");
+
+        let prefixed = FrameCodec::new(Framing::LengthPrefixed, 64);
+        let mut out = vec![];
+        prefixed.encode_error("bad", &mut out);
+        assert_eq!(prefixed.decode(&mut out)?, Some(b"ERR bad".to_vec()));
+        Ok(())
+    }
+}
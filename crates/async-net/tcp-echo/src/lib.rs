@@ -1,16 +1,41 @@
-use std::{io::ErrorKind, net::SocketAddr, str::FromStr};
-
-use anyverr::{AnyError, AnyResult};
+use anyverr::AnyResult;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-};
+
+mod framing;
+mod registry;
+mod server;
+pub mod shutdown;
+mod stats;
+mod transform;
+
+pub use framing::*;
+pub use registry::*;
+pub use server::*;
+pub use shutdown::GracefulShutdown;
+pub use stats::*;
+pub use transform::*;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    ip: String,
-    port: u16,
+    pub ip: String,
+    pub port: u16,
+    /// How the stream is split into messages before they are echoed.
+    pub framing: Framing,
+    /// Largest frame payload accepted in the framed modes, in bytes.
+    pub max_frame_size: usize,
+    /// Applied in order to every frame before it is echoed. Empty means echo as-is.
+    pub transforms: Vec<TransformConfig>,
+    /// Close a connection that sends nothing for this many milliseconds. `0` disables it.
+    pub read_timeout: u64,
+    /// Close a connection whose echo cannot be written within this many milliseconds. `0` disables it.
+    pub write_timeout: u64,
+    /// How long shutdown waits for in-flight echoes after shutdown before aborting them.
+    pub drain_timeout: u64,
+    /// Serve traffic statistics as JSON on this port of `ip`. `None` disables the endpoint.
+    pub stats_port: Option<u16>,
+    /// Print a traffic summary every this many milliseconds. `0` disables it.
+    pub stats_interval: u64,
 }
 
 impl Default for Config {
@@ -18,70 +43,21 @@ impl Default for Config {
         Self {
             ip: "127.0.0.1".into(),
             port: 59411,
+            framing: Framing::Raw,
+            max_frame_size: 64 * 1024,
+            transforms: vec![],
+            read_timeout: 60_000,
+            write_timeout: 10_000,
+            drain_timeout: 5_000,
+            stats_port: None,
+            stats_interval: 30_000,
         }
     }
 }
 
+/// Runs the echo server until Ctrl-C, then drains in-flight connections.
 pub async fn run(config: Config) -> AnyResult<()> {
-    let fallback_addr = "127.0.0.1:0".parse().unwrap();
-    let socket_addr_str = format!("{}:{}", config.ip, config.port);
-    let socket_addr = SocketAddr::from_str(&socket_addr_str).unwrap_or(fallback_addr);
-    let bind_res = TcpListener::bind(socket_addr).await;
-    println!("First bind");
-    let listener = match bind_res {
-        Ok(l) => Ok(l),
-        Err(e) => {
-            if e.kind() == ErrorKind::AddrInUse {
-                Ok(TcpListener::bind(fallback_addr).await.unwrap())
-            } else {
-                Err(AnyError::quick(
-                    format!("Failed to bind to local: {}", e),
-                    anyverr::ErrKind::ValueValidation,
-                ))
-            }
-        }
-    }?;
-
-    println!("successfully bind to:  {}", listener.local_addr().unwrap());
-    let mut conn_handles = vec![];
-    loop {
-        let (stream, addr) = listener.accept().await.map_err(|e| AnyError::wrap(e))?;
-        conn_handles.push(tokio::spawn(process_conn(stream, addr)));
-    }
-}
-
-async fn process_conn(mut stream: tokio::net::TcpStream, addr: SocketAddr) {
-    println!("client {} connected", addr);
-    let mut buf = vec![0u8; 2048];
-    let (mut rx, mut tx) = stream.split();
-    loop {
-        match rx.read(&mut buf).await {
-            Ok(0) => {
-                println!("client: {} closed when reading", addr);
-                break;
-            }
-            Ok(rn) => {
-                let mut written_len = 0;
-                while written_len < rn {
-                    match tx.write(&mut buf[written_len..rn]).await {
-                        Ok(0) => {
-                            println!("client: {} closed when writing", addr);
-                            break;
-                        }
-                        Ok(wn) => written_len += wn,
-                        Err(e) => {
-                            eprintln!("failed to write: {}", e);
-                            break;
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                eprintln!("failed to write: {}", e);
-                break;
-            }
-        }
-
-        println!("current data: {}", String::from_utf8_lossy(&buf[..]))
-    }
+    let (handle, _) = Server::bind(config).await?;
+    shutdown::termination(handle.shutdown_signal());
+    handle.wait().await
 }
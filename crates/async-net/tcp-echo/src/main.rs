@@ -3,7 +3,10 @@ use tcp_echo::Config;
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let config = Config::default();
+    let config = Config {
+        stats_port: Some(59421),
+        ..Config::default()
+    };
     tcp_echo::run(config).await?;
     Ok(())
 }
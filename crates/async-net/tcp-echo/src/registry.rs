@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::stats::{ConnStats, StatsSnapshot, TotalStats, Traffic};
+
+/// A live connection as seen by the registry.
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub connected_at: Instant,
+    pub traffic: Arc<Traffic>,
+}
+
+/// Tracks live connections. Entries are removed when their [`ConnEntry`] drops,
+/// so the registry only ever holds connections that are still being served.
+#[derive(Debug, Clone, Default)]
+pub struct ConnRegistry {
+    inner: Arc<ConnRegistryInner>,
+}
+
+#[derive(Debug)]
+struct ConnRegistryInner {
+    started_at: Instant,
+    next_id: AtomicU64,
+    peak: AtomicU64,
+    closed: AtomicU64,
+    closed_duration_ms: AtomicU64,
+    totals: Traffic,
+    conns: Mutex<HashMap<u64, ConnInfo>>,
+}
+
+impl Default for ConnRegistryInner {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            next_id: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            closed_duration_ms: AtomicU64::new(0),
+            totals: Traffic::default(),
+            conns: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Keeps a connection registered for as long as it is alive.
+#[derive(Debug)]
+pub struct ConnEntry {
+    id: u64,
+    connected_at: Instant,
+    traffic: Arc<Traffic>,
+    inner: Arc<ConnRegistryInner>,
+}
+
+impl ConnEntry {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn record_read(&self, n: usize) {
+        self.traffic.add_in(n);
+        self.inner.totals.add_in(n);
+    }
+
+    pub fn record_write(&self, n: usize) {
+        self.traffic.add_out(n);
+        self.inner.totals.add_out(n);
+    }
+
+    pub fn record_frame(&self) {
+        self.traffic.add_frame();
+        self.inner.totals.add_frame();
+    }
+}
+
+impl Drop for ConnEntry {
+    fn drop(&mut self) {
+        self.inner.conns.lock().unwrap().remove(&self.id);
+        let lived = self.connected_at.elapsed().as_millis() as u64;
+        self.inner
+            .closed_duration_ms
+            .fetch_add(lived, Ordering::Relaxed);
+        self.inner.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnRegistry {
+    pub fn register(&self, peer: SocketAddr) -> ConnEntry {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ConnInfo {
+            id,
+            peer,
+            connected_at: Instant::now(),
+            traffic: Arc::default(),
+        };
+        let entry = ConnEntry {
+            id,
+            connected_at: info.connected_at,
+            traffic: info.traffic.clone(),
+            inner: self.inner.clone(),
+        };
+        let mut conns = self.inner.conns.lock().unwrap();
+        conns.insert(id, info);
+        self.inner
+            .peak
+            .fetch_max(conns.len() as u64, Ordering::Relaxed);
+        entry
+    }
+
+    /// Connections registered since the registry was created.
+    pub fn accepted(&self) -> u64 {
+        self.inner.next_id.load(Ordering::Relaxed)
+    }
+
+    /// The most connections that were ever live at the same time.
+    pub fn peak(&self) -> u64 {
+        self.inner.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.conns.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnInfo> {
+        let mut conns = self
+            .inner
+            .conns
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        conns.sort_by_key(|c| c.id);
+        conns
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        let conns = self.snapshot();
+        let closed = self.inner.closed.load(Ordering::Relaxed);
+        let closed_duration_ms = self.inner.closed_duration_ms.load(Ordering::Relaxed);
+        let totals = TotalStats {
+            accepted: self.accepted(),
+            active: conns.len(),
+            peak_concurrency: self.peak(),
+            closed,
+            avg_duration_ms: closed_duration_ms.checked_div(closed).unwrap_or(0),
+            bytes_in: self.inner.totals.bytes_in(),
+            bytes_out: self.inner.totals.bytes_out(),
+            frames: self.inner.totals.frames(),
+        };
+        StatsSnapshot {
+            uptime_ms: self.inner.started_at.elapsed().as_millis() as u64,
+            totals,
+            connections: conns.iter().map(ConnStats::from).collect(),
+        }
+    }
+}
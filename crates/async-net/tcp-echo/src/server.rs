@@ -0,0 +1,431 @@
+use std::{io::ErrorKind, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use anyverr::{AnyError, AnyResult};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, tcp::WriteHalf},
+    task::{JoinHandle, JoinSet},
+    time,
+};
+
+use crate::{
+    Config, ConnEntry, ConnInfo, ConnRegistry, FrameCodec, GracefulShutdown, Pipeline,
+    StatsSnapshot, Transform, stats,
+};
+
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+#[derive(Debug, Clone)]
+struct ConnOptions {
+    codec: FrameCodec,
+    transform: Arc<dyn Transform>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+/// An echo server running in the background.
+pub struct Server;
+
+impl Server {
+    /// Binds the listener and starts accepting connections.
+    ///
+    /// Falls back to an ephemeral port on `127.0.0.1` when the configured address is in use,
+    /// so the returned address is the one to connect to.
+    pub async fn bind(config: Config) -> AnyResult<(ServerHandle, SocketAddr)> {
+        let transform = Arc::new(Pipeline::from_config(&config.transforms)?);
+        Self::bind_with(config, transform).await
+    }
+
+    /// Like [`Self::bind`], but echoes through `transform` instead of `config.transforms`.
+    pub async fn bind_with(
+        config: Config,
+        transform: Arc<dyn Transform>,
+    ) -> AnyResult<(ServerHandle, SocketAddr)> {
+        let listener = bind_listener(&config).await?;
+        let local_addr = listener.local_addr().map_err(AnyError::wrap)?;
+        println!("successfully bind to:  {}", local_addr);
+        let (stats_listener, stats_addr) = match config.stats_port {
+            Some(port) => {
+                let addr = format!("{}:{}", config.ip, port);
+                let l = TcpListener::bind(&addr).await.map_err(|e| {
+                    AnyError::quick(
+                        format!("Failed to bind stats endpoint {}: {}", addr, e),
+                        anyverr::ErrKind::ValueValidation,
+                    )
+                })?;
+                let stats_addr = l.local_addr().map_err(AnyError::wrap)?;
+                println!("stats served on: {}", stats_addr);
+                (Some(l), Some(stats_addr))
+            }
+            None => (None, None),
+        };
+
+        let shutdown = GracefulShutdown::new();
+        let registry = ConnRegistry::default();
+        let task = tokio::spawn(accept_loop(
+            listener,
+            stats_listener,
+            config,
+            transform,
+            shutdown.clone(),
+            registry.clone(),
+        ));
+        let handle = ServerHandle {
+            local_addr,
+            stats_addr,
+            shutdown,
+            registry,
+            task,
+        };
+        Ok((handle, local_addr))
+    }
+}
+
+/// Controls a server started with [`Server::bind`].
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    stats_addr: Option<SocketAddr>,
+    shutdown: GracefulShutdown,
+    registry: ConnRegistry,
+    task: JoinHandle<AnyResult<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Where the JSON stats endpoint listens, if it is enabled.
+    pub fn stats_addr(&self) -> Option<SocketAddr> {
+        self.stats_addr
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.registry.stats()
+    }
+
+    /// Connections currently being served.
+    pub fn active_connections(&self) -> usize {
+        self.registry.len()
+    }
+
+    /// Connections accepted since the server started.
+    pub fn total_connections(&self) -> u64 {
+        self.registry.accepted()
+    }
+
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        self.registry.snapshot()
+    }
+
+    /// The signal that stops the server; initiating it has the same effect as [`Self::shutdown`].
+    pub fn shutdown_signal(&self) -> GracefulShutdown {
+        self.shutdown.clone()
+    }
+
+    /// Stops accepting, drains in-flight echoes and waits for the server to finish.
+    pub async fn shutdown(self) -> AnyResult<()> {
+        self.shutdown.initiate();
+        self.wait().await
+    }
+
+    /// Waits for the server to finish without asking it to stop.
+    pub async fn wait(self) -> AnyResult<()> {
+        self.task.await.map_err(AnyError::wrap)?
+    }
+}
+
+async fn bind_listener(config: &Config) -> AnyResult<TcpListener> {
+    let fallback_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let socket_addr_str = format!("{}:{}", config.ip, config.port);
+    let socket_addr = SocketAddr::from_str(&socket_addr_str).unwrap_or(fallback_addr);
+    match TcpListener::bind(socket_addr).await {
+        Ok(l) => Ok(l),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            println!(
+                "{} is in use, falling back to {}",
+                socket_addr, fallback_addr
+            );
+            TcpListener::bind(fallback_addr)
+                .await
+                .map_err(AnyError::wrap)
+        }
+        Err(e) => Err(AnyError::quick(
+            format!("Failed to bind to local: {}", e),
+            anyverr::ErrKind::ValueValidation,
+        )),
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    stats_listener: Option<TcpListener>,
+    config: Config,
+    transform: Arc<dyn Transform>,
+    shutdown: GracefulShutdown,
+    registry: ConnRegistry,
+) -> AnyResult<()> {
+    let opts = ConnOptions {
+        codec: FrameCodec::new(config.framing, config.max_frame_size),
+        transform,
+        read_timeout: millis(config.read_timeout),
+        write_timeout: millis(config.write_timeout),
+    };
+    let mut aux = JoinSet::new();
+    if let Some(l) = stats_listener {
+        aux.spawn(stats::serve(l, registry.clone(), shutdown.clone()));
+    }
+    if let Some(every) = millis(config.stats_interval) {
+        aux.spawn(stats::report(registry.clone(), shutdown.clone(), every));
+    }
+
+    let mut conns = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_shutting_down() => break,
+            // reap finished connections so the set does not grow forever
+            Some(_) = conns.join_next(), if !conns.is_empty() => {}
+            accepted = listener.accept() => {
+                let (stream, addr) = match accepted {
+                    Ok(t) => t,
+                    Err(e) => {
+                        eprintln!("failed to accept: {}", e);
+                        continue;
+                    }
+                };
+                let entry = registry.register(addr);
+                let inflight = shutdown.inflight_guard();
+                let shutdown = shutdown.clone();
+                let opts = opts.clone();
+                conns.spawn(async move {
+                    let _inflight = inflight;
+                    if let Err(e) = process_conn(stream, addr, &entry, opts, shutdown).await {
+                        eprintln!("client: {} rejected: {}", addr, e);
+                    }
+                });
+            }
+        }
+    }
+    drop(listener);
+
+    println!("draining {} connection(s)", registry.len());
+    let drained = match millis(config.drain_timeout) {
+        Some(d) => time::timeout(d, shutdown.wait_inflight_zero())
+            .await
+            .is_ok(),
+        None => {
+            shutdown.wait_inflight_zero().await;
+            true
+        }
+    };
+    if !drained {
+        eprintln!("drain timed out, aborting {} connection(s)", registry.len());
+        conns.abort_all();
+    }
+    while conns.join_next().await.is_some() {}
+    while aux.join_next().await.is_some() {}
+    let t = registry.stats().totals;
+    println!(
+        "echo server stopped: accepted={} peak={} in={}B out={}B frames={}",
+        t.accepted, t.peak_concurrency, t.bytes_in, t.bytes_out, t.frames
+    );
+    Ok(())
+}
+
+async fn process_conn(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    entry: &ConnEntry,
+    opts: ConnOptions,
+    shutdown: GracefulShutdown,
+) -> AnyResult<()> {
+    println!("client {} connected", addr);
+    let mut buf = vec![0u8; 2048];
+    let mut pending = Vec::new();
+    let mut out = Vec::new();
+    let (mut rx, mut tx) = stream.split();
+    loop {
+        let read = tokio::select! {
+            _ = shutdown.wait_shutting_down() => {
+                println!("client: {} closed by shutdown", addr);
+                return Ok(());
+            }
+            read = with_timeout(opts.read_timeout, rx.read(&mut buf)) => read,
+        };
+        let rn = match read {
+            None => {
+                println!("client: {} idle for too long, closing", addr);
+                return Ok(());
+            }
+            Some(Ok(0)) => {
+                println!("client: {} closed when reading", addr);
+                return match opts.codec.finish(&pending) {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(reject(&mut tx, vec![], e, addr, entry, &opts).await),
+                };
+            }
+            Some(Ok(rn)) => rn,
+            Some(Err(e)) => {
+                eprintln!("failed to read: {}", e);
+                return Ok(());
+            }
+        };
+        entry.record_read(rn);
+        pending.extend_from_slice(&buf[..rn]);
+
+        out.clear();
+        loop {
+            let frame = match opts.codec.decode(&mut pending) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => return Err(reject(&mut tx, out, e, addr, entry, &opts).await),
+            };
+            entry.record_frame();
+            let echo = opts.transform.apply(frame);
+            if !echo.delay.is_zero() {
+                // earlier frames must not wait for this frame's delay
+                if !send(&mut tx, &out, addr, entry, &opts).await {
+                    return Ok(());
+                }
+                out.clear();
+                time::sleep(echo.delay).await;
+            }
+            if let Some(frame) = echo.frame {
+                opts.codec.encode(&frame, &mut out);
+            }
+        }
+        if !send(&mut tx, &out, addr, entry, &opts).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends the echoes already in `out` followed by an `ERR` message for `err`, which is handed back
+/// so the caller can close the connection with it.
+async fn reject(
+    tx: &mut WriteHalf<'_>,
+    mut out: Vec<u8>,
+    err: AnyError,
+    addr: SocketAddr,
+    entry: &ConnEntry,
+    opts: &ConnOptions,
+) -> AnyError {
+    opts.codec.encode_error(&err.message(), &mut out);
+    if send(tx, &out, addr, entry, opts).await {
+        let _ = tx.shutdown().await;
+    }
+    err
+}
+
+/// Writes `out` to the client, returning `false` once the connection should be closed.
+async fn send(
+    tx: &mut WriteHalf<'_>,
+    out: &[u8],
+    addr: SocketAddr,
+    entry: &ConnEntry,
+    opts: &ConnOptions,
+) -> bool {
+    if out.is_empty() {
+        return true;
+    }
+    match with_timeout(opts.write_timeout, tx.write_all(out)).await {
+        None => {
+            println!("client: {} stalled when writing, closing", addr);
+            false
+        }
+        Some(Err(e)) => {
+            eprintln!("failed to write: {}", e);
+            false
+        }
+        Some(Ok(())) => {
+            entry.record_write(out.len());
+            true
+        }
+    }
+}
+
+/// Awaits `fut`, returning `None` if it does not finish within `limit`.
+async fn with_timeout<F: Future>(limit: Option<Duration>, fut: F) -> Option<F::Output> {
+    match limit {
+        Some(d) => time::timeout(d, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Framing;
+
+    #[tokio::test]
+    async fn test_bind_echo_and_shutdown() -> AnyResult<()> {
+        let config = Config {
+            port: 0,
+            framing: Framing::Line,
+            ..Config::default()
+        };
+        let (handle, addr) = Server::bind(config).await?;
+        assert_eq!(handle.local_addr(), addr);
+
+        let mut client = TcpStream::connect(addr).await.map_err(AnyError::wrap)?;
+        client
+            .write_all(b"ping\npo")
+            .await
+            .map_err(AnyError::wrap)?;
+        client.write_all(b"ng\n").await.map_err(AnyError::wrap)?;
+        let mut buf = [0u8; 10];
+        client.read_exact(&mut buf).await.map_err(AnyError::wrap)?;
+        assert_eq!(&buf, b"ping\npong\n");
+        assert_eq!(handle.active_connections(), 1);
+        assert_eq!(handle.total_connections(), 1);
+        let stats = handle.stats();
+        assert_eq!(stats.totals.bytes_in, 10);
+        assert_eq!(stats.totals.frames, 2);
+        assert_eq!(stats.connections[0].frames, 2);
+
+        handle.shutdown().await?;
+        let n = client.read(&mut buf).await.map_err(AnyError::wrap)?;
+        assert_eq!(n, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_with_error() -> AnyResult<()> {
+        let config = Config {
+            port: 0,
+            framing: Framing::Line,
+            max_frame_size: 4,
+            ..Config::default()
+        };
+        let (handle, addr) = Server::bind(config).await?;
+
+        // the frame before the oversized one is still echoed
+        let mut client = TcpStream::connect(addr).await.map_err(AnyError::wrap)?;
+        client
+            .write_all(b"ok\n123456\n")
+            .await
+            .map_err(AnyError::wrap)?;
+        let mut reply = String::new();
+        client
+            .read_to_string(&mut reply)
+            .await
+            .map_err(AnyError::wrap)?;
+        assert_eq!(reply, "ok\nERR frame too large: 6 byte(s) (max 4)\n");
+
+        let mut client = TcpStream::connect(addr).await.map_err(AnyError::wrap)?;
+        client.write_all(b"abc").await.map_err(AnyError::wrap)?;
+        client.shutdown().await.map_err(AnyError::wrap)?;
+        let mut reply = String::new();
+        client
+            .read_to_string(&mut reply)
+            .await
+            .map_err(AnyError::wrap)?;
+        assert!(reply.starts_with("ERR malformed frame: "), "{}", reply);
+        assert!(reply.ends_with('\n'));
+
+        handle.shutdown().await
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use tokio::sync::Notify;
+
+/// Initiates `shutdown_for_signal` when Ctrl-C is received.
+pub(crate) fn termination(shutdown_for_signal: GracefulShutdown) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Shutdown requested (Ctrl+C). Waiting for in-flight echoes...");
+            shutdown_for_signal.initiate();
+        }
+    });
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GracefulShutdown {
+    inner: Arc<GracefulShutdownInner>,
+}
+
+#[derive(Debug, Default)]
+struct GracefulShutdownInner {
+    shutting_down: AtomicBool,
+    inflight: AtomicU64,
+    notify: Notify,
+}
+
+#[derive(Debug)]
+pub struct InflightGuard {
+    inner: Arc<GracefulShutdownInner>,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        if self.inner.inflight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // last in-flight connection finished
+            self.inner.notify.notify_waiters();
+        }
+    }
+}
+
+impl GracefulShutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn initiate(&self) {
+        if self.inner.shutting_down.swap(true, Ordering::Release) {
+            return;
+        }
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.load(Ordering::Acquire)
+    }
+
+    pub async fn wait_shutting_down(&self) {
+        loop {
+            // register before checking so a concurrent `initiate` is never missed
+            let notified = self.inner.notify.notified();
+            if self.is_shutting_down() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn inflight_guard(&self) -> InflightGuard {
+        self.inner.inflight.fetch_add(1, Ordering::Relaxed);
+        InflightGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn inflight(&self) -> u64 {
+        self.inner.inflight.load(Ordering::Acquire)
+    }
+
+    pub async fn wait_inflight_zero(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.inflight() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::{ConnInfo, ConnRegistry, GracefulShutdown};
+
+/// Byte and frame counters of one connection, or of the whole server.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    frames: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_frame(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_ms: u64,
+    pub totals: TotalStats,
+    pub connections: Vec<ConnStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TotalStats {
+    pub accepted: u64,
+    pub active: usize,
+    pub peak_concurrency: u64,
+    pub closed: u64,
+    /// Average lifetime of closed connections.
+    pub avg_duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnStats {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub duration_ms: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub frames: u64,
+}
+
+impl From<&ConnInfo> for ConnStats {
+    fn from(info: &ConnInfo) -> Self {
+        Self {
+            id: info.id,
+            peer: info.peer,
+            duration_ms: info.connected_at.elapsed().as_millis() as u64,
+            bytes_in: info.traffic.bytes_in(),
+            bytes_out: info.traffic.bytes_out(),
+            frames: info.traffic.frames(),
+        }
+    }
+}
+
+/// Answers every connection on `listener` with the current stats as a JSON HTTP response.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    registry: ConnRegistry,
+    shutdown: GracefulShutdown,
+) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown.wait_shutting_down() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("stats: failed to accept: {}", e);
+                    continue;
+                }
+            },
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &registry).await {
+                eprintln!("stats: failed to answer {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, registry: &ConnRegistry) -> std::io::Result<()> {
+    // The request itself is irrelevant, but read it so clients do not see a reset.
+    let mut buf = [0u8; 1024];
+    let _ = time::timeout(Duration::from_millis(200), stream.read(&mut buf)).await;
+
+    let body = serde_json::to_string_pretty(&registry.stats())?;
+    let resp = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Prints a one-line summary every `every` until shutdown.
+pub(crate) async fn report(registry: ConnRegistry, shutdown: GracefulShutdown, every: Duration) {
+    let mut ticker = time::interval(every);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = shutdown.wait_shutting_down() => break,
+            _ = ticker.tick() => {
+                let t = registry.stats().totals;
+                println!(
+                    "stats: active={} peak={} accepted={} in={}B out={}B frames={} avg_duration={}ms",
+                    t.active,
+                    t.peak_concurrency,
+                    t.accepted,
+                    t.bytes_in,
+                    t.bytes_out,
+                    t.frames,
+                    t.avg_duration_ms
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::{fmt::Debug, time::Duration};
+
+use anyverr::{AnyError, AnyResult};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// What to send back for one received frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Echo {
+    /// Wait this long before sending `frame`.
+    pub delay: Duration,
+    /// The frame to send back; `None` drops it.
+    pub frame: Option<Vec<u8>>,
+}
+
+impl Echo {
+    pub fn now(frame: Vec<u8>) -> Self {
+        Self {
+            delay: Duration::ZERO,
+            frame: Some(frame),
+        }
+    }
+}
+
+/// Decides how a received frame is echoed back.
+pub trait Transform: Debug + Send + Sync {
+    fn apply(&self, frame: Vec<u8>) -> Echo;
+}
+
+/// The built-in transforms, as they appear in [`crate::Config`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TransformConfig {
+    Identity,
+    Uppercase,
+    Reverse,
+    HexDump,
+    /// Holds each frame for `ms`, plus up to `jitter_ms` chosen at random.
+    Delay {
+        ms: u64,
+        #[serde(default)]
+        jitter_ms: u64,
+    },
+    /// Drops a frame with the given probability in `0.0..=1.0`.
+    Drop {
+        probability: f64,
+    },
+    /// Flips one random bit of a frame with the given probability in `0.0..=1.0`.
+    Corrupt {
+        probability: f64,
+    },
+}
+
+impl TransformConfig {
+    /// Fails when a probability is not a number in `0.0..=1.0`.
+    pub fn build(&self) -> AnyResult<Box<dyn Transform>> {
+        let transform: Box<dyn Transform> = match self {
+            TransformConfig::Identity => Box::new(Identity),
+            TransformConfig::Uppercase => Box::new(Uppercase),
+            TransformConfig::Reverse => Box::new(Reverse),
+            TransformConfig::HexDump => Box::new(HexDump),
+            TransformConfig::Delay { ms, jitter_ms } => Box::new(Delay {
+                base: Duration::from_millis(*ms),
+                jitter: Duration::from_millis(*jitter_ms),
+            }),
+            TransformConfig::Drop { probability } => Box::new(RandomDrop {
+                probability: checked_probability("drop", *probability)?,
+            }),
+            TransformConfig::Corrupt { probability } => Box::new(Corrupt {
+                probability: checked_probability("corrupt", *probability)?,
+            }),
+        };
+        Ok(transform)
+    }
+}
+
+fn checked_probability(kind: &str, probability: f64) -> AnyResult<f64> {
+    if (0.0..=1.0).contains(&probability) {
+        return Ok(probability);
+    }
+    Err(AnyError::quick(
+        format!(
+            "{} probability must be within 0.0..=1.0, got {}",
+            kind, probability
+        ),
+        anyverr::ErrKind::ValueValidation,
+    ))
+}
+
+/// Runs transforms in order, adding up their delays and stopping at the first drop.
+#[derive(Debug, Default)]
+pub struct Pipeline(Vec<Box<dyn Transform>>);
+
+impl Pipeline {
+    pub fn new(transforms: Vec<Box<dyn Transform>>) -> Self {
+        Self(transforms)
+    }
+
+    pub fn from_config(configs: &[TransformConfig]) -> AnyResult<Self> {
+        configs
+            .iter()
+            .map(TransformConfig::build)
+            .collect::<AnyResult<_>>()
+            .map(Self)
+    }
+}
+
+impl Transform for Pipeline {
+    fn apply(&self, frame: Vec<u8>) -> Echo {
+        let mut echo = Echo::now(frame);
+        for t in &self.0 {
+            let Some(frame) = echo.frame.take() else {
+                break;
+            };
+            let next = t.apply(frame);
+            echo.delay += next.delay;
+            echo.frame = next.frame;
+        }
+        echo
+    }
+}
+
+#[derive(Debug)]
+pub struct Identity;
+
+impl Transform for Identity {
+    fn apply(&self, frame: Vec<u8>) -> Echo {
+        Echo::now(frame)
+    }
+}
+
+#[derive(Debug)]
+pub struct Uppercase;
+
+impl Transform for Uppercase {
+    fn apply(&self, mut frame: Vec<u8>) -> Echo {
+        frame.make_ascii_uppercase();
+        Echo::now(frame)
+    }
+}
+
+/// Reverses characters when the frame is UTF-8, bytes otherwise.
+#[derive(Debug)]
+pub struct Reverse;
+
+impl Transform for Reverse {
+    fn apply(&self, mut frame: Vec<u8>) -> Echo {
+        match std::str::from_utf8(&frame) {
+            Ok(s) => Echo::now(s.chars().rev().collect::<String>().into_bytes()),
+            Err(_) => {
+                frame.reverse();
+                Echo::now(frame)
+            }
+        }
+    }
+}
+
+/// Replaces the frame with its bytes in hex followed by the printable ones, like a `hexdump -C`
+/// row, all on one line so the reply stays a single frame under line framing.
+#[derive(Debug)]
+pub struct HexDump;
+
+impl Transform for HexDump {
+    fn apply(&self, frame: Vec<u8>) -> Echo {
+        let hex = frame
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = frame
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        Echo::now(format!("{}  |{}|", hex, ascii).into_bytes())
+    }
+}
+
+#[derive(Debug)]
+pub struct Delay {
+    pub base: Duration,
+    pub jitter: Duration,
+}
+
+impl Transform for Delay {
+    fn apply(&self, frame: Vec<u8>) -> Echo {
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::rng().random_range(Duration::ZERO..=self.jitter)
+        };
+        Echo {
+            delay: self.base + jitter,
+            frame: Some(frame),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RandomDrop {
+    pub probability: f64,
+}
+
+impl Transform for RandomDrop {
+    fn apply(&self, frame: Vec<u8>) -> Echo {
+        if rand::rng().random_bool(self.probability) {
+            return Echo {
+                delay: Duration::ZERO,
+                frame: None,
+            };
+        }
+        Echo::now(frame)
+    }
+}
+
+#[derive(Debug)]
+pub struct Corrupt {
+    pub probability: f64,
+}
+
+impl Transform for Corrupt {
+    fn apply(&self, mut frame: Vec<u8>) -> Echo {
+        let mut rng = rand::rng();
+        if !frame.is_empty() && rng.random_bool(self.probability) {
+            let idx = rng.random_range(0..frame.len());
+            frame[idx] ^= 1 << rng.random_range(0..8);
+        }
+        Echo::now(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_transforms() {
+        assert_eq!(
+            Uppercase.apply(b"abc1".to_vec()),
+            Echo::now(b"ABC1".to_vec())
+        );
+        assert_eq!(
+            Reverse.apply("héllo".as_bytes().to_vec()),
+            Echo::now("olléh".as_bytes().to_vec())
+        );
+        let dump = HexDump.apply(b"hi\n".to_vec()).frame.unwrap();
+        assert_eq!(dump, b"68 69 0a  |hi.|");
+        let dump = HexDump.apply(vec![b'\n'; 40]).frame.unwrap();
+        assert!(!dump.contains(&b'\n'));
+
+        let corrupted = Corrupt { probability: 1.0 }.apply(b"abc".to_vec());
+        assert_ne!(corrupted.frame.unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_pipeline() -> AnyResult<()> {
+        let pipeline = Pipeline::from_config(&[
+            TransformConfig::Uppercase,
+            TransformConfig::Delay {
+                ms: 20,
+                jitter_ms: 0,
+            },
+            TransformConfig::Delay {
+                ms: 5,
+                jitter_ms: 0,
+            },
+        ])?;
+        let echo = pipeline.apply(b"ping".to_vec());
+        assert_eq!(echo.delay, Duration::from_millis(25));
+        assert_eq!(echo.frame.unwrap(), b"PING");
+
+        let dropping = Pipeline::from_config(&[
+            TransformConfig::Drop { probability: 1.0 },
+            TransformConfig::Uppercase,
+        ])?;
+        assert_eq!(dropping.apply(b"ping".to_vec()).frame, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_probability() {
+        for probability in [f64::NAN, f64::INFINITY, -0.1, 1.5] {
+            assert!(TransformConfig::Drop { probability }.build().is_err());
+            assert!(TransformConfig::Corrupt { probability }.build().is_err());
+        }
+        assert!(Pipeline::from_config(&[TransformConfig::Drop { probability: 0.0 }]).is_ok());
+    }
+}
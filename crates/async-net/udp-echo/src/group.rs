@@ -0,0 +1,195 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyverr::{AnyError, AnyResult};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::UdpSocket;
+
+/// Group echo: every port receives group traffic and echoes it to the whole group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Group {
+    /// Join an IPv4 or IPv6 multicast group on every port.
+    ///
+    /// IPv4 groups are joined on the interface that owns `Config::ip`.
+    Multicast {
+        group: IpAddr,
+        /// Time-to-live (hop limit for IPv6) of the echoes.
+        #[serde(default = "default_ttl")]
+        ttl: u32,
+        /// Also deliver echoes to group members on this host.
+        #[serde(default = "default_loopback")]
+        loopback: bool,
+        /// Interface index for IPv6 groups; `0` lets the OS choose.
+        #[serde(default)]
+        interface_index: u32,
+    },
+    /// Accept broadcast datagrams on every port and echo them to `addr`.
+    Broadcast {
+        #[serde(default = "default_broadcast_addr")]
+        addr: Ipv4Addr,
+    },
+}
+
+fn default_ttl() -> u32 {
+    1
+}
+
+fn default_loopback() -> bool {
+    true
+}
+
+fn default_broadcast_addr() -> Ipv4Addr {
+    Ipv4Addr::BROADCAST
+}
+
+/// Where the echoes of a port in group mode go.
+#[derive(Debug, Clone)]
+pub struct GroupReply {
+    pub socket: Arc<UdpSocket>,
+    pub target: SocketAddr,
+    /// The address group members see echoes coming from, see [`Group::source_addr`].
+    pub source: SocketAddr,
+}
+
+impl GroupReply {
+    /// Whether `source` is this server's own echo coming back through the group.
+    pub fn is_own(&self, source: SocketAddr) -> bool {
+        source == self.source
+    }
+}
+
+impl Group {
+    fn is_ipv6(&self) -> bool {
+        matches!(self, Group::Multicast { group, .. } if group.is_ipv6())
+    }
+
+    fn unspecified(&self) -> IpAddr {
+        if self.is_ipv6() {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        }
+    }
+
+    /// The address echoes of `port` are sent to.
+    pub fn target(&self, port: u16) -> SocketAddr {
+        match self {
+            Group::Multicast { group, .. } => SocketAddr::new(*group, port),
+            Group::Broadcast { addr } => SocketAddr::new((*addr).into(), port),
+        }
+    }
+
+    /// Binds `port` on the wildcard address and joins the group on it.
+    ///
+    /// The socket allows address reuse, so several members can share a port on one host.
+    pub fn bind(&self, port: u16, interface: Ipv4Addr) -> AnyResult<UdpSocket> {
+        let socket =
+            reusable_socket(SocketAddr::new(self.unspecified(), port)).map_err(AnyError::wrap)?;
+        if let Group::Multicast {
+            group,
+            interface_index,
+            ..
+        } = self
+        {
+            match group {
+                IpAddr::V4(g) => socket.join_multicast_v4(*g, interface),
+                IpAddr::V6(g) => socket.join_multicast_v6(g, *interface_index),
+            }
+            .map_err(|e| {
+                AnyError::quick(
+                    format!("Failed to join multicast group {}: {}", group, e),
+                    anyverr::ErrKind::ValueValidation,
+                )
+            })?;
+        }
+        Ok(socket)
+    }
+
+    /// Creates the socket that sends echoes to the group.
+    pub fn sender(&self, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+        let socket = reusable_socket(SocketAddr::new(self.unspecified(), 0))?;
+        let sock_ref = SockRef::from(&socket);
+        match self {
+            Group::Multicast {
+                group: IpAddr::V4(_),
+                ttl,
+                loopback,
+                ..
+            } => {
+                sock_ref.set_multicast_if_v4(&interface)?;
+                socket.set_multicast_ttl_v4(*ttl)?;
+                socket.set_multicast_loop_v4(*loopback)?;
+            }
+            Group::Multicast {
+                group: IpAddr::V6(_),
+                ttl,
+                loopback,
+                interface_index,
+            } => {
+                sock_ref.set_multicast_if_v6(*interface_index)?;
+                sock_ref.set_multicast_hops_v6(*ttl)?;
+                socket.set_multicast_loop_v6(*loopback)?;
+            }
+            Group::Broadcast { .. } => socket.set_broadcast(true)?,
+        }
+        Ok(socket)
+    }
+}
+
+impl Group {
+    /// The address datagrams from `sender` to `target` arrive from.
+    ///
+    /// `sender` is bound to the wildcard address, so the source IP is the one the kernel picks
+    /// for the route to the group; a throwaway socket set up like `sender` finds it out.
+    pub fn source_addr(
+        &self,
+        sender: &UdpSocket,
+        interface: Ipv4Addr,
+        target: SocketAddr,
+    ) -> io::Result<SocketAddr> {
+        let probe = self.sender(interface)?;
+        SockRef::from(&probe).connect(&target.into())?;
+        let ip = probe.local_addr()?.ip();
+        Ok(SocketAddr::new(ip, sender.local_addr()?.port()))
+    }
+}
+
+fn reusable_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_config() {
+        let group: Group =
+            serde_json::from_str(r#"{"kind":"multicast","group":"239.255.0.1"}"#).unwrap();
+        assert_eq!(
+            group,
+            Group::Multicast {
+                group: "239.255.0.1".parse().unwrap(),
+                ttl: 1,
+                loopback: true,
+                interface_index: 0,
+            }
+        );
+        assert_eq!(group.target(5000), "239.255.0.1:5000".parse().unwrap());
+
+        let group: Group = serde_json::from_str(r#"{"kind":"broadcast"}"#).unwrap();
+        assert_eq!(group.target(5000), "255.255.255.255:5000".parse().unwrap());
+    }
+}
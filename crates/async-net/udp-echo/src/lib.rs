@@ -1,14 +1,45 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyverr::{AnyError, AnyResult};
 use serde::{Deserialize, Serialize};
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, task::JoinSet, time};
+
+mod group;
+mod limit;
+mod port;
+mod probe;
+pub mod shutdown;
+
+pub use group::*;
+pub use limit::*;
+pub use port::*;
+pub use probe::*;
+pub use shutdown::GracefulShutdown;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    ip: String,
-    port_start: u16,
-    port_end: u16,
+    pub ip: String,
+    pub port_start: u16,
+    pub port_end: u16,
+    /// How datagrams are echoed.
+    pub mode: Mode,
+    /// Join a multicast group or accept broadcasts, and echo to the group instead of the sender.
+    pub group: Option<Group>,
+    /// Datagrams per second echoed to one source address. `0` disables the limit.
+    pub per_source_pps: u32,
+    /// How many datagrams one source may send in a burst before `per_source_pps` applies.
+    /// A burst below the rate smooths it out; `1` is the strictest setting.
+    pub per_source_burst: u32,
+    /// Datagrams per second echoed across all ports and sources. `0` disables the limit.
+    pub global_pps: u32,
+    /// Log per-port counters every this many milliseconds. `0` only logs them on shutdown.
+    pub report_interval: u64,
 }
 
 impl Default for Config {
@@ -17,44 +48,337 @@ impl Default for Config {
             ip: "127.0.0.1".into(),
             port_start: 59412,
             port_end: 59415,
+            mode: Mode::Raw,
+            group: None,
+            per_source_pps: 100,
+            per_source_burst: 200,
+            global_pps: 10_000,
+            report_interval: 30_000,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Send every datagram back unchanged.
+    #[default]
+    Raw,
+    /// Datagrams are [`Probe`] objects; stamp `server_recv_at` before echoing them.
+    Json,
+}
+
+/// Echoes on every port of the configured range until Ctrl-C.
 pub async fn run(config: Config) -> AnyResult<()> {
-    let mut addrs = vec![];
+    let shutdown = shutdown::init();
+    let ports = bind(&config).await?;
+    serve(ports, &config, shutdown).await
+}
+
+/// Binds one socket per port in `port_start..=port_end`.
+///
+/// Ports that fail to bind are reported and skipped; it is only an error when none could be bound.
+pub async fn bind(config: &Config) -> AnyResult<Vec<EchoPort>> {
+    let ip = IpAddr::from_str(&config.ip).map_err(AnyError::wrap)?;
+    // IPv4 groups are joined on the interface that owns `ip`
+    let interface = match ip {
+        IpAddr::V4(v4) => v4,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let group_tx = match &config.group {
+        Some(group) => {
+            let socket = group.sender(interface).map_err(|e| {
+                AnyError::quick(
+                    format!("Failed to create group sender: {}", e),
+                    anyverr::ErrKind::ValueValidation,
+                )
+            })?;
+            Some(Arc::new(socket))
+        }
+        None => None,
+    };
+
+    let mut ports = vec![];
+    let mut failed = vec![];
     for port in config.port_start..=config.port_end {
-        let socket_addr_str = format!("{}:{}", config.ip, port);
-        let socket_addr =
-            SocketAddr::from_str(&socket_addr_str).expect("should be valid socketAddr");
-        addrs.push(socket_addr);
-    }
-    let udp_socket = UdpSocket::bind(&addrs[..])
-        .await
-        .map_err(|e| AnyError::wrap(e))?;
-    println!("Udp bind on: {}", udp_socket.local_addr().unwrap());
-
-    let echo_task = tokio::spawn(async move {
-        let mut buf = vec![0u8; 2048];
-        loop {
-            match udp_socket.recv_from(&mut buf).await {
-                Ok((0, t)) => {
-                    println!("target send zero data {}", t);
-                    continue;
-                }
-                Ok((n, t)) => {
-                    let _ = udp_socket.send_to(&buf[..n], t).await; // Dont care the result
-                    println!("send data {} to {}", String::from_utf8_lossy(&buf[..n]), t);
-                }
-                Err(e) => {
-                    eprintln!("failed to send data: {}", e);
-                    break;
-                }
+        let bound = match &config.group {
+            Some(group) => group.bind(port, interface),
+            None => UdpSocket::bind(SocketAddr::new(ip, port))
+                .await
+                .map_err(AnyError::wrap),
+        };
+        match bound {
+            Ok(socket) => {
+                let local_addr = socket.local_addr().map_err(AnyError::wrap)?;
+                println!("Udp bind on: {}", local_addr);
+                let group = match config.group.as_ref().zip(group_tx.clone()) {
+                    Some((group, socket)) => {
+                        let target = group.target(local_addr.port());
+                        let source =
+                            group.source_addr(&socket, interface, target).map_err(|e| {
+                                AnyError::quick(
+                                    format!("Failed to route echoes to {}: {}", target, e),
+                                    anyverr::ErrKind::ValueValidation,
+                                )
+                            })?;
+                        Some(GroupReply {
+                            socket,
+                            target,
+                            source,
+                        })
+                    }
+                    None => None,
+                };
+                ports.push(EchoPort {
+                    port,
+                    socket: Arc::new(socket),
+                    stats: Arc::default(),
+                    group,
+                });
             }
+            Err(e) => failed.push(format!("{} ({})", port, e)),
         }
-    });
+    }
+
+    let total = config.port_end.saturating_sub(config.port_start) as usize + 1;
+    println!("Udp bound {}/{} port(s)", ports.len(), total);
+    if !failed.is_empty() {
+        eprintln!("Udp failed to bind: {}", failed.join(", "));
+    }
+    if ports.is_empty() {
+        return Err(AnyError::quick(
+            format!(
+                "Failed to bind any port in {}..={}",
+                config.port_start, config.port_end
+            ),
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    Ok(ports)
+}
+
+/// Runs one echo task per port until `shutdown` is initiated.
+pub async fn serve(
+    ports: Vec<EchoPort>,
+    config: &Config,
+    shutdown: GracefulShutdown,
+) -> AnyResult<()> {
+    let limiter = Arc::new(RateLimiter::new(
+        config.per_source_pps,
+        config.per_source_burst,
+        config.global_pps,
+    ));
+    let mut echo_tasks = JoinSet::new();
+    for port in ports.iter().cloned() {
+        echo_tasks.spawn(port::echo(
+            port,
+            config.mode,
+            limiter.clone(),
+            shutdown.clone(),
+        ));
+    }
 
-    echo_task.await.map_err(|e| AnyError::wrap(e))?;
+    if config.report_interval > 0 {
+        let mut ticker = time::interval(Duration::from_millis(config.report_interval));
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.wait_shutting_down() => break,
+                _ = ticker.tick() => report(&ports),
+            }
+        }
+    }
 
+    while let Some(res) = echo_tasks.join_next().await {
+        res.map_err(AnyError::wrap)?;
+    }
+    report(&ports);
     Ok(())
 }
+
+fn report(ports: &[EchoPort]) {
+    for port in ports {
+        println!("port {}: {}", port.port, port.stats.snapshot());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_echo_and_shutdown() -> AnyResult<()> {
+        let config = Config {
+            port_start: 0,
+            port_end: 0,
+            report_interval: 0,
+            ..Config::default()
+        };
+        let ports = bind(&config).await?;
+        let addr = ports[0].local_addr();
+        let stats = ports[0].stats.clone();
+        let shutdown = GracefulShutdown::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(ports, &config, shutdown).await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        client
+            .send_to(b"ping", addr)
+            .await
+            .map_err(AnyError::wrap)?;
+        let mut buf = [0u8; 16];
+        let (n, from) = client.recv_from(&mut buf).await.map_err(AnyError::wrap)?;
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(from, addr);
+
+        shutdown.initiate();
+        server.await.map_err(AnyError::wrap)??;
+        assert_eq!(stats.snapshot().packets_out, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_json_probe() -> AnyResult<()> {
+        let config = Config {
+            port_start: 0,
+            port_end: 0,
+            mode: Mode::Json,
+            report_interval: 0,
+            ..Config::default()
+        };
+        let ports = bind(&config).await?;
+        let addr = ports[0].local_addr();
+        let shutdown = GracefulShutdown::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(ports, &config, shutdown).await }
+        });
+
+        let report = probe(ProbeConfig {
+            count: 50,
+            interval: 0,
+            ..ProbeConfig::new(addr)
+        })
+        .await?;
+        assert_eq!(report.sent, 50);
+        assert_eq!(report.lost + report.rtts.len() as u64, 50);
+        assert_eq!(report.duplicates, 0);
+
+        shutdown.initiate();
+        server.await.map_err(AnyError::wrap)??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_amplification_guard() -> AnyResult<()> {
+        let config = Config {
+            port_start: 0,
+            port_end: 0,
+            mode: Mode::Json,
+            per_source_pps: 1,
+            per_source_burst: 1,
+            report_interval: 0,
+            ..Config::default()
+        };
+        let ports = bind(&config).await?;
+        let addr = ports[0].local_addr();
+        let stats = ports[0].stats.clone();
+        let shutdown = GracefulShutdown::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(ports, &config, shutdown).await }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .map_err(AnyError::wrap)?;
+        // without a `server_recv_at` placeholder the stamped reply would be larger
+        let req = br#"{"seq":0,"sent_at":1,"payload":""}"#;
+        for _ in 0..3 {
+            client.send_to(req, addr).await.map_err(AnyError::wrap)?;
+        }
+        while stats.snapshot().packets_in < 3 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        shutdown.initiate();
+        server.await.map_err(AnyError::wrap)??;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.packets_out, 0);
+        assert_eq!(snapshot.dropped_oversized, 1);
+        assert_eq!(snapshot.dropped_rate_limited, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_loopback_multicast_echo() -> AnyResult<()> {
+        let group = Group::Multicast {
+            group: "239.255.41.2".parse().unwrap(),
+            ttl: 0,
+            loopback: true,
+            interface_index: 0,
+        };
+        let config = Config {
+            port_start: 0,
+            port_end: 0,
+            group: Some(group.clone()),
+            report_interval: 0,
+            ..Config::default()
+        };
+        let ports = bind(&config).await?;
+        let port = ports[0].local_addr().port();
+        let echo_from = ports[0].group.as_ref().unwrap().source;
+        let stats = ports[0].stats.clone();
+        let shutdown = GracefulShutdown::new();
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(ports, &config, shutdown).await }
+        });
+
+        // another member on the same port, and a peer that shares the server's source port
+        // but not its address, as a sender on another host would
+        let lo = Ipv4Addr::LOCALHOST;
+        let member = group.bind(port, lo)?;
+        let peer_addr = SocketAddr::new("127.0.0.2".parse().unwrap(), echo_from.port());
+        let peer = {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+                .map_err(AnyError::wrap)?;
+            socket.set_reuse_address(true).map_err(AnyError::wrap)?;
+            socket.set_multicast_if_v4(&lo).map_err(AnyError::wrap)?;
+            socket.set_nonblocking(true).map_err(AnyError::wrap)?;
+            socket.bind(&peer_addr.into()).map_err(AnyError::wrap)?;
+            UdpSocket::from_std(socket.into()).map_err(AnyError::wrap)?
+        };
+        peer.send_to(b"hello group", group.target(port))
+            .await
+            .map_err(AnyError::wrap)?;
+
+        // the member sees the original, then the server's echo of it, and nothing more
+        let mut buf = [0u8; 64];
+        let mut from = vec![];
+        for _ in 0..2 {
+            let (n, addr) = time::timeout(Duration::from_secs(2), member.recv_from(&mut buf))
+                .await
+                .map_err(AnyError::wrap)?
+                .map_err(AnyError::wrap)?;
+            assert_eq!(&buf[..n], b"hello group");
+            from.push(addr);
+        }
+        from.sort();
+        let mut expected = vec![peer_addr, echo_from];
+        expected.sort();
+        assert_eq!(from, expected);
+
+        shutdown.initiate();
+        server.await.map_err(AnyError::wrap)??;
+        // the echo came back to the server too, but was not echoed again
+        assert_eq!(stats.snapshot().packets_out, 1);
+        Ok(())
+    }
+}
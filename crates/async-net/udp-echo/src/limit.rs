@@ -0,0 +1,155 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Instant};
+
+/// Stop tracking sources beyond this many; idle ones are pruned first.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_sec: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full and refills `per_sec` tokens a second, up to `burst`.
+    pub fn new(per_sec: u32, burst: u32, now: Instant) -> Self {
+        let capacity = burst.max(1) as f64;
+        Self {
+            capacity,
+            tokens: capacity,
+            per_sec: per_sec as f64,
+            last: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has been idle long enough to be full again.
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.last = now;
+    }
+}
+
+/// Why a datagram was not echoed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    SourceLimited,
+    GlobalLimited,
+}
+
+/// Per-source and global packet-rate limits, shared by every port of the server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_source_pps: u32,
+    per_source_burst: u32,
+    sources: Mutex<HashMap<SocketAddr, TokenBucket>>,
+    global: Option<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// `0` disables the corresponding limit.
+    pub fn new(per_source_pps: u32, per_source_burst: u32, global_pps: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            per_source_pps,
+            per_source_burst,
+            sources: Mutex::new(HashMap::new()),
+            global: (global_pps > 0)
+                .then(|| Mutex::new(TokenBucket::new(global_pps, global_pps, now))),
+        }
+    }
+
+    pub fn check(&self, source: SocketAddr) -> Verdict {
+        self.check_at(source, Instant::now())
+    }
+
+    fn check_at(&self, source: SocketAddr, now: Instant) -> Verdict {
+        if self.per_source_pps > 0 {
+            let mut sources = self.sources.lock().unwrap();
+            if !sources.contains_key(&source) && sources.len() >= MAX_TRACKED_SOURCES {
+                sources.retain(|_, b| !b.is_full(now));
+                if sources.len() >= MAX_TRACKED_SOURCES {
+                    // too many active sources to track fairly; treat the newcomer as limited
+                    return Verdict::SourceLimited;
+                }
+            }
+            let bucket = sources.entry(source).or_insert_with(|| {
+                TokenBucket::new(self.per_source_pps, self.per_source_burst, now)
+            });
+            if !bucket.try_take(now) {
+                return Verdict::SourceLimited;
+            }
+        }
+        if let Some(global) = &self.global
+            && !global.lock().unwrap().try_take(now)
+        {
+            return Verdict::GlobalLimited;
+        }
+        Verdict::Allowed
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn after(now: Instant, ms: u64) -> Instant {
+        now + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+        assert!(bucket.try_take(now));
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+        assert!(bucket.try_take(after(now, 100)));
+        assert!(!bucket.try_take(after(now, 100)));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let now = Instant::now();
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let limiter = RateLimiter::new(1, 1, 2);
+        assert_eq!(limiter.check_at(a, now), Verdict::Allowed);
+        assert_eq!(limiter.check_at(a, now), Verdict::SourceLimited);
+        assert_eq!(limiter.check_at(b, now), Verdict::Allowed);
+        let c: SocketAddr = "127.0.0.1:3".parse().unwrap();
+        assert_eq!(limiter.check_at(c, now), Verdict::GlobalLimited);
+        assert_eq!(RateLimiter::default().check_at(a, now), Verdict::Allowed);
+
+        // a burst below the rate smooths it instead of being raised to it
+        let smooth = RateLimiter::new(100, 1, 0);
+        assert_eq!(smooth.check_at(a, now), Verdict::Allowed);
+        assert_eq!(smooth.check_at(a, now), Verdict::SourceLimited);
+        assert_eq!(smooth.check_at(a, after(now, 10)), Verdict::Allowed);
+    }
+}
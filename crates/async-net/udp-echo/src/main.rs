@@ -1,9 +1,82 @@
-use anyverr::AnyResult;
-use udp_echo::Config;
+use std::{net::SocketAddr, str::FromStr};
+
+use anyverr::{AnyError, AnyResult};
+use udp_echo::{Config, Group, Mode, ProbeConfig};
+
+const USAGE: &str = "Usage:
+  udp-echo [--json] [--multicast <group>] [--broadcast]
+      Echo on every port of the default range; --json stamps structured probes,
+      --multicast/--broadcast echo to the group instead of the sender.
+  udp-echo probe <addr> [--count N] [--interval MS] [--size BYTES] [--timeout MS]
+      Send a burst of JSON probes to a server started with --json and report loss and RTT.";
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let config = Config::default();
-    udp_echo::run(config).await?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("probe") => {
+            let config = parse_probe(&args[1..])?;
+            let report = udp_echo::probe(config).await?;
+            println!("{}", report);
+        }
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        _ => {
+            let mut config = Config::default();
+            let mut rest = args.iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--json" => config.mode = Mode::Json,
+                    "--multicast" => {
+                        let group = rest
+                            .next()
+                            .ok_or_else(|| usage_err("--multicast requires a group".into()))?;
+                        config.group = Some(Group::Multicast {
+                            group: group.parse().map_err(AnyError::wrap)?,
+                            ttl: 1,
+                            loopback: true,
+                            interface_index: 0,
+                        });
+                    }
+                    "--broadcast" => {
+                        config.group = Some(Group::Broadcast {
+                            addr: std::net::Ipv4Addr::BROADCAST,
+                        })
+                    }
+                    other => return Err(usage_err(format!("unknown argument: {}", other))),
+                }
+            }
+            udp_echo::run(config).await?;
+        }
+    }
     Ok(())
 }
+
+fn parse_probe(args: &[String]) -> AnyResult<ProbeConfig> {
+    let target = args
+        .first()
+        .ok_or_else(|| usage_err("probe requires a target address".into()))?;
+    let target = SocketAddr::from_str(target).map_err(AnyError::wrap)?;
+    let mut config = ProbeConfig::new(target);
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest
+            .next()
+            .ok_or_else(|| usage_err(format!("{} requires a value", flag)))?;
+        let number = || u64::from_str(value).map_err(AnyError::wrap);
+        match flag.as_str() {
+            "--count" => config.count = number()?,
+            "--interval" => config.interval = number()?,
+            "--size" => config.payload_size = number()? as usize,
+            "--timeout" => config.timeout = number()?,
+            other => return Err(usage_err(format!("unknown argument: {}", other))),
+        }
+    }
+    Ok(config)
+}
+
+fn usage_err(msg: String) -> AnyError {
+    AnyError::quick(
+        format!("{}\n{}", msg, USAGE),
+        anyverr::ErrKind::ValueValidation,
+    )
+}
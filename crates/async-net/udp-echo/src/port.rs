@@ -0,0 +1,171 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::Serialize;
+use tokio::net::UdpSocket;
+
+use crate::{GracefulShutdown, GroupReply, Mode, RateLimiter, Verdict, probe};
+
+/// One bound port of the configured range.
+#[derive(Debug, Clone)]
+pub struct EchoPort {
+    pub port: u16,
+    pub socket: Arc<UdpSocket>,
+    pub stats: Arc<PortStats>,
+    /// Set in group mode; echoes then go to the group instead of the sender.
+    pub group: Option<GroupReply>,
+}
+
+impl EchoPort {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket
+            .local_addr()
+            .expect("bound socket should have a local address")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PortStats {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    errors: AtomicU64,
+    dropped_rate_limited: AtomicU64,
+    dropped_oversized: AtomicU64,
+}
+
+impl PortStats {
+    pub fn record_in(&self, n: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_out(&self, n: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.dropped_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_oversized(&self) {
+        self.dropped_oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PortStatsSnapshot {
+        PortStatsSnapshot {
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            dropped_rate_limited: self.dropped_rate_limited.load(Ordering::Relaxed),
+            dropped_oversized: self.dropped_oversized.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PortStatsSnapshot {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+    /// Requests refused by the per-source or global rate limit.
+    pub dropped_rate_limited: u64,
+    /// Replies withheld because they would have been larger than the request.
+    pub dropped_oversized: u64,
+}
+
+impl Display for PortStatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "in={} ({}B) out={} ({}B) errors={} rate_limited={} oversized={}",
+            self.packets_in,
+            self.bytes_in,
+            self.packets_out,
+            self.bytes_out,
+            self.errors,
+            self.dropped_rate_limited,
+            self.dropped_oversized
+        )
+    }
+}
+
+/// Echoes every datagram received on `port` back to its sender until shutdown.
+pub(crate) async fn echo(
+    port: EchoPort,
+    mode: Mode,
+    limiter: Arc<RateLimiter>,
+    shutdown: GracefulShutdown,
+) {
+    // large enough for any UDP payload, so probes are never truncated
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let (n, t) = tokio::select! {
+            _ = shutdown.wait_shutting_down() => break,
+            recv = port.socket.recv_from(&mut buf) => match recv {
+                Ok(r) => r,
+                Err(e) => {
+                    // e.g. ICMP port unreachable from an earlier reply; the socket is still usable
+                    port.stats.record_error();
+                    eprintln!("port {}: failed to recv data: {}", port.port, e);
+                    continue;
+                }
+            },
+        };
+        if port.group.as_ref().is_some_and(|g| g.is_own(t)) {
+            continue;
+        }
+        if n == 0 {
+            println!("target send zero data {}", t);
+            continue;
+        }
+        port.stats.record_in(n);
+        if limiter.check(t) != Verdict::Allowed {
+            port.stats.record_rate_limited();
+            continue;
+        }
+        let reply = match mode {
+            Mode::Raw => buf[..n].to_vec(),
+            Mode::Json => match probe::stamp(&buf[..n]) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    port.stats.record_error();
+                    eprintln!("port {}: malformed probe from {}: {}", port.port, t, e);
+                    continue;
+                }
+            },
+        };
+        if reply.len() > n {
+            // never let the echo amplify traffic towards a possibly spoofed source
+            port.stats.record_oversized();
+            continue;
+        }
+        let (socket, dest) = match &port.group {
+            Some(g) => (&g.socket, g.target),
+            None => (&port.socket, t),
+        };
+        match socket.send_to(&reply, dest).await {
+            Ok(sent) => port.stats.record_out(sent),
+            Err(e) => {
+                port.stats.record_error();
+                eprintln!("port {}: failed to send data to {}: {}", port.port, dest, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyverr::{AnyError, AnyResult};
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, time};
+
+/// A datagram of the structured (`Mode::Json`) protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Probe {
+    pub seq: u64,
+    /// Microseconds since the Unix epoch, on the sender's clock.
+    pub sent_at: u64,
+    pub payload: String,
+    /// Microseconds since the Unix epoch, stamped by the echo server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_recv_at: Option<u64>,
+}
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Parses a request datagram and returns the stamped reply.
+pub fn stamp(datagram: &[u8]) -> AnyResult<Vec<u8>> {
+    let mut probe: Probe = serde_json::from_slice(datagram).map_err(AnyError::wrap)?;
+    probe.server_recv_at = Some(now_micros());
+    serde_json::to_vec(&probe).map_err(AnyError::wrap)
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    pub target: SocketAddr,
+    pub count: u64,
+    /// Pause between two sends, in milliseconds. `0` sends the burst back to back.
+    pub interval: u64,
+    /// Length of the payload string of every probe.
+    pub payload_size: usize,
+    /// How long to wait for late replies after the last send, in milliseconds.
+    pub timeout: u64,
+}
+
+impl ProbeConfig {
+    pub fn new(target: SocketAddr) -> Self {
+        Self {
+            target,
+            count: 100,
+            interval: 10,
+            payload_size: 32,
+            timeout: 1_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeReport {
+    pub sent: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub duplicates: u64,
+    pub reordered: u64,
+    /// Round-trip times of the unique replies in microseconds, sorted ascending.
+    pub rtts: Vec<u64>,
+}
+
+impl ProbeReport {
+    pub fn loss_ratio(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / self.sent as f64
+    }
+
+    /// The nearest-rank percentile of the round-trip times, `p` in `0.0..=100.0`.
+    pub fn rtt_percentile(&self, p: f64) -> Option<u64> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.rtts.len() as f64).ceil() as usize;
+        Some(self.rtts[rank.clamp(1, self.rtts.len()) - 1])
+    }
+}
+
+impl Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "sent={} received={} lost={} ({:.2}%) duplicates={} reordered={}",
+            self.sent,
+            self.received,
+            self.lost,
+            self.loss_ratio() * 100.0,
+            self.duplicates,
+            self.reordered
+        )?;
+        let ms = |p: f64| {
+            self.rtt_percentile(p)
+                .map_or("-".to_string(), |us| format!("{:.3}ms", us as f64 / 1000.0))
+        };
+        write!(
+            f,
+            "rtt min={} p50={} p90={} p99={} max={}",
+            ms(0.0),
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(100.0)
+        )
+    }
+}
+
+/// Sends a burst of probes to an echo server in `Mode::Json` and measures the replies.
+pub async fn probe(config: ProbeConfig) -> AnyResult<ProbeReport> {
+    let bind_addr = if config.target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = Arc::new(UdpSocket::bind(bind_addr).await.map_err(AnyError::wrap)?);
+    socket
+        .connect(config.target)
+        .await
+        .map_err(AnyError::wrap)?;
+
+    let sender = {
+        let socket = socket.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let payload = "x".repeat(config.payload_size);
+            for seq in 0..config.count {
+                let sent_at = now_micros();
+                let probe = Probe {
+                    seq,
+                    sent_at,
+                    payload: payload.clone(),
+                    // a same-width placeholder keeps the stamped reply no larger than the request
+                    server_recv_at: Some(sent_at),
+                };
+                let datagram = serde_json::to_vec(&probe).map_err(AnyError::wrap)?;
+                socket.send(&datagram).await.map_err(AnyError::wrap)?;
+                if config.interval > 0 {
+                    time::sleep(Duration::from_millis(config.interval)).await;
+                }
+            }
+            AnyResult::Ok(())
+        })
+    };
+
+    let mut report = ProbeReport {
+        sent: config.count,
+        ..ProbeReport::default()
+    };
+    let mut seen = HashSet::new();
+    let mut highest = None;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut sender = Some(sender);
+    let mut deadline = None;
+    loop {
+        let deadline_now = deadline;
+        let recv = async {
+            match deadline_now {
+                Some(d) => time::timeout_at(d, socket.recv(&mut buf)).await.ok(),
+                None => Some(socket.recv(&mut buf).await),
+            }
+        };
+        let n = tokio::select! {
+            done = async { sender.as_mut().unwrap().await }, if sender.is_some() => {
+                sender = None;
+                done.map_err(AnyError::wrap)??;
+                if seen.len() as u64 == config.count {
+                    break;
+                }
+                deadline = Some(time::Instant::now() + Duration::from_millis(config.timeout));
+                continue;
+            }
+            recv = recv => match recv {
+                None => break,
+                Some(Ok(n)) => n,
+                // an ICMP error for an earlier probe, e.g. nothing listening yet
+                Some(Err(_)) => continue,
+            },
+        };
+        let Ok(reply) = serde_json::from_slice::<Probe>(&buf[..n]) else {
+            continue;
+        };
+        let now = now_micros();
+        report.received += 1;
+        if !seen.insert(reply.seq) {
+            report.duplicates += 1;
+            continue;
+        }
+        if highest.is_some_and(|h| reply.seq < h) {
+            report.reordered += 1;
+        }
+        highest = highest.max(Some(reply.seq));
+        report.rtts.push(now.saturating_sub(reply.sent_at));
+        if seen.len() as u64 == config.count && sender.is_none() {
+            break;
+        }
+    }
+
+    report.rtts.sort_unstable();
+    report.lost = config.count.saturating_sub(seen.len() as u64);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp_keeps_fields() -> AnyResult<()> {
+        let req = Probe {
+            seq: 7,
+            sent_at: 42,
+            payload: "hi".into(),
+            server_recv_at: None,
+        };
+        let reply = stamp(&serde_json::to_vec(&req).unwrap())?;
+        let reply: Probe = serde_json::from_slice(&reply).unwrap();
+        assert_eq!(reply.seq, 7);
+        assert_eq!(reply.sent_at, 42);
+        assert_eq!(reply.payload, "hi");
+        assert!(reply.server_recv_at.is_some());
+        assert!(stamp(b"not json").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rtt_percentile() {
+        let report = ProbeReport {
+            rtts: (1..=100).collect(),
+            ..ProbeReport::default()
+        };
+        assert_eq!(report.rtt_percentile(0.0), Some(1));
+        assert_eq!(report.rtt_percentile(50.0), Some(50));
+        assert_eq!(report.rtt_percentile(99.0), Some(99));
+        assert_eq!(report.rtt_percentile(100.0), Some(100));
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::Notify;
+
+/// Creates a shutdown signal that is triggered by Ctrl-C.
+pub fn init() -> GracefulShutdown {
+    let shutdown = GracefulShutdown::new();
+    termination(shutdown.clone());
+    shutdown
+}
+
+fn termination(shutdown_for_signal: GracefulShutdown) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Shutdown requested (Ctrl+C). Stopping echo sockets...");
+            shutdown_for_signal.initiate();
+        }
+    });
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GracefulShutdown {
+    inner: Arc<GracefulShutdownInner>,
+}
+
+#[derive(Debug, Default)]
+struct GracefulShutdownInner {
+    shutting_down: AtomicBool,
+    notify: Notify,
+}
+
+impl GracefulShutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn initiate(&self) {
+        if self.inner.shutting_down.swap(true, Ordering::Release) {
+            return;
+        }
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.shutting_down.load(Ordering::Acquire)
+    }
+
+    pub async fn wait_shutting_down(&self) {
+        loop {
+            // register before checking so a concurrent `initiate` is never missed
+            let notified = self.inner.notify.notified();
+            if self.is_shutting_down() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use anyverr::AnyError;
+use chacha20poly1305::aead::OsRng;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::{FRAME_NONCE_LEN, FrameDecryptor, FrameEncryptor, Result};
+
+/// X25519 公钥的长度，握手时双方各发送一次
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// 握手里的角色，决定用哪个方向的密钥发送
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// 发起连接的一方
+    Initiator,
+    Responder,
+}
+
+/// 一次性的 X25519 密钥交换，每个连接新建一个，用完即弃
+///
+/// 双方的公钥都没有经过认证，所以只能防住被动的窃听：中间人可以分别和两边各握一次手，
+/// 解开再重新加密所有的帧，双方都察觉不到。需要防中间人时得另外核对公钥或者预共享的密钥。
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+    role: Role,
+}
+
+/// 握手得到的两个方向的帧加解密器
+pub struct SessionKeys {
+    pub send: FrameEncryptor,
+    pub recv: FrameDecryptor,
+}
+
+impl Handshake {
+    pub fn new(role: Role) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self {
+            secret,
+            public,
+            role,
+        }
+    }
+
+    /// 发给对方的公钥
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    /// 用对方的公钥算出共享密钥，再用 HKDF 为每个方向派生出独立的密钥和基础 nonce
+    ///
+    /// 双方的公钥都作为盐参与派生，密钥和这一对公钥绑定在一起；但这证明不了对方是谁，
+    /// 见 [`Handshake`] 关于中间人的说明。
+    pub fn finish(self, peer: &[u8; PUBLIC_KEY_LEN]) -> Result<SessionKeys> {
+        let peer = PublicKey::from(*peer);
+        let shared = self.secret.diffie_hellman(&peer);
+        // 对方用低阶点凑出全零的共享密钥时拒绝握手
+        if !shared.was_contributory() {
+            return Err(AnyError::quick(
+                "peer sent an invalid public key",
+                anyverr::ErrKind::RuleViolation,
+            ));
+        }
+        let (initiator, responder) = match self.role {
+            Role::Initiator => (self.public, peer),
+            Role::Responder => (peer, self.public),
+        };
+        let mut salt = [0u8; PUBLIC_KEY_LEN * 2];
+        salt[..PUBLIC_KEY_LEN].copy_from_slice(initiator.as_bytes());
+        salt[PUBLIC_KEY_LEN..].copy_from_slice(responder.as_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+
+        let derive = |info: &[u8]| -> Result<[u8; 32 + FRAME_NONCE_LEN]> {
+            let mut okm = [0u8; 32 + FRAME_NONCE_LEN];
+            hkdf.expand(info, &mut okm).map_err(|e| {
+                AnyError::quick(
+                    format!("failed to derive session key: {}", e),
+                    anyverr::ErrKind::ValueValidation,
+                )
+            })?;
+            Ok(okm)
+        };
+        let to_responder = derive(b"en-de handshake: initiator to responder")?;
+        let to_initiator = derive(b"en-de handshake: responder to initiator")?;
+        let (send, recv) = match self.role {
+            Role::Initiator => (to_responder, to_initiator),
+            Role::Responder => (to_initiator, to_responder),
+        };
+        Ok(SessionKeys {
+            send: FrameEncryptor::new(&send[..32], &send[32..])?,
+            recv: FrameDecryptor::new(&recv[..32], &recv[32..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() -> Result<()> {
+        let client = Handshake::new(Role::Initiator);
+        let server = Handshake::new(Role::Responder);
+        let (client_pub, server_pub) = (client.public_key(), server.public_key());
+        let mut client = client.finish(&server_pub)?;
+        let mut server = server.finish(&client_pub)?;
+
+        let frame = client.send.encrypt_frame(b"ping")?;
+        assert_eq!(server.recv.decrypt_frame(&frame)?, b"ping");
+        let frame = server.send.encrypt_frame(b"pong")?;
+        assert_eq!(client.recv.decrypt_frame(&frame)?, b"pong");
+        // 两个方向的密钥不同，帧不能被反射回发送方
+        let frame = client.send.encrypt_frame(b"echo")?;
+        assert!(client.recv.decrypt_frame(&frame).is_err());
+
+        let eve = Handshake::new(Role::Responder);
+        assert!(eve.finish(&[0u8; PUBLIC_KEY_LEN]).is_err());
+        Ok(())
+    }
+}
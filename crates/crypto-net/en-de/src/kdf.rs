@@ -0,0 +1,54 @@
+use anyverr::AnyError;
+use argon2::Argon2;
+
+use super::Result;
+
+/// 派生出的密钥长度，正好是 XChaCha20Poly1305 的密钥长度
+pub const KEY_LEN: usize = 32;
+/// Argon2 要求盐至少 8 个字节
+pub const MIN_SALT_LEN: usize = 8;
+
+/// 用 Argon2id（默认参数）从口令派生出对称密钥
+///
+/// 同样的口令和盐总是得到同样的密钥，所以知道口令的各方不用交换密钥；
+/// 盐不需要保密，但应该让不同的用途各不相同。
+pub fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<[u8; KEY_LEN]> {
+    if passphrase.is_empty() {
+        return Err(AnyError::quick(
+            "Passphrase is empty",
+            anyverr::ErrKind::ValueValidation,
+        ));
+    }
+    if salt.len() < MIN_SALT_LEN {
+        return Err(AnyError::quick(
+            format!("Salt must be at least {} bytes", MIN_SALT_LEN),
+            anyverr::ErrKind::RuleViolation,
+        ));
+    }
+    let mut key = [0u8; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|e| {
+            AnyError::quick(
+                format!("failed to derive key: {}", e),
+                anyverr::ErrKind::ValueValidation,
+            )
+        })?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_key() -> Result<()> {
+        let key = derive_key(b"correct horse", b"ct-room/rust")?;
+        assert_eq!(key, derive_key(b"correct horse", b"ct-room/rust")?);
+        assert_ne!(key, derive_key(b"correct horse", b"ct-room/go")?);
+        assert_ne!(key, derive_key(b"battery staple", b"ct-room/rust")?);
+        assert!(derive_key(b"", b"ct-room/rust").is_err());
+        assert!(derive_key(b"correct horse", b"short").is_err());
+        Ok(())
+    }
+}
@@ -2,17 +2,28 @@ use std::str::FromStr;
 
 use anyverr::{AnyError, AnyResult};
 use chacha20poly1305::{
-    AeadCore, ChaCha20Poly1305, Key, KeyInit, XChaCha20Poly1305, XNonce,
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
     aead::{Aead, OsRng, rand_core::RngCore},
 };
 
+mod handshake;
+mod kdf;
 mod stream;
 
+pub use handshake::*;
+pub use kdf::*;
 pub use stream::*;
 
 type Result<T> = AnyResult<T>;
 type Span = u16;
 
+/// 操作系统提供的随机字节，可以用作密钥、nonce 或令牌
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
 #[derive(Debug)]
 pub enum Cipher {
     Xor(Option<Span>),
@@ -40,7 +51,8 @@ impl FromStr for Cipher {
                 Ok(Self::Xor(number))
             }
             "rc6" => Ok(Self::Rc6),
-            "xchacha20poly1305" | _ => Ok(Self::XChaCha20Poly1305),
+            // 其他名字都按 xchacha20poly1305 处理
+            _ => Ok(Self::XChaCha20Poly1305),
         }
     }
 }
@@ -67,7 +79,7 @@ impl Cipher {
     /// # 参数
     /// * `data`: 待加密的字节切片。
     /// * `span`: 一个 `Option<Span>`，如果为 `Some(s)`，则每隔 `s` 个字节跳过一个字节不进行加密。
-    ///           如果为 `None` 或 `Some(0)`，则对所有字节进行加密。
+    ///   如果为 `None` 或 `Some(0)`，则对所有字节进行加密。
     /// * `key`: XOR 密钥。
     ///
     /// # 返回值
@@ -206,52 +218,50 @@ impl Cipher {
         Ok(res)
     }
 }
-struct CryptoSuite {
-    key: Vec<u8>,
-    nonce: Option<Vec<u8>>,
-}
+#[cfg(test)]
+mod test {
+    use chacha20poly1305::{AeadCore, ChaCha20Poly1305};
 
-impl CryptoSuite {
-    pub fn new() -> Self {
-        let os_rng = OsRng::default();
+    use super::*;
 
-        let key = ChaCha20Poly1305::generate_key(os_rng);
-        let key = key.to_vec();
+    struct CryptoSuite {
+        key: Vec<u8>,
+        nonce: Option<Vec<u8>>,
+    }
 
-        let nonce = ChaCha20Poly1305::generate_nonce(os_rng);
-        let nonce = Some(nonce.to_vec());
+    impl CryptoSuite {
+        fn new() -> Self {
+            let os_rng = OsRng;
 
-        CryptoSuite { key, nonce }
-    }
+            let key = ChaCha20Poly1305::generate_key(os_rng);
+            let key = key.to_vec();
 
-    pub fn key_len(mut self, len: usize) -> Self {
-        let mut key = Vec::with_capacity(len);
-        unsafe { key.set_len(len) };
-        let mut os_rng = OsRng::default();
-        os_rng.fill_bytes(&mut key);
-        self.key = key;
-        self
-    }
+            let nonce = ChaCha20Poly1305::generate_nonce(os_rng);
+            let nonce = Some(nonce.to_vec());
 
-    pub fn nonce_len(mut self, len: usize) -> Self {
-        if len == 0 {
-            self.nonce = None;
-            return self;
+            CryptoSuite { key, nonce }
         }
 
-        let mut nonce = Vec::with_capacity(len);
-        unsafe { nonce.set_len(len) };
-        let mut os_rng = OsRng::default();
-        os_rng.fill_bytes(&mut nonce);
-        self.nonce = Some(nonce);
+        fn key_len(mut self, len: usize) -> Self {
+            let mut key = vec![0u8; len];
+            OsRng.fill_bytes(&mut key);
+            self.key = key;
+            self
+        }
 
-        self
-    }
-}
+        fn nonce_len(mut self, len: usize) -> Self {
+            if len == 0 {
+                self.nonce = None;
+                return self;
+            }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+            let mut nonce = vec![0u8; len];
+            OsRng.fill_bytes(&mut nonce);
+            self.nonce = Some(nonce);
+
+            self
+        }
+    }
 
     /// 以简洁、统一的方式打印数据摘要，避免在控制台刷屏。
     /// 这个版本兼容稳定版 Rust，并能安全地处理 UTF-8 字符串。
@@ -361,12 +371,12 @@ mod test {
 
     #[test]
     fn test_xchacha20poly1305_en_de() -> Result<()> {
-        let origin_msg = String::from("Hello world".repeat(100));
+        let origin_msg = "Hello world".repeat(100);
         let CryptoSuite { key, nonce } = CryptoSuite::new().nonce_len(24).key_len(32);
         assert!(nonce.is_some());
         let key = &key.as_slice();
         let xcahcha20poly1305_cipher = Cipher::XChaCha20Poly1305;
-        print_data_summary(&origin_msg, &origin_msg.as_bytes());
+        print_data_summary(&origin_msg, origin_msg.as_bytes());
 
         let encrypt = if let Some(n) = nonce.clone() {
             let nonce = Some(n.as_slice());
@@ -397,12 +407,12 @@ mod test {
 
     #[test]
     fn test_xchacha20poly1305_en_de_with_none_nonce() -> Result<()> {
-        let origin_msg = String::from("Hello world".repeat(100));
+        let origin_msg = "Hello world".repeat(100);
         let CryptoSuite { key, nonce } = CryptoSuite::new().nonce_len(0).key_len(32);
         assert!(nonce.is_none());
         let key = &key.as_slice();
         let xcahcha20poly1305_cipher = Cipher::XChaCha20Poly1305;
-        print_data_summary(&origin_msg, &origin_msg.as_bytes());
+        print_data_summary(&origin_msg, origin_msg.as_bytes());
 
         let encrypt = if let Some(n) = nonce.clone() {
             let nonce = Some(n.as_slice());
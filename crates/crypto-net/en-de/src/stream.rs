@@ -1,8 +1,20 @@
 use anyverr::AnyError;
-use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce, aead::Aead};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce, XChaCha20Poly1305, XNonce, aead::Aead,
+};
 
 use super::Result;
 
+/// 第 `counter` 块的 nonce：把 8 字节的计数器（小端）与基础 nonce 的前 8 字节进行 XOR
+///
+/// [`StreamEncryptor`]、[`FrameEncryptor`] 和对应的解密器都用它，两端必须一致。
+fn chunk_nonce<const N: usize>(mut nonce: [u8; N], counter: u64) -> [u8; N] {
+    for (n, byte) in nonce.iter_mut().zip(counter.to_le_bytes()) {
+        *n ^= byte;
+    }
+    nonce
+}
+
 /// 流式加密器，支持大文件的分块加密
 pub struct StreamEncryptor {
     cipher: XChaCha20Poly1305,
@@ -43,24 +55,14 @@ impl StreamEncryptor {
         }
 
         // 为每个块生成唯一的 nonce：基础 nonce + 计数器
-        let mut chunk_nonce = self.nonce;
-        let counter_bytes = self.counter.to_le_bytes();
-        // 将 8 字节的计数器与 nonce 的前 8 字节进行 XOR 操作。
-        for (i, &byte) in counter_bytes.iter().enumerate() {
-            if i < 24 {
-                chunk_nonce[i] ^= byte;
-            }
-        }
+        let nonce = XNonce::from(chunk_nonce(self.nonce.into(), self.counter));
 
-        let ct = self
-            .cipher
-            .encrypt(&chunk_nonce.into(), chunk)
-            .map_err(|e| {
-                AnyError::quick(
-                    format!("failed to encrypt chunk: {}", e),
-                    anyverr::ErrKind::ValueValidation,
-                )
-            })?;
+        let ct = self.cipher.encrypt(&nonce, chunk).map_err(|e| {
+            AnyError::quick(
+                format!("failed to encrypt chunk: {}", e),
+                anyverr::ErrKind::ValueValidation,
+            )
+        })?;
 
         self.counter += 1;
         Ok(ct)
@@ -112,26 +114,165 @@ impl StreamDecryptor {
         }
 
         // 为每个块生成唯一的 nonce：基础 nonce + 计数器
-        let mut chunk_nonce = self.nonce;
-        let counter_bytes = self.counter.to_le_bytes();
-        // 将计数器添加到 nonce 的最后 8 字节
-        for (i, &byte) in counter_bytes.iter().enumerate() {
-            if i < 24 {
-                chunk_nonce[i] ^= byte;
-            }
-        }
+        let nonce = XNonce::from(chunk_nonce(self.nonce.into(), self.counter));
 
-        let pt = self
-            .cipher
-            .decrypt(&chunk_nonce.into(), chunk)
-            .map_err(|e| {
-                AnyError::quick(
-                    format!("failed to decrypt chunk: {}", e),
-                    anyverr::ErrKind::ValueValidation,
-                )
-            })?;
+        let pt = self.cipher.decrypt(&nonce, chunk).map_err(|e| {
+            AnyError::quick(
+                format!("failed to decrypt chunk: {}", e),
+                anyverr::ErrKind::ValueValidation,
+            )
+        })?;
 
         self.counter += 1;
         Ok(pt)
     }
 }
+
+/// 按顺序加密一帧帧数据，nonce 的生成方式与 [`StreamEncryptor`] 相同，但使用 12 字节 nonce 的 ChaCha20Poly1305
+///
+/// 计数器不随帧发送，接收方按同样的顺序计数，所以重放、乱序或丢失的帧都会解密失败。
+pub struct FrameEncryptor {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; FRAME_NONCE_LEN],
+    counter: u64,
+}
+
+/// [`FrameEncryptor`] 的基础 nonce 长度
+pub const FRAME_NONCE_LEN: usize = 12;
+
+impl FrameEncryptor {
+    pub fn new(key: &[u8], nonce: &[u8]) -> Result<Self> {
+        let (cipher, nonce) = frame_cipher(key, nonce)?;
+        Ok(Self {
+            cipher,
+            nonce,
+            counter: 0,
+        })
+    }
+
+    /// 加密一帧，输出比输入多 16 字节的认证标签
+    pub fn encrypt_frame(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from(chunk_nonce(self.nonce, next_counter(&mut self.counter)?));
+        self.cipher.encrypt(&nonce, frame).map_err(|e| {
+            AnyError::quick(
+                format!("failed to encrypt frame: {}", e),
+                anyverr::ErrKind::ValueValidation,
+            )
+        })
+    }
+}
+
+/// [`FrameEncryptor`] 的接收端，只接受下一个计数器对应的帧
+pub struct FrameDecryptor {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; FRAME_NONCE_LEN],
+    counter: u64,
+}
+
+impl FrameDecryptor {
+    pub fn new(key: &[u8], nonce: &[u8]) -> Result<Self> {
+        let (cipher, nonce) = frame_cipher(key, nonce)?;
+        Ok(Self {
+            cipher,
+            nonce,
+            counter: 0,
+        })
+    }
+
+    /// 解密失败时计数器不前进，调用方应该断开连接
+    pub fn decrypt_frame(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Nonce::from(chunk_nonce(self.nonce, self.counter));
+        let pt = self.cipher.decrypt(&nonce, frame).map_err(|_| {
+            AnyError::quick(
+                format!(
+                    "frame #{} rejected: it was replayed, reordered or tampered with",
+                    self.counter
+                ),
+                anyverr::ErrKind::RuleViolation,
+            )
+        })?;
+        next_counter(&mut self.counter)?;
+        Ok(pt)
+    }
+}
+
+fn frame_cipher(key: &[u8], nonce: &[u8]) -> Result<(ChaCha20Poly1305, [u8; FRAME_NONCE_LEN])> {
+    if key.len() != 32 {
+        return Err(AnyError::quick(
+            "The key must be 32 bytes for ChaCha20",
+            anyverr::ErrKind::RuleViolation,
+        ));
+    }
+    let nonce: [u8; FRAME_NONCE_LEN] = nonce.try_into().map_err(|_| {
+        AnyError::quick(
+            "Nonce must be 12 bytes for ChaCha20",
+            anyverr::ErrKind::RuleViolation,
+        )
+    })?;
+    Ok((ChaCha20Poly1305::new(Key::from_slice(key)), nonce))
+}
+
+/// 计数器用完之前必须换新的密钥，否则 nonce 会重复
+fn next_counter(counter: &mut u64) -> Result<u64> {
+    let current = *counter;
+    *counter = current.checked_add(1).ok_or_else(|| {
+        AnyError::quick(
+            "frame counter exhausted, a new key is required",
+            anyverr::ErrKind::RuleViolation,
+        )
+    })?;
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_nonce() {
+        let base = [0xffu8; FRAME_NONCE_LEN];
+        assert_eq!(chunk_nonce(base, 0), base);
+        let nonce = chunk_nonce(base, 0x0102);
+        assert_eq!(nonce[..3], [0xfd, 0xfe, 0xff]);
+        assert_eq!(nonce[8..], base[8..]);
+    }
+
+    #[test]
+    fn test_stream_chunks_use_chunk_nonce() -> Result<()> {
+        let (key, base) = ([7u8; 32], [9u8; 24]);
+        let mut tx = StreamEncryptor::new(&key, &base)?;
+        let mut rx = StreamDecryptor::new(&key, &base)?;
+        tx.encrypt_chunk(b"first")?;
+        let second = tx.encrypt_chunk(b"second")?;
+        // 第二块用的就是 chunk_nonce(base, 1)
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+        let nonce = XNonce::from(chunk_nonce(base, 1));
+        assert_eq!(
+            cipher.decrypt(&nonce, second.as_slice()).unwrap(),
+            b"second"
+        );
+        assert!(rx.decrypt_chunk(&second).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames_in_order_only() -> Result<()> {
+        let (key, nonce) = ([7u8; 32], [9u8; FRAME_NONCE_LEN]);
+        let mut tx = FrameEncryptor::new(&key, &nonce)?;
+        let mut rx = FrameDecryptor::new(&key, &nonce)?;
+        let first = tx.encrypt_frame(b"hello")?;
+        let second = tx.encrypt_frame(b"hello")?;
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 5 + 16);
+
+        // 乱序
+        assert!(rx.decrypt_frame(&second).is_err());
+        assert_eq!(rx.decrypt_frame(&first)?, b"hello");
+        // 重放
+        assert!(rx.decrypt_frame(&first).is_err());
+        assert_eq!(rx.decrypt_frame(&second)?, b"hello");
+
+        assert!(FrameEncryptor::new(&key, &[0u8; 24]).is_err());
+        Ok(())
+    }
+}